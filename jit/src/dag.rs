@@ -1,12 +1,21 @@
-use std::collections::{HashMap, HashSet};
+use crate::group::{Group, GroupNode};
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    collections::{hash_map::Entry, HashMap, HashSet},
+};
 
-#[derive(Debug, Default, Clone, Copy, PartialEq)]
+/// What an input port of a node is connected to, which is `None` while it
+/// isn't connected to anything
+pub type Port = Option<u32>;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct V2 {
     pub x: i32,
     pub y: i32,
 }
 
-#[derive(Debug, Default, Clone, Copy, PartialEq)]
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Node {
     pub kind: NodeKind,
     pub position: V2,
@@ -25,58 +34,184 @@ impl Node {
         self
     }
 
-    pub fn inputs(&self) -> InputIterator {
+    pub fn inputs(&self) -> InputIterator<'_> {
         self.kind.inputs()
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum NodeKind {
-    Passthrough(u32),
+    Passthrough(Port),
     Intrinsic(Intrinsic),
     Input,
     Constant(f32),
+    /// An instance of one of the DAG's groups
+    Group(GroupNode),
 }
 
 impl NodeKind {
-    pub fn inputs(&self) -> InputIterator {
-        InputIterator { kind: *self, i: 0 }
+    /// Gets what each input port is connected to, in the same order as
+    /// [`ports`](Self::ports)
+    pub fn inputs(&self) -> InputIterator<'_> {
+        InputIterator { kind: self, i: 0 }
+    }
+
+    /// Describes the input ports of the node. Group nodes have the inputs of
+    /// their group, which [`Dag::ports`] looks up.
+    pub fn ports(&self) -> &'static [PortInfo] {
+        static PASSTHROUGH: [PortInfo; 1] = [PortInfo::required("input")];
+        match self {
+            Self::Passthrough(_) => &PASSTHROUGH,
+            Self::Intrinsic(intrinsic) => intrinsic.ports(),
+            Self::Input | Self::Constant(_) | Self::Group(_) => &[],
+        }
+    }
+
+    /// Replaces each input with the result of the function
+    pub fn map_inputs(self, f: impl Fn(Port) -> Port) -> Self {
+        match self {
+            Self::Passthrough(input) => Self::Passthrough(f(input)),
+            Self::Intrinsic(intrinsic) => Self::Intrinsic(intrinsic.map_inputs(f)),
+            Self::Group(mut node) => {
+                for input in &mut node.inputs {
+                    *input = f(*input);
+                }
+                Self::Group(node)
+            }
+            Self::Input | Self::Constant(_) => self,
+        }
+    }
+
+    /// Replaces the input at the index, or returns `None` if the node has no
+    /// port there. Group nodes take inputs at any index since their ports
+    /// depend on their group.
+    pub fn with_input(self, index: usize, input: Port) -> Option<Self> {
+        match (self, index) {
+            (Self::Passthrough(_), 0) => Some(Self::Passthrough(input)),
+            (Self::Intrinsic(intrinsic), _) => {
+                intrinsic.with_input(index, input).map(Self::Intrinsic)
+            }
+            (Self::Group(mut node), _) => {
+                if node.inputs.len() <= index {
+                    node.inputs.resize(index + 1, None);
+                }
+                node.inputs[index] = input;
+                Some(Self::Group(node))
+            }
+            _ => None,
+        }
     }
 }
 
 impl Default for NodeKind {
     fn default() -> Self {
-        Self::Passthrough(0)
+        Self::Passthrough(None)
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum Intrinsic {
-    Add(u32, u32),
-    Sub(u32, u32),
-    Mul(u32, u32),
-    Div(u32, u32),
+    Add(Port, Port),
+    Sub(Port, Port),
+    Mul(Port, Port),
+    Div(Port, Port),
+}
+
+impl Intrinsic {
+    /// The second operand defaults to the value that leaves the first one
+    /// unchanged
+    pub fn ports(&self) -> &'static [PortInfo] {
+        static ADDITIVE: [PortInfo; 2] = [PortInfo::required("a"), PortInfo::optional("b", 0.)];
+        static MULTIPLICATIVE: [PortInfo; 2] =
+            [PortInfo::required("a"), PortInfo::optional("b", 1.)];
+        match self {
+            Self::Add(..) | Self::Sub(..) => &ADDITIVE,
+            Self::Mul(..) | Self::Div(..) => &MULTIPLICATIVE,
+        }
+    }
+
+    pub fn map_inputs(self, f: impl Fn(Port) -> Port) -> Self {
+        match self {
+            Self::Add(a, b) => Self::Add(f(a), f(b)),
+            Self::Sub(a, b) => Self::Sub(f(a), f(b)),
+            Self::Mul(a, b) => Self::Mul(f(a), f(b)),
+            Self::Div(a, b) => Self::Div(f(a), f(b)),
+        }
+    }
+
+    pub fn with_input(self, index: usize, input: Port) -> Option<Self> {
+        Some(match (self, index) {
+            (Self::Add(_, b), 0) => Self::Add(input, b),
+            (Self::Add(a, _), 1) => Self::Add(a, input),
+            (Self::Sub(_, b), 0) => Self::Sub(input, b),
+            (Self::Sub(a, _), 1) => Self::Sub(a, input),
+            (Self::Mul(_, b), 0) => Self::Mul(input, b),
+            (Self::Mul(a, _), 1) => Self::Mul(a, input),
+            (Self::Div(_, b), 0) => Self::Div(input, b),
+            (Self::Div(a, _), 1) => Self::Div(a, input),
+            _ => return None,
+        })
+    }
+}
+
+/// The type of value that flows through a port
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortType {
+    Float,
 }
 
-pub struct InputIterator {
-    kind: NodeKind,
+/// Describes an input port of a node
+#[derive(Debug, Clone, PartialEq)]
+pub struct PortInfo {
+    pub name: Cow<'static, str>,
+    pub ty: PortType,
+    /// The value that the port takes while it isn't connected. Ports without
+    /// one are required, and compiling fails if they aren't connected.
+    pub default: Option<f32>,
+}
+
+impl PortInfo {
+    const fn required(name: &'static str) -> Self {
+        Self {
+            name: Cow::Borrowed(name),
+            ty: PortType::Float,
+            default: None,
+        }
+    }
+
+    const fn optional(name: &'static str, default: f32) -> Self {
+        Self {
+            name: Cow::Borrowed(name),
+            ty: PortType::Float,
+            default: Some(default),
+        }
+    }
+
+    pub fn is_required(&self) -> bool {
+        self.default.is_none()
+    }
+}
+
+pub struct InputIterator<'a> {
+    kind: &'a NodeKind,
     i: usize,
 }
 
-impl Iterator for InputIterator {
-    type Item = u32;
+impl Iterator for InputIterator<'_> {
+    type Item = Port;
 
     fn next(&mut self) -> Option<Self::Item> {
         let out = match (self.kind, self.i) {
-            (NodeKind::Passthrough(input), 0) => Some(input),
-            (NodeKind::Intrinsic(Intrinsic::Add(a, _)), 0) => Some(a),
-            (NodeKind::Intrinsic(Intrinsic::Add(_, b)), 1) => Some(b),
-            (NodeKind::Intrinsic(Intrinsic::Sub(a, _)), 0) => Some(a),
-            (NodeKind::Intrinsic(Intrinsic::Sub(_, b)), 1) => Some(b),
-            (NodeKind::Intrinsic(Intrinsic::Mul(a, _)), 0) => Some(a),
-            (NodeKind::Intrinsic(Intrinsic::Mul(_, b)), 1) => Some(b),
-            (NodeKind::Intrinsic(Intrinsic::Div(a, _)), 0) => Some(a),
-            (NodeKind::Intrinsic(Intrinsic::Div(_, b)), 1) => Some(b),
+            (&NodeKind::Passthrough(input), 0) => Some(input),
+            (&NodeKind::Intrinsic(Intrinsic::Add(a, _)), 0) => Some(a),
+            (&NodeKind::Intrinsic(Intrinsic::Add(_, b)), 1) => Some(b),
+            (&NodeKind::Intrinsic(Intrinsic::Sub(a, _)), 0) => Some(a),
+            (&NodeKind::Intrinsic(Intrinsic::Sub(_, b)), 1) => Some(b),
+            (&NodeKind::Intrinsic(Intrinsic::Mul(a, _)), 0) => Some(a),
+            (&NodeKind::Intrinsic(Intrinsic::Mul(_, b)), 1) => Some(b),
+            (&NodeKind::Intrinsic(Intrinsic::Div(a, _)), 0) => Some(a),
+            (&NodeKind::Intrinsic(Intrinsic::Div(_, b)), 1) => Some(b),
+            (NodeKind::Group(node), i) => node.inputs.get(i).copied(),
             _ => None,
         };
         self.i += 1;
@@ -84,31 +219,394 @@ impl Iterator for InputIterator {
     }
 }
 
-#[derive(Default)]
+#[derive(Debug, Default, Clone)]
 pub struct Dag {
-    out_node: u32,
+    out_node: Option<u32>,
     next_node: u32,
     nodes: HashMap<u32, Node>,
+    /// For each node ID, the nodes that use it as an input and how many of
+    /// their inputs do. Missing nodes are included so that references to them
+    /// can be found.
+    consumers: HashMap<u32, HashMap<u32, u32>>,
+    /// The position of each node in a topological order, kept up to date as
+    /// edges change so that most new edges can be checked for cycles without
+    /// searching the graph
+    order: HashMap<u32, u64>,
+    next_order: u64,
+    /// The groups that group nodes are instances of. Each group has its own
+    /// DAG with its own groups, so a group carries the groups nested in it.
+    groups: HashMap<u32, Group>,
+}
+
+impl PartialEq for Dag {
+    /// Compares the nodes regardless of the order that was found for them
+    fn eq(&self, other: &Self) -> bool {
+        self.out_node == other.out_node
+            && self.next_node == other.next_node
+            && self.nodes == other.nodes
+            && self.groups == other.groups
+    }
 }
 
 impl Dag {
     pub fn new() -> Self {
         Self {
-            out_node: 0,
+            out_node: None,
             next_node: 1,
-            nodes: HashMap::new(),
+            ..Default::default()
         }
     }
 
+    /// Creates a DAG from its parts, or returns a node that is part of a
+    /// cycle
+    pub(crate) fn from_parts(
+        out_node: Option<u32>,
+        next_node: u32,
+        nodes: HashMap<u32, Node>,
+        groups: HashMap<u32, Group>,
+    ) -> Result<Self, u32> {
+        let mut dag = Self {
+            out_node,
+            next_node,
+            groups,
+            ..Default::default()
+        };
+        for (&id, node) in &nodes {
+            dag.link(id, &node.kind);
+        }
+        dag.nodes = nodes;
+
+        // Kahn's algorithm, sorting the nodes that are ready so that the
+        // order doesn't depend on the hash map
+        let mut remaining: HashMap<u32, u32> = dag
+            .nodes
+            .iter()
+            .map(|(&id, node)| {
+                (
+                    id,
+                    node.inputs()
+                        .flatten()
+                        .filter(|input| dag.nodes.contains_key(input))
+                        .count() as u32,
+                )
+            })
+            .collect();
+        let mut ready: Vec<_> = remaining
+            .iter()
+            .filter(|(_, &count)| count == 0)
+            .map(|(&id, _)| id)
+            .collect();
+        ready.sort_unstable_by(|a, b| b.cmp(a));
+        while let Some(id) = ready.pop() {
+            dag.order.insert(id, dag.next_order);
+            dag.next_order += 1;
+            for (&consumer, &count) in dag.consumers.get(&id).into_iter().flatten() {
+                let remaining = remaining.get_mut(&consumer).unwrap();
+                *remaining -= count;
+                if *remaining == 0 {
+                    ready.push(consumer);
+                }
+            }
+        }
+
+        if dag.order.len() == dag.nodes.len() {
+            return Ok(dag);
+        }
+        // Each node that is left has an input that is left, so following
+        // them leads around a cycle
+        let unordered = |id: &u32| dag.nodes.contains_key(id) && !dag.order.contains_key(id);
+        let mut id = *dag.nodes.keys().find(|id| unordered(id)).unwrap();
+        let mut visited = HashSet::new();
+        while visited.insert(id) {
+            id = dag.nodes[&id].inputs().flatten().find(unordered).unwrap();
+        }
+        Err(id)
+    }
+
+    /// Adds a node with a new ID. IDs that dangling references point to are
+    /// skipped so that the node doesn't get connected by accident.
     pub fn add_node(&mut self, node: Node) -> u32 {
-        let id = self.next_node;
-        self.next_node += 1;
-        self.nodes.insert(id, node);
+        let mut id = self.next_node;
+        while self.consumers.contains_key(&id) {
+            id += 1;
+        }
+        self.next_node = id + 1;
+        self.insert(id, node);
         id
     }
 
+    /// Adds a node after every other node in the topological order
+    fn insert(&mut self, id: u32, node: Node) {
+        self.link(id, &node.kind);
+        self.nodes.insert(id, node);
+        self.order.insert(id, self.next_order);
+        self.next_order += 1;
+    }
+
+    /// Removes a node, leaving any references to it dangling. See
+    /// [`remove_node`](Self::remove_node) for removing a node that is in use.
     pub fn remove_vertex(&mut self, node: u32) {
-        self.nodes.remove(&node);
+        if let Some(removed) = self.nodes.remove(&node) {
+            self.unlink(node, &removed.kind);
+            self.order.remove(&node);
+        }
+    }
+
+    /// Removes a node and deals with the nodes that use it according to the
+    /// mode, returning the removed node
+    pub fn remove_node(&mut self, node: u32, mode: RemoveMode) -> Result<Node, RemoveError> {
+        let removed = self
+            .nodes
+            .get(&node)
+            .ok_or(RemoveError::MissingNode)?
+            .clone();
+        let consumers = self.consumers(node);
+        let replacement = match mode {
+            RemoveMode::Disconnect => None,
+            RemoveMode::Splice => removed
+                .inputs()
+                .next()
+                .flatten()
+                .filter(|input| self.nodes.contains_key(input)),
+            RemoveMode::Refuse => {
+                let output = self.out_node == Some(node);
+                if !consumers.is_empty() || output {
+                    return Err(RemoveError::InUse { consumers, output });
+                }
+                None
+            }
+        };
+
+        self.remove_vertex(node);
+        let replace = |input| {
+            if input == Some(node) {
+                replacement
+            } else {
+                input
+            }
+        };
+        for consumer in consumers {
+            let kind = self.nodes[&consumer].kind.clone().map_inputs(replace);
+            self.set_kind(consumer, kind);
+        }
+        self.out_node = replace(self.out_node);
+        Ok(removed)
+    }
+
+    /// Gets the nodes that use the node as an input, in ID order
+    pub fn consumers(&self, node: u32) -> Vec<u32> {
+        let mut consumers: Vec<_> = self
+            .consumers
+            .get(&node)
+            .into_iter()
+            .flat_map(|consumers| consumers.keys().copied())
+            .collect();
+        consumers.sort_unstable();
+        consumers
+    }
+
+    /// Gets every node that the node depends on, in topological order
+    pub fn upstream(&self, node: u32) -> Vec<u32> {
+        self.sorted(
+            self.search(node, Direction::Upstream, 0, None).unwrap(),
+            node,
+        )
+    }
+
+    /// Gets every node that depends on the node, in topological order
+    pub fn downstream(&self, node: u32) -> Vec<u32> {
+        self.sorted(
+            self.search(node, Direction::Downstream, u64::MAX, None)
+                .unwrap(),
+            node,
+        )
+    }
+
+    /// Gets every node with each node after all of its inputs
+    pub fn topological_order(&self) -> Vec<u32> {
+        let mut nodes: Vec<_> = self.nodes.keys().copied().collect();
+        nodes.sort_unstable_by_key(|id| self.order[id]);
+        nodes
+    }
+
+    /// Sorts the nodes found by a search from the node, leaving it out
+    fn sorted(&self, mut nodes: Vec<u32>, node: u32) -> Vec<u32> {
+        nodes.retain(|&other| other != node);
+        nodes.sort_unstable_by_key(|id| self.order[id]);
+        nodes
+    }
+
+    /// Finds every node that is reachable from the start in the direction and
+    /// whose position in the topological order is no further than the bound.
+    /// Returns `None` if the target is reached.
+    fn search(
+        &self,
+        start: u32,
+        direction: Direction,
+        bound: u64,
+        target: Option<u32>,
+    ) -> Option<Vec<u32>> {
+        if !self.nodes.contains_key(&start) {
+            return Some(vec![]);
+        }
+        let within = |position: u64| match direction {
+            Direction::Upstream => position >= bound,
+            Direction::Downstream => position <= bound,
+        };
+        let mut found = vec![];
+        let mut visited = HashSet::from([start]);
+        let mut stack = vec![start];
+        let mut neighbors = vec![];
+        while let Some(id) = stack.pop() {
+            found.push(id);
+            neighbors.clear();
+            match direction {
+                Direction::Upstream => neighbors.extend(self.nodes[&id].inputs().flatten()),
+                Direction::Downstream => neighbors.extend(
+                    self.consumers
+                        .get(&id)
+                        .into_iter()
+                        .flat_map(|consumers| consumers.keys()),
+                ),
+            }
+            for &neighbor in &neighbors {
+                if Some(neighbor) == target {
+                    return None;
+                }
+                let Some(&position) = self.order.get(&neighbor) else {
+                    continue;
+                };
+                if within(position) && visited.insert(neighbor) {
+                    stack.push(neighbor);
+                }
+            }
+        }
+        Some(found)
+    }
+
+    /// Moves nodes so that the input comes before the consumer in the
+    /// topological order. Only the nodes between the two that are connected
+    /// to them are searched and moved, following Pearce and Kelly's dynamic
+    /// topological sort. Returns false without changing anything if the
+    /// consumer is upstream of the input, in which case the edge would create
+    /// a cycle.
+    fn order_edge(&mut self, input: u32, consumer: u32) -> bool {
+        let (Some(&upper), Some(&lower)) = (self.order.get(&input), self.order.get(&consumer))
+        else {
+            return true;
+        };
+        if input == consumer {
+            return false;
+        } else if upper < lower {
+            return true;
+        }
+        let Some(mut downstream) = self.search(consumer, Direction::Downstream, upper, Some(input))
+        else {
+            return false;
+        };
+        let mut upstream = self
+            .search(input, Direction::Upstream, lower, None)
+            .unwrap();
+
+        // Reuse the positions of the moved nodes, putting the input and what
+        // it depends on first
+        let mut positions: Vec<_> = upstream
+            .iter()
+            .chain(&downstream)
+            .map(|id| self.order[id])
+            .collect();
+        positions.sort_unstable();
+        upstream.sort_unstable_by_key(|id| self.order[id]);
+        downstream.sort_unstable_by_key(|id| self.order[id]);
+        for (id, position) in upstream.into_iter().chain(downstream).zip(positions) {
+            self.order.insert(id, position);
+        }
+        true
+    }
+
+    /// Records the node as a consumer of its inputs
+    fn link(&mut self, id: u32, kind: &NodeKind) {
+        for input in kind.inputs().flatten() {
+            *self
+                .consumers
+                .entry(input)
+                .or_default()
+                .entry(id)
+                .or_default() += 1;
+        }
+    }
+
+    fn unlink(&mut self, id: u32, kind: &NodeKind) {
+        for input in kind.inputs().flatten() {
+            let Some(consumers) = self.consumers.get_mut(&input) else {
+                continue;
+            };
+            if let Entry::Occupied(mut count) = consumers.entry(id) {
+                *count.get_mut() -= 1;
+                if *count.get() == 0 {
+                    count.remove();
+                }
+            }
+            if consumers.is_empty() {
+                self.consumers.remove(&input);
+            }
+        }
+    }
+
+    /// Changes the kind of a node, which unlike
+    /// [`add_input`](Self::add_input) doesn't check for cycles
+    pub(crate) fn set_kind(&mut self, id: u32, kind: NodeKind) {
+        self.link(id, &kind);
+        let inputs: Vec<_> = kind.inputs().flatten().collect();
+        let old = std::mem::replace(&mut self.nodes.get_mut(&id).unwrap().kind, kind);
+        self.unlink(id, &old);
+        for input in inputs {
+            let ordered = self.order_edge(input, id);
+            debug_assert!(ordered, "Node {id} is part of a cycle");
+        }
+    }
+
+    pub fn set_position(&mut self, id: u32, position: V2) {
+        if let Some(node) = self.nodes.get_mut(&id) {
+            node.position = position;
+        }
+    }
+
+    /// Finds every reference to a node that doesn't exist. Unconnected inputs
+    /// are not dangling, and neither is a DAG without an output node.
+    pub fn dangling_references(&self) -> Vec<DanglingReference> {
+        let dangling = |target: &u32| !self.nodes.contains_key(target);
+        let mut references: Vec<_> = self
+            .nodes
+            .iter()
+            .flat_map(|(&node, other)| {
+                other
+                    .inputs()
+                    .enumerate()
+                    .filter_map(|(index, target)| Some((index, target.filter(dangling)?)))
+                    .map(move |(index, target)| DanglingReference::Input {
+                        node,
+                        index,
+                        target,
+                    })
+            })
+            .collect();
+        references.sort_unstable();
+        if let Some(out_node) = self.out_node.filter(dangling) {
+            references.push(DanglingReference::Output(out_node));
+        }
+        references
+    }
+
+    /// Puts back a node that was removed, keeping its ID
+    pub(crate) fn restore_node(&mut self, id: u32, node: Node) {
+        self.next_node = self.next_node.max(id + 1);
+        self.insert(id, node);
+        // Nodes that used the node may be earlier in the order
+        for consumer in self.consumers(id) {
+            let ordered = self.order_edge(id, consumer);
+            debug_assert!(ordered, "Node {id} is part of a cycle");
+        }
     }
 
     pub fn add_input(&mut self, node: u32, input: u32, index: usize) -> Result<(), EdgeError> {
@@ -116,69 +614,109 @@ impl Dag {
             return Err(EdgeError::SameNode);
         }
 
-        if self.reachable(input, node) {
+        let kind = self.with_input(node, index, Some(input))?;
+        if !self.order_edge(input, node) {
             return Err(EdgeError::CreatesCycle);
         }
+        self.set_kind(node, kind);
+        Ok(())
+    }
 
-        let node = self.nodes.get_mut(&node).ok_or(EdgeError::MissingNode)?;
+    /// Leaves the input at the index unconnected
+    pub fn remove_input(&mut self, node: u32, index: usize) -> Result<(), EdgeError> {
+        let kind = self.with_input(node, index, None)?;
+        self.set_kind(node, kind);
+        Ok(())
+    }
 
-        match node.kind {
-            NodeKind::Constant(_) | NodeKind::Input => Err(EdgeError::InputIndex),
-            NodeKind::Passthrough(_) => {
-                if index == 0 {
-                    node.kind = NodeKind::Passthrough(input);
-                    Ok(())
-                } else {
-                    Err(EdgeError::InputIndex)
-                }
-            }
+    /// Gets the kind of the node with the input at the index replaced
+    fn with_input(&self, node: u32, index: usize, input: Port) -> Result<NodeKind, EdgeError> {
+        let kind = &self.nodes.get(&node).ok_or(EdgeError::MissingNode)?.kind;
+        if index >= self.ports(kind).len() {
+            return Err(EdgeError::InputIndex);
+        }
+        Ok(kind.clone().with_input(index, input).unwrap())
+    }
 
-            NodeKind::Intrinsic(intrinsic) => {
-                node.kind = NodeKind::Intrinsic(match (intrinsic, index) {
-                    (Intrinsic::Add(_, b), 0) => Intrinsic::Add(input, b),
-                    (Intrinsic::Add(a, _), 1) => Intrinsic::Add(a, input),
-                    (Intrinsic::Sub(_, b), 0) => Intrinsic::Sub(input, b),
-                    (Intrinsic::Sub(a, _), 1) => Intrinsic::Sub(a, input),
-                    (Intrinsic::Mul(_, b), 0) => Intrinsic::Mul(input, b),
-                    (Intrinsic::Mul(a, _), 1) => Intrinsic::Mul(a, input),
-                    (Intrinsic::Div(_, b), 0) => Intrinsic::Div(input, b),
-                    (Intrinsic::Div(a, _), 1) => Intrinsic::Div(a, input),
-                    _ => return Err(EdgeError::InputIndex),
-                });
-                Ok(())
-            }
+    /// Describes the input ports of a node of the kind, including those of
+    /// group nodes. A group node whose group is missing has none.
+    pub fn ports(&self, kind: &NodeKind) -> Cow<'static, [PortInfo]> {
+        match kind {
+            NodeKind::Group(node) => self
+                .groups
+                .get(&node.group)
+                .map_or(Cow::Borrowed(&[]), |group| Cow::Owned(group.ports())),
+            _ => Cow::Borrowed(kind.ports()),
         }
     }
 
+    /// Adds a group that group nodes can be instances of, returning its ID
+    pub fn add_group(&mut self, group: Group) -> u32 {
+        let id = self.groups.keys().max().map_or(1, |id| id + 1);
+        self.groups.insert(id, group);
+        id
+    }
+
+    pub fn group(&self, id: u32) -> Option<&Group> {
+        self.groups.get(&id)
+    }
+
+    /// Changes a group, which changes every instance of it
+    pub fn group_mut(&mut self, id: u32) -> Option<&mut Group> {
+        self.groups.get_mut(&id)
+    }
+
+    pub fn groups(&self) -> impl Iterator<Item = (&u32, &Group)> {
+        self.groups.iter()
+    }
+
+    /// Puts back, replaces or removes a group without checking its instances,
+    /// for restoring an earlier state
+    pub(crate) fn restore_group(&mut self, id: u32, group: Option<Group>) {
+        match group {
+            Some(group) => self.groups.insert(id, group),
+            None => self.groups.remove(&id),
+        };
+    }
+
     pub fn set_out_node(&mut self, node: u32) {
         assert!(self.nodes.keys().any(|&id| id == node));
+        self.out_node = Some(node);
+    }
+
+    /// Sets the output node without checking that it exists, for restoring
+    /// an earlier state
+    pub(crate) fn restore_out_node(&mut self, node: Option<u32>) {
         self.out_node = node;
     }
 
-    pub fn out_node(&self) -> u32 {
+    /// The node whose value the DAG computes, if one has been chosen
+    pub fn out_node(&self) -> Option<u32> {
         self.out_node
     }
 
-    pub fn reachable(&self, src: u32, dst: u32) -> bool {
-        let mut visited = HashSet::new();
-        self.reachable_inner(src, dst, &mut visited)
+    /// The ID that the next added node gets
+    pub fn next_node(&self) -> u32 {
+        self.next_node
     }
 
-    fn reachable_inner(&self, src: u32, dst: u32, visited: &mut HashSet<u32>) -> bool {
+    /// Whether the destination is the source or one of the nodes it depends
+    /// on
+    pub fn reachable(&self, src: u32, dst: u32) -> bool {
         if src == dst {
-            true
-        } else if visited.contains(&src) {
-            false
-        } else {
-            visited.insert(src);
-            if let Some(src) = self.node(src) {
-                for neighbor in src.inputs() {
-                    if self.reachable_inner(neighbor, dst, visited) {
-                        return true;
-                    }
-                }
-            }
-            false
+            return true;
+        }
+        match self.order.get(&dst) {
+            // Only nodes after the destination in the order can depend on it
+            Some(&bound) => self
+                .search(src, Direction::Upstream, bound, Some(dst))
+                .is_none(),
+            // A missing node is reachable if anything refers to it
+            None => self
+                .search(src, Direction::Upstream, 0, None)
+                .unwrap()
+                .iter()
+                .any(|id| self.nodes[id].inputs().any(|input| input == Some(dst))),
         }
     }
 
@@ -195,6 +733,49 @@ impl Dag {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    /// Towards the inputs
+    Upstream,
+    /// Towards the consumers
+    Downstream,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RemoveMode {
+    /// Leaves the inputs that used the node unconnected
+    Disconnect,
+    /// Connects the nodes that used the node to its first input instead, so
+    /// that the node is taken out of the chain it was in
+    Splice,
+    /// Fails if any node uses the node or it is the output node
+    Refuse,
+}
+
+/// A reference to a node that doesn't exist
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DanglingReference {
+    /// The input at the index of the node
+    Input {
+        node: u32,
+        index: usize,
+        target: u32,
+    },
+    /// The output node
+    Output(u32),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum RemoveError {
+    #[error("The node doesn't exist")]
+    MissingNode,
+    #[error(
+        "The node is used by nodes {consumers:?}{}",
+        if *output { " and as the output" } else { "" }
+    )]
+    InUse { consumers: Vec<u32>, output: bool },
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
 pub enum EdgeError {
     #[error("The vertex already exists")]
@@ -212,6 +793,28 @@ pub enum EdgeError {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::{
+        collection::vec,
+        prelude::*,
+        sample::{select, Index},
+    };
+
+    impl Dag {
+        /// Searches every input without using the order
+        fn reachable_without_order(&self, src: u32, dst: u32) -> bool {
+            let mut stack = vec![src];
+            let mut visited = HashSet::new();
+            while let Some(id) = stack.pop() {
+                if id == dst {
+                    return true;
+                }
+                if visited.insert(id) {
+                    stack.extend(self.node(id).into_iter().flat_map(Node::inputs).flatten());
+                }
+            }
+            false
+        }
+    }
 
     #[test]
     fn identifies_cycle() {
@@ -223,4 +826,272 @@ mod tests {
         assert_eq!(dag.add_input(b, c, 0), Ok(()));
         assert_eq!(dag.add_input(c, a, 0), Err(EdgeError::CreatesCycle));
     }
+
+    /// An input x, a = x + x and b = a * a as the output
+    fn chain() -> (Dag, [u32; 3]) {
+        let mut dag = Dag::new();
+        let x = dag.add_node(Node::with_kind(NodeKind::Input));
+        let a = dag.add_node(Node::with_kind(NodeKind::Intrinsic(Intrinsic::Add(
+            Some(x),
+            Some(x),
+        ))));
+        let b = dag.add_node(Node::with_kind(NodeKind::Intrinsic(Intrinsic::Mul(
+            Some(a),
+            Some(a),
+        ))));
+        dag.set_out_node(b);
+        (dag, [x, a, b])
+    }
+
+    #[test]
+    fn remove_modes() {
+        let (mut dag, [x, a, b]) = chain();
+        assert_eq!(
+            dag.remove_node(a, RemoveMode::Disconnect)
+                .map(|node| node.kind),
+            Ok(NodeKind::Intrinsic(Intrinsic::Add(Some(x), Some(x))))
+        );
+        assert_eq!(
+            dag.node(b).unwrap().kind,
+            NodeKind::Intrinsic(Intrinsic::Mul(None, None))
+        );
+        assert_eq!(
+            dag.remove_node(a, RemoveMode::Disconnect),
+            Err(RemoveError::MissingNode)
+        );
+
+        let (mut dag, [x, a, b]) = chain();
+        dag.remove_node(a, RemoveMode::Splice).unwrap();
+        assert_eq!(
+            dag.node(b).unwrap().kind,
+            NodeKind::Intrinsic(Intrinsic::Mul(Some(x), Some(x)))
+        );
+        dag.remove_node(b, RemoveMode::Splice).unwrap();
+        assert_eq!(dag.out_node(), Some(x));
+
+        let (mut dag, [x, a, b]) = chain();
+        assert_eq!(
+            dag.remove_node(a, RemoveMode::Refuse),
+            Err(RemoveError::InUse {
+                consumers: vec![b],
+                output: false
+            })
+        );
+        assert_eq!(
+            dag.remove_node(b, RemoveMode::Refuse),
+            Err(RemoveError::InUse {
+                consumers: vec![],
+                output: true
+            })
+        );
+        assert_eq!(dag.ids().count(), 3);
+        dag.remove_node(b, RemoveMode::Disconnect).unwrap();
+        assert_eq!(dag.out_node(), None);
+        dag.remove_node(a, RemoveMode::Refuse).unwrap();
+        dag.remove_node(x, RemoveMode::Refuse).unwrap();
+        assert!(dag.dangling_references().is_empty());
+    }
+
+    #[test]
+    fn ports() {
+        let (mut dag, [x, a, _]) = chain();
+        let kind = |dag: &Dag| dag.node(a).unwrap().kind.clone();
+        let names: Vec<_> = kind(&dag)
+            .ports()
+            .iter()
+            .map(|port| &port.name[..])
+            .collect();
+        assert_eq!(names, ["a", "b"]);
+        assert_eq!(kind(&dag).inputs().count(), 2);
+        assert!(kind(&dag).ports()[0].is_required());
+        assert_eq!(kind(&dag).ports()[1].default, Some(0.));
+        assert!(NodeKind::Input.ports().is_empty());
+
+        dag.remove_input(a, 1).unwrap();
+        assert_eq!(
+            kind(&dag),
+            NodeKind::Intrinsic(Intrinsic::Add(Some(x), None))
+        );
+        assert_eq!(dag.consumers(x), [a]);
+        dag.remove_input(a, 0).unwrap();
+        assert!(dag.consumers(x).is_empty());
+        assert!(dag.dangling_references().is_empty());
+        assert_eq!(dag.remove_input(a, 2), Err(EdgeError::InputIndex));
+        assert_eq!(dag.remove_input(x, 0), Err(EdgeError::InputIndex));
+    }
+
+    #[test]
+    fn dangling_references() {
+        let (mut dag, [x, a, b]) = chain();
+        assert!(dag.dangling_references().is_empty());
+        dag.remove_vertex(x);
+        dag.remove_vertex(b);
+        assert_eq!(
+            dag.dangling_references(),
+            [
+                DanglingReference::Input {
+                    node: a,
+                    index: 0,
+                    target: x
+                },
+                DanglingReference::Input {
+                    node: a,
+                    index: 1,
+                    target: x
+                },
+                DanglingReference::Output(b),
+            ]
+        );
+    }
+
+    #[test]
+    fn dependencies() {
+        let (mut dag, [x, a, b]) = chain();
+        let y = dag.add_node(Node::with_kind(NodeKind::Input));
+        let c = dag.add_node(Node::with_kind(NodeKind::Intrinsic(Intrinsic::Sub(
+            Some(y),
+            Some(a),
+        ))));
+        assert_eq!(dag.consumers(x), [a]);
+        assert_eq!(dag.consumers(a), [b, c]);
+        assert_eq!(dag.upstream(b), [x, a]);
+        assert_eq!(dag.upstream(c).len(), 3);
+        assert_eq!(dag.downstream(x), [a, b, c]);
+        assert!(dag.downstream(b).is_empty());
+        assert!(dag.reachable(c, x));
+        assert!(!dag.reachable(x, c));
+
+        // Replacing one of two uses keeps the other
+        dag.add_input(b, y, 0).unwrap();
+        assert_eq!(dag.consumers(a), [b, c]);
+        dag.add_input(b, y, 1).unwrap();
+        assert_eq!(dag.consumers(a), [c]);
+        assert_eq!(dag.consumers(y), [b, c]);
+    }
+
+    #[test]
+    fn skips_referenced_ids() {
+        let mut dag = Dag::new();
+        let a = dag.add_node(Node::with_kind(NodeKind::Passthrough(Some(2))));
+        let b = dag.add_node(Node::default());
+        assert_eq!((a, b), (1, 3));
+        assert_eq!(dag.dangling_references().len(), 1);
+    }
+
+    #[test]
+    fn long_chains() {
+        // Edges that agree with the order don't search the graph
+        let mut dag = Dag::new();
+        let first = dag.add_node(Node::with_kind(NodeKind::Input));
+        let mut last = first;
+        for _ in 0..50_000 {
+            let next = dag.add_node(Node::default());
+            dag.add_input(next, last, 0).unwrap();
+            last = next;
+        }
+        let middle = dag.topological_order()[25_000];
+        assert_eq!(dag.add_input(middle, last, 0), Err(EdgeError::CreatesCycle));
+        assert_eq!(dag.upstream(last).len(), 50_000);
+    }
+
+    /// An edit to a DAG, picking the nodes it involves among the existing ones
+    #[derive(Debug, Clone)]
+    enum Edit {
+        AddInput,
+        AddPassthrough(Index),
+        AddProduct(Index, Index),
+        Connect(Index, Index, usize),
+        Remove(Index, RemoveMode),
+        RemoveVertex(Index),
+    }
+
+    fn edit() -> impl Strategy<Value = Edit> {
+        const MODES: [RemoveMode; 3] = [
+            RemoveMode::Disconnect,
+            RemoveMode::Splice,
+            RemoveMode::Refuse,
+        ];
+        prop_oneof![
+            2 => Just(Edit::AddInput),
+            2 => any::<Index>().prop_map(Edit::AddPassthrough),
+            2 => any::<(Index, Index)>().prop_map(|(a, b)| Edit::AddProduct(a, b)),
+            6 => (any::<(Index, Index)>(), 0..2usize)
+                .prop_map(|((node, input), index)| Edit::Connect(node, input, index)),
+            3 => (any::<Index>(), select(&MODES[..]))
+                .prop_map(|(node, mode)| Edit::Remove(node, mode)),
+            3 => any::<Index>().prop_map(Edit::RemoveVertex),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn random_edits(edits in vec(edit(), 1..300)) {
+            let mut dag = Dag::new();
+            for edit in edits {
+                let ids: Vec<_> = dag.ids().collect();
+                let pick = |index: &Index| (!ids.is_empty()).then(|| *index.get(&ids));
+                match edit {
+                    Edit::AddInput => {
+                        dag.add_node(Node::with_kind(NodeKind::Input));
+                    }
+                    Edit::AddPassthrough(input) => {
+                        dag.add_node(Node::with_kind(NodeKind::Passthrough(pick(&input))));
+                    }
+                    Edit::AddProduct(a, b) => {
+                        let kind = NodeKind::Intrinsic(Intrinsic::Mul(pick(&a), pick(&b)));
+                        dag.add_node(Node::with_kind(kind));
+                    }
+                    Edit::Connect(node, input, index) => {
+                        let (Some(node), Some(input)) = (pick(&node), pick(&input)) else {
+                            continue;
+                        };
+                        // Compare against searching the graph
+                        let cycle = node != input && dag.reachable_without_order(input, node);
+                        let result = dag.add_input(node, input, index);
+                        if !matches!(result, Err(EdgeError::InputIndex | EdgeError::MissingNode)) {
+                            prop_assert_eq!(result == Err(EdgeError::CreatesCycle), cycle);
+                        }
+                    }
+                    Edit::Remove(node, mode) => {
+                        if let Some(node) = pick(&node) {
+                            let _ = dag.remove_node(node, mode);
+                        }
+                    }
+                    Edit::RemoveVertex(node) => {
+                        if let Some(node) = pick(&node) {
+                            dag.remove_vertex(node);
+                        }
+                    }
+                }
+
+                let position: HashMap<_, _> = dag
+                    .topological_order()
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, id)| (id, i))
+                    .collect();
+                for (&id, node) in dag.iter() {
+                    for input in node
+                        .inputs()
+                        .flatten()
+                        .filter(|input| dag.nodes.contains_key(input))
+                    {
+                        prop_assert!(position[&input] < position[&id]);
+                        prop_assert!(dag.consumers(input).contains(&id));
+                    }
+                }
+                let mut rebuilt = Dag::from_parts(
+                    dag.out_node,
+                    dag.next_node,
+                    dag.nodes.clone(),
+                    HashMap::new(),
+                )
+                .unwrap();
+                for consumers in rebuilt.consumers.values_mut() {
+                    consumers.retain(|id, _| dag.nodes.contains_key(id));
+                }
+                prop_assert_eq!(&rebuilt.consumers, &dag.consumers);
+            }
+        }
+    }
 }
@@ -0,0 +1,616 @@
+use crate::dag::{Dag, Node, NodeKind, Port, PortInfo, PortType, V2};
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+};
+
+/// A reusable subgraph that group nodes are instances of. Its input nodes
+/// stand for the inputs of group nodes and its output node is their value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Group {
+    pub name: String,
+    pub dag: Dag,
+    /// The input nodes of the group's DAG that are exposed as ports, in port
+    /// order
+    pub inputs: Vec<GroupInput>,
+    /// The constant nodes of the group's DAG whose values each group node can
+    /// set
+    pub knobs: Vec<Knob>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GroupInput {
+    pub name: String,
+    pub node: u32,
+    /// The value that the input takes while it isn't connected, if it is
+    /// optional
+    #[serde(default)]
+    pub default: Option<f32>,
+}
+
+/// A parameter of a group, which defaults to the value of its constant node
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Knob {
+    pub name: String,
+    pub node: u32,
+}
+
+/// The kind of a node that is an instance of a group
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GroupNode {
+    pub group: u32,
+    /// Connected to the inputs of the group in order. Inputs past the end
+    /// aren't connected.
+    pub inputs: Vec<Port>,
+    /// The values of the knobs of the group in order, where `None` and knobs
+    /// past the end keep their default
+    #[serde(default)]
+    pub knobs: Vec<Option<f32>>,
+}
+
+impl Group {
+    pub fn new(name: impl Into<String>, dag: Dag) -> Self {
+        Self {
+            name: name.into(),
+            dag,
+            inputs: vec![],
+            knobs: vec![],
+        }
+    }
+
+    pub fn ports(&self) -> Vec<PortInfo> {
+        self.inputs
+            .iter()
+            .map(|input| PortInfo {
+                name: Cow::Owned(input.name.clone()),
+                ty: PortType::Float,
+                default: input.default,
+            })
+            .collect()
+    }
+
+    /// Exposes a constant node as a knob, returning the knob's index
+    pub fn expose(&mut self, node: u32, name: impl Into<String>) -> Result<usize, GroupError> {
+        match self.dag.node(node).map(|node| &node.kind) {
+            Some(NodeKind::Constant(_)) => {}
+            Some(_) => return Err(GroupError::NotConstant(node)),
+            None => return Err(GroupError::MissingNode(node)),
+        }
+        self.knobs.push(Knob {
+            name: name.into(),
+            node,
+        });
+        Ok(self.knobs.len() - 1)
+    }
+}
+
+impl Dag {
+    /// Moves the nodes into a new group and replaces them with an instance of
+    /// it, returning the group node. Every node outside the selection that the
+    /// nodes use becomes an input of the group, and the selected node that is
+    /// used outside the selection, or the only one that isn't used at all,
+    /// becomes its output. Group nodes in the selection take their groups
+    /// with them.
+    pub fn collapse(&mut self, nodes: &[u32], name: impl Into<String>) -> Result<u32, GroupError> {
+        let selected: HashSet<_> = nodes.iter().copied().collect();
+        if let Some(&missing) = nodes.iter().find(|&&id| self.node(id).is_none()) {
+            return Err(GroupError::MissingNode(missing));
+        }
+        // Copying in topological order maps the inputs of each node first
+        let order: Vec<_> = self
+            .topological_order()
+            .into_iter()
+            .filter(|id| selected.contains(id))
+            .collect();
+        if order.is_empty() {
+            return Err(GroupError::Empty);
+        }
+        // Input nodes are parameters of the compiled function
+        if let Some(&input) = order
+            .iter()
+            .find(|&&id| self.node(id).unwrap().kind == NodeKind::Input)
+        {
+            return Err(GroupError::Input(input));
+        }
+
+        let mut inputs = vec![];
+        for &id in &order {
+            for input in self.node(id).unwrap().inputs().flatten() {
+                if !selected.contains(&input) && !inputs.contains(&input) {
+                    inputs.push(input);
+                }
+            }
+        }
+        let used_outside = |id: &u32| {
+            self.out_node() == Some(*id)
+                || self
+                    .consumers(*id)
+                    .iter()
+                    .any(|consumer| !selected.contains(consumer))
+        };
+        let mut outputs: Vec<_> = order.iter().copied().filter(used_outside).collect();
+        if outputs.is_empty() {
+            outputs = order
+                .iter()
+                .copied()
+                .filter(|&id| self.consumers(id).is_empty())
+                .collect();
+        }
+        let [output] = outputs[..] else {
+            return Err(GroupError::MultipleOutputs);
+        };
+        // An input that depends on a selected node would depend on the group
+        let downstream: HashSet<_> = order.iter().flat_map(|&id| self.downstream(id)).collect();
+        if inputs.iter().any(|input| downstream.contains(input)) {
+            return Err(GroupError::CreatesCycle);
+        }
+
+        // Nodes in the group are positioned relative to the group node
+        let count = order.len() as i32;
+        let sum = order.iter().fold(V2::default(), |sum, &id| {
+            let position = self.node(id).unwrap().position;
+            V2 {
+                x: sum.x + position.x,
+                y: sum.y + position.y,
+            }
+        });
+        let center = V2 {
+            x: sum.x / count,
+            y: sum.y / count,
+        };
+
+        let mut group = Group::new(name, Dag::new());
+        let mut ids = HashMap::new();
+        for (i, &input) in inputs.iter().enumerate() {
+            let node = group.dag.add_node(Node::with_kind(NodeKind::Input));
+            ids.insert(input, node);
+            group.inputs.push(GroupInput {
+                name: format!("input {}", i + 1),
+                node,
+                default: None,
+            });
+        }
+        let mut groups = HashMap::new();
+        for &id in &order {
+            let mut node = self.node(id).unwrap().clone();
+            if let NodeKind::Group(instance) = &mut node.kind {
+                instance.group = match groups.get(&instance.group) {
+                    Some(&group) => group,
+                    None => {
+                        let definition = self
+                            .group(instance.group)
+                            .ok_or(GroupError::MissingGroup(instance.group))?;
+                        let copy = group.dag.add_group(definition.clone());
+                        groups.insert(instance.group, copy);
+                        copy
+                    }
+                };
+            }
+            node.kind = node.kind.map_inputs(|input| input.map(|input| ids[&input]));
+            node.position = V2 {
+                x: node.position.x - center.x,
+                y: node.position.y - center.y,
+            };
+            ids.insert(id, group.dag.add_node(node));
+        }
+        group.dag.set_out_node(ids[&output]);
+
+        let group = self.add_group(group);
+        let node = self.add_node(
+            Node::with_kind(NodeKind::Group(GroupNode {
+                group,
+                inputs: inputs.into_iter().map(Some).collect(),
+                knobs: vec![],
+            }))
+            .positioned(center),
+        );
+        for &id in &order {
+            self.remove_vertex(id);
+        }
+        self.replace_uses(output, Some(node));
+        Ok(node)
+    }
+
+    /// Replaces a group node with a copy of the nodes of its group, returning
+    /// the added nodes. Unconnected inputs with a default become constants,
+    /// and knobs that the node sets change the constants they expose. Input
+    /// nodes of the group that aren't exposed as ports are left unconnected
+    /// rather than copied, since they would become inputs of this DAG. Only
+    /// the nested groups that the copied nodes use are added to this DAG, and
+    /// only if it doesn't have an identical group already.
+    pub fn expand(&mut self, node: u32) -> Result<Vec<u32>, GroupError> {
+        let (instance, position) = match self.node(node) {
+            Some(Node {
+                kind: NodeKind::Group(instance),
+                position,
+            }) => (instance.clone(), *position),
+            Some(_) => return Err(GroupError::NotGroup(node)),
+            None => return Err(GroupError::MissingNode(node)),
+        };
+        let group = self
+            .group(instance.group)
+            .ok_or(GroupError::MissingGroup(instance.group))?
+            .clone();
+        for (_, inner) in group.dag.iter() {
+            if let NodeKind::Group(nested) = &inner.kind {
+                if group.dag.group(nested.group).is_none() {
+                    return Err(GroupError::MissingGroup(nested.group));
+                }
+            }
+        }
+
+        let mut added = vec![];
+        // What each node of the group's DAG is replaced with
+        let mut ids = HashMap::new();
+        for (i, input) in group.inputs.iter().enumerate() {
+            let port = instance.inputs.get(i).copied().flatten().or_else(|| {
+                let value = input.default?;
+                let constant =
+                    self.add_node(Node::with_kind(NodeKind::Constant(value)).positioned(position));
+                added.push(constant);
+                Some(constant)
+            });
+            ids.insert(input.node, port);
+        }
+        let knobs: HashMap<_, _> = group
+            .knobs
+            .iter()
+            .zip(&instance.knobs)
+            .filter_map(|(knob, &value)| Some((knob.node, value?)))
+            .collect();
+        // What each nested group is replaced with
+        let mut groups = HashMap::new();
+        for id in group.dag.topological_order() {
+            if ids.contains_key(&id) {
+                continue;
+            }
+            let mut inner = group.dag.node(id).unwrap().clone();
+            if inner.kind == NodeKind::Input {
+                ids.insert(id, None);
+                continue;
+            }
+            if let Some(&value) = knobs.get(&id) {
+                inner.kind = NodeKind::Constant(value);
+            }
+            if let NodeKind::Group(nested) = &mut inner.kind {
+                nested.group = *groups.entry(nested.group).or_insert_with(|| {
+                    // Checked above
+                    self.share_group(group.dag.group(nested.group).unwrap())
+                });
+            }
+            // References to missing nodes of the group are left unconnected
+            inner.kind = inner
+                .kind
+                .map_inputs(|input| input.and_then(|input| ids.get(&input).copied().flatten()));
+            inner.position = V2 {
+                x: position.x + inner.position.x,
+                y: position.y + inner.position.y,
+            };
+            let copy = self.add_node(inner);
+            ids.insert(id, Some(copy));
+            added.push(copy);
+        }
+
+        let output = group
+            .dag
+            .out_node()
+            .and_then(|out| ids.get(&out).copied().flatten());
+        self.remove_vertex(node);
+        self.replace_uses(node, output);
+        Ok(added)
+    }
+
+    /// Gets a group identical to the given one, adding it if there isn't one
+    fn share_group(&mut self, group: &Group) -> u32 {
+        let existing = self
+            .groups()
+            .find(|&(_, existing)| existing == group)
+            .map(|(&id, _)| id);
+        existing.unwrap_or_else(|| self.add_group(group.clone()))
+    }
+
+    /// Adds a group node that is an instance of the group, with its inputs
+    /// unconnected and its knobs at their defaults
+    pub fn instantiate(&mut self, group: u32) -> Result<u32, GroupError> {
+        let definition = self.group(group).ok_or(GroupError::MissingGroup(group))?;
+        let kind = NodeKind::Group(GroupNode {
+            group,
+            inputs: vec![None; definition.inputs.len()],
+            knobs: vec![None; definition.knobs.len()],
+        });
+        Ok(self.add_node(Node::with_kind(kind)))
+    }
+
+    /// Sets the value of a knob of a group node, or resets it to its default
+    pub fn set_knob(
+        &mut self,
+        node: u32,
+        index: usize,
+        value: Option<f32>,
+    ) -> Result<(), GroupError> {
+        let mut instance = match self.node(node).map(|node| &node.kind) {
+            Some(NodeKind::Group(instance)) => instance.clone(),
+            Some(_) => return Err(GroupError::NotGroup(node)),
+            None => return Err(GroupError::MissingNode(node)),
+        };
+        let group = self
+            .group(instance.group)
+            .ok_or(GroupError::MissingGroup(instance.group))?;
+        if index >= group.knobs.len() {
+            return Err(GroupError::KnobIndex);
+        }
+        if instance.knobs.len() <= index {
+            instance.knobs.resize(index + 1, None);
+        }
+        instance.knobs[index] = value;
+        self.set_kind(node, NodeKind::Group(instance));
+        Ok(())
+    }
+
+    /// Points the inputs and output that use the node at the replacement
+    fn replace_uses(&mut self, node: u32, replacement: Port) {
+        let replace = |input| {
+            if input == Some(node) {
+                replacement
+            } else {
+                input
+            }
+        };
+        for consumer in self.consumers(node) {
+            let kind = self
+                .node(consumer)
+                .unwrap()
+                .kind
+                .clone()
+                .map_inputs(replace);
+            self.set_kind(consumer, kind);
+        }
+        if self.out_node() == Some(node) {
+            self.restore_out_node(replacement);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum GroupError {
+    #[error("No nodes are selected")]
+    Empty,
+    #[error("Node {0} doesn't exist")]
+    MissingNode(u32),
+    #[error("Group {0} doesn't exist")]
+    MissingGroup(u32),
+    #[error("Node {0} isn't a group node")]
+    NotGroup(u32),
+    #[error("Node {0} isn't a constant")]
+    NotConstant(u32),
+    #[error("Node {0} is an input, which can't be grouped")]
+    Input(u32),
+    #[error("More than one of the nodes is used outside of the selection")]
+    MultipleOutputs,
+    #[error("A node that the selection uses depends on the selection")]
+    CreatesCycle,
+    #[error("The knob index is out of bounds")]
+    KnobIndex,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        dag::{EdgeError, Intrinsic},
+        jit::Jit,
+        save,
+    };
+
+    fn evaluate(dag: &Dag, x: f32) -> f32 {
+        let code = Jit::default().compile(dag).unwrap();
+        let f = unsafe { std::mem::transmute::<*const u8, extern "C" fn(f32) -> f32>(code) };
+        f(x)
+    }
+
+    fn intrinsic(dag: &mut Dag, intrinsic: Intrinsic) -> u32 {
+        dag.add_node(Node::with_kind(NodeKind::Intrinsic(intrinsic)))
+    }
+
+    /// An input x and x * 2 + x as the output, returning the input, constant,
+    /// product and sum
+    fn dag() -> (Dag, [u32; 4]) {
+        let mut dag = Dag::new();
+        let x = dag.add_node(Node::with_kind(NodeKind::Input));
+        let two =
+            dag.add_node(Node::with_kind(NodeKind::Constant(2.)).positioned(V2 { x: 0, y: 10 }));
+        let product = intrinsic(&mut dag, Intrinsic::Mul(Some(x), Some(two)));
+        let sum = intrinsic(&mut dag, Intrinsic::Add(Some(product), Some(x)));
+        dag.set_position(sum, V2 { x: 30, y: 20 });
+        dag.set_out_node(sum);
+        (dag, [x, two, product, sum])
+    }
+
+    #[test]
+    fn collapse_and_expand() {
+        let (mut dag, [x, two, product, sum]) = dag();
+        let before = dag.clone();
+        let node = dag.collapse(&[sum, product, two], "double").unwrap();
+        assert_eq!(dag.out_node(), Some(node));
+        assert_eq!(dag.ids().count(), 2);
+        assert_eq!(dag.node(node).unwrap().position, V2 { x: 10, y: 10 });
+        let NodeKind::Group(instance) = &dag.node(node).unwrap().kind else {
+            panic!("Expected a group node");
+        };
+        assert_eq!(instance.inputs, [Some(x)]);
+        let group = dag.group(instance.group).unwrap();
+        assert_eq!(group.name, "double");
+        assert_eq!(group.dag.ids().count(), 4);
+        assert_eq!(evaluate(&dag, 1.5), evaluate(&before, 1.5));
+
+        let added = dag.expand(node).unwrap();
+        assert_eq!(added.len(), 3);
+        assert!(dag.node(node).is_none());
+        assert!(dag.dangling_references().is_empty());
+        let mut positions: Vec<_> = added
+            .iter()
+            .map(|&id| dag.node(id).unwrap().position)
+            .collect();
+        positions.sort_unstable_by_key(|position| (position.x, position.y));
+        assert_eq!(
+            positions,
+            [V2::default(), V2 { x: 0, y: 10 }, V2 { x: 30, y: 20 }]
+        );
+        assert_eq!(evaluate(&dag, -4.), -12.);
+    }
+
+    #[test]
+    fn invalid_selections() {
+        let (mut dag, [x, two, product, sum]) = dag();
+        let before = dag.clone();
+        assert_eq!(dag.collapse(&[], ""), Err(GroupError::Empty));
+        assert_eq!(dag.collapse(&[x, product], ""), Err(GroupError::Input(x)));
+        assert_eq!(dag.collapse(&[99], ""), Err(GroupError::MissingNode(99)));
+        // The constant is only used inside the selection
+        assert_eq!(dag.collapse(&[two, product], "").map(|_| ()), Ok(()));
+        // Something else uses the constant
+        let mut dag = before.clone();
+        intrinsic(&mut dag, Intrinsic::Sub(Some(two), Some(x)));
+        assert_eq!(
+            dag.collapse(&[two, product], ""),
+            Err(GroupError::MultipleOutputs)
+        );
+        // The difference would use the group through the doubled product
+        let mut dag = before.clone();
+        let doubled = intrinsic(&mut dag, Intrinsic::Mul(Some(product), Some(two)));
+        let difference = intrinsic(&mut dag, Intrinsic::Sub(Some(doubled), Some(product)));
+        assert_eq!(
+            dag.collapse(&[product, difference], ""),
+            Err(GroupError::CreatesCycle)
+        );
+        assert_eq!(dag.expand(99), Err(GroupError::MissingNode(99)));
+        assert_eq!(dag.expand(sum), Err(GroupError::NotGroup(sum)));
+    }
+
+    #[test]
+    fn nested_instances() {
+        // A group that scales its input by a knob, defaulting to 2
+        let (mut library, [_, two, product, _]) = dag();
+        let scale = library.collapse(&[two, product], "scale").unwrap();
+        let NodeKind::Group(instance) = &library.node(scale).unwrap().kind else {
+            panic!("Expected a group node");
+        };
+        let mut group = library.group(instance.group).unwrap().clone();
+        let constant = group
+            .dag
+            .iter()
+            .find(|(_, node)| node.kind == NodeKind::Constant(2.))
+            .map(|(&id, _)| id)
+            .unwrap();
+        assert_eq!(group.expose(constant, "factor"), Ok(0));
+        group.inputs[0].default = Some(1.);
+        let library = save::load_text(&save::save_text(&library)).unwrap();
+        assert_eq!(library.groups().count(), 1);
+
+        // x * 3 + x * 2 + 2
+        let mut dag = Dag::new();
+        let scale = dag.add_group(group);
+        let x = dag.add_node(Node::with_kind(NodeKind::Input));
+        let tripled = dag.instantiate(scale).unwrap();
+        dag.add_input(tripled, x, 0).unwrap();
+        dag.set_knob(tripled, 0, Some(3.)).unwrap();
+        let doubled = dag.instantiate(scale).unwrap();
+        dag.add_input(doubled, x, 0).unwrap();
+        let unconnected = dag.instantiate(scale).unwrap();
+        let sum = intrinsic(&mut dag, Intrinsic::Add(Some(tripled), Some(doubled)));
+        let out = intrinsic(&mut dag, Intrinsic::Add(Some(sum), Some(unconnected)));
+        dag.set_out_node(out);
+        assert_eq!(dag.set_knob(tripled, 1, None), Err(GroupError::KnobIndex));
+        assert_eq!(dag.add_input(tripled, x, 1), Err(EdgeError::InputIndex));
+        assert_eq!(evaluate(&dag, 1.), 7.);
+
+        let outer = dag.collapse(&[tripled, doubled, sum], "outer").unwrap();
+        assert_eq!(dag.groups().count(), 2);
+        let NodeKind::Group(instance) = &dag.node(outer).unwrap().kind else {
+            panic!("Expected a group node");
+        };
+        assert_eq!(dag.group(instance.group).unwrap().dag.groups().count(), 1);
+        assert_eq!(evaluate(&dag, 1.), 7.);
+        let loaded = save::load_text(&save::save_text(&dag)).unwrap();
+        assert_eq!(loaded, dag);
+        assert_eq!(evaluate(&loaded, 2.), 12.);
+
+        dag.expand(outer).unwrap();
+        assert_eq!(evaluate(&dag, 2.), 12.);
+    }
+
+    #[test]
+    fn undeclared_inputs() {
+        // x + y, where only x is a port of the group
+        let mut group = Group::new("sum", Dag::new());
+        let x = group.dag.add_node(Node::with_kind(NodeKind::Input));
+        let y = group.dag.add_node(Node::with_kind(NodeKind::Input));
+        let sum = intrinsic(&mut group.dag, Intrinsic::Add(Some(x), Some(y)));
+        group.dag.set_out_node(sum);
+        group.inputs.push(GroupInput {
+            name: "x".to_string(),
+            node: x,
+            default: None,
+        });
+
+        let mut dag = Dag::new();
+        let input = dag.add_node(Node::with_kind(NodeKind::Input));
+        let group = dag.add_group(group);
+        let node = dag.instantiate(group).unwrap();
+        dag.add_input(node, input, 0).unwrap();
+        dag.set_out_node(node);
+        // The compiled function only takes the input of the outer DAG
+        assert_eq!(evaluate(&dag, 2.), 2.);
+
+        let mut expanded = dag.clone();
+        let added = expanded.expand(node).unwrap();
+        assert_eq!(added.len(), 1);
+        let inputs = expanded
+            .iter()
+            .filter(|(_, node)| node.kind == NodeKind::Input)
+            .count();
+        assert_eq!(inputs, 1);
+        let sum = &expanded.node(added[0]).unwrap().kind;
+        assert_eq!(sum, &NodeKind::Intrinsic(Intrinsic::Add(Some(input), None)));
+    }
+
+    #[test]
+    fn expanding_shares_groups() {
+        // A group that doubles its input through an instance of another group
+        let (mut library, [_, two, product, _]) = dag();
+        let double = library.collapse(&[two, product], "double").unwrap();
+        let mut outer = Group::new("outer", Dag::new());
+        let input = outer.dag.add_node(Node::with_kind(NodeKind::Input));
+        let NodeKind::Group(instance) = &library.node(double).unwrap().kind else {
+            panic!("Expected a group node");
+        };
+        let double = outer
+            .dag
+            .add_group(library.group(instance.group).unwrap().clone());
+        let node = outer.dag.instantiate(double).unwrap();
+        outer.dag.add_input(node, input, 0).unwrap();
+        outer.dag.set_out_node(node);
+        outer.dag.add_group(Group::new("unused", Dag::new()));
+        outer.inputs.push(GroupInput {
+            name: "x".to_string(),
+            node: input,
+            default: None,
+        });
+
+        let mut dag = Dag::new();
+        let x = dag.add_node(Node::with_kind(NodeKind::Input));
+        let outer = dag.add_group(outer);
+        let a = dag.instantiate(outer).unwrap();
+        let b = dag.instantiate(outer).unwrap();
+        dag.add_input(a, x, 0).unwrap();
+        dag.add_input(b, x, 0).unwrap();
+        let sum = intrinsic(&mut dag, Intrinsic::Add(Some(a), Some(b)));
+        dag.set_out_node(sum);
+        dag.expand(a).unwrap();
+        dag.expand(b).unwrap();
+        // The outer group and one copy of the doubling group
+        assert_eq!(dag.groups().count(), 2);
+        assert_eq!(evaluate(&dag, 1.5), 6.);
+    }
+}
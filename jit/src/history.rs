@@ -0,0 +1,723 @@
+use crate::{
+    dag::{Dag, EdgeError, Node, NodeKind, RemoveError, RemoveMode, V2},
+    group::{Group, GroupError},
+};
+use std::{
+    collections::VecDeque,
+    mem::take,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HistoryOptions {
+    /// The number of edits that can be undone
+    pub depth: usize,
+    /// Moves of the same node closer together than this are undone together,
+    /// so that dragging a node is a single edit
+    pub coalesce_window: Duration,
+}
+
+impl Default for HistoryOptions {
+    fn default() -> Self {
+        Self {
+            depth: 100,
+            coalesce_window: Duration::from_millis(500),
+        }
+    }
+}
+
+/// A single change to a DAG along with what it replaced, so that it can be
+/// applied in either direction
+#[derive(Debug, Clone, PartialEq)]
+enum Change {
+    AddNode {
+        id: u32,
+        node: Node,
+    },
+    RemoveNode {
+        id: u32,
+        node: Node,
+    },
+    SetKind {
+        id: u32,
+        before: NodeKind,
+        after: NodeKind,
+    },
+    SetOutNode {
+        before: Option<u32>,
+        after: Option<u32>,
+    },
+    Move {
+        id: u32,
+        before: V2,
+        after: V2,
+    },
+    /// Adds, replaces or removes a group
+    SetGroup {
+        id: u32,
+        before: Option<Box<Group>>,
+        after: Option<Box<Group>>,
+    },
+}
+
+impl Change {
+    fn inverse(self) -> Self {
+        match self {
+            Self::AddNode { id, node } => Self::RemoveNode { id, node },
+            Self::RemoveNode { id, node } => Self::AddNode { id, node },
+            Self::SetKind { id, before, after } => Self::SetKind {
+                id,
+                before: after,
+                after: before,
+            },
+            Self::SetOutNode { before, after } => Self::SetOutNode {
+                before: after,
+                after: before,
+            },
+            Self::Move { id, before, after } => Self::Move {
+                id,
+                before: after,
+                after: before,
+            },
+            Self::SetGroup { id, before, after } => Self::SetGroup {
+                id,
+                before: after,
+                after: before,
+            },
+        }
+    }
+
+    fn apply(self, dag: &mut Dag) {
+        match self {
+            Self::AddNode { id, node } => dag.restore_node(id, node),
+            Self::RemoveNode { id, .. } => dag.remove_vertex(id),
+            Self::SetKind { id, after, .. } => dag.set_kind(id, after),
+            Self::SetOutNode { after, .. } => dag.restore_out_node(after),
+            Self::Move { id, after, .. } => dag.set_position(id, after),
+            Self::SetGroup { id, after, .. } => dag.restore_group(id, after.map(|group| *group)),
+        }
+    }
+}
+
+/// Finds the changes that turn one DAG into the other. Groups and nodes are
+/// added before anything uses them and removed after nothing does, so the
+/// changes can be applied in order, or inverted in reverse order.
+fn diff(before: &Dag, after: &Dag) -> Vec<Change> {
+    let mut changes = vec![];
+    let mut removals = vec![];
+    let mut groups: Vec<_> = before
+        .groups()
+        .chain(after.groups())
+        .map(|(&id, _)| id)
+        .collect();
+    groups.sort_unstable();
+    groups.dedup();
+    for id in groups {
+        let (old, new) = (before.group(id), after.group(id));
+        if old == new {
+            continue;
+        }
+        let change = Change::SetGroup {
+            id,
+            before: old.cloned().map(Box::new),
+            after: new.cloned().map(Box::new),
+        };
+        match new {
+            Some(_) => changes.push(change),
+            None => removals.push(change),
+        }
+    }
+
+    let mut ids: Vec<_> = before.ids().chain(after.ids()).collect();
+    ids.sort_unstable();
+    ids.dedup();
+    let mut removed_nodes = vec![];
+    for id in ids {
+        match (before.node(id), after.node(id)) {
+            (None, Some(node)) => changes.push(Change::AddNode {
+                id,
+                node: node.clone(),
+            }),
+            (Some(node), None) => removed_nodes.push(Change::RemoveNode {
+                id,
+                node: node.clone(),
+            }),
+            (Some(old), Some(new)) => {
+                if old.kind != new.kind {
+                    changes.push(Change::SetKind {
+                        id,
+                        before: old.kind.clone(),
+                        after: new.kind.clone(),
+                    });
+                }
+                if old.position != new.position {
+                    changes.push(Change::Move {
+                        id,
+                        before: old.position,
+                        after: new.position,
+                    });
+                }
+            }
+            (None, None) => unreachable!("The ID comes from one of the DAGs"),
+        }
+    }
+    if before.out_node() != after.out_node() {
+        changes.push(Change::SetOutNode {
+            before: before.out_node(),
+            after: after.out_node(),
+        });
+    }
+    changes.extend(removed_nodes);
+    changes.extend(removals);
+    changes
+}
+
+/// A DAG that records every edit made through it so that edits can be undone
+/// and redone. Each entry in the history is either a single edit or every
+/// edit of a transaction. Node IDs are never reused, so an undone node comes
+/// back with the same ID.
+#[derive(Debug, Clone)]
+pub struct History {
+    dag: Dag,
+    options: HistoryOptions,
+    undo: VecDeque<Vec<Change>>,
+    redo: Vec<Vec<Change>>,
+    /// The edits of the open transaction
+    transaction: Vec<Change>,
+    /// The number of transactions that have begun without ending
+    open_transactions: usize,
+    /// When the last edit was made, if the next one may be coalesced with it
+    last_edit: Option<Instant>,
+}
+
+impl History {
+    pub fn new(dag: Dag, options: HistoryOptions) -> Self {
+        Self {
+            dag,
+            options,
+            undo: VecDeque::new(),
+            redo: vec![],
+            transaction: vec![],
+            open_transactions: 0,
+            last_edit: None,
+        }
+    }
+
+    pub fn dag(&self) -> &Dag {
+        &self.dag
+    }
+
+    pub fn into_dag(self) -> Dag {
+        self.dag
+    }
+
+    pub fn add_node(&mut self, node: Node) -> u32 {
+        let id = self.dag.add_node(node.clone());
+        self.record(Change::AddNode { id, node });
+        id
+    }
+
+    pub fn remove_vertex(&mut self, id: u32) {
+        if let Some(node) = self.dag.node(id).cloned() {
+            self.dag.remove_vertex(id);
+            self.record(Change::RemoveNode { id, node });
+        }
+    }
+
+    /// Removes a node like [`Dag::remove_node`], so that undoing it also
+    /// restores the inputs and output that used the node
+    pub fn remove_node(&mut self, id: u32, mode: RemoveMode) -> Result<Node, RemoveError> {
+        let consumers: Vec<_> = self
+            .dag
+            .consumers(id)
+            .into_iter()
+            .map(|consumer| (consumer, self.dag.node(consumer).unwrap().kind.clone()))
+            .collect();
+        let out_node = self.dag.out_node();
+        let node = self.dag.remove_node(id, mode)?;
+
+        self.begin_transaction();
+        for (consumer, before) in consumers {
+            let after = self.dag.node(consumer).unwrap().kind.clone();
+            self.record(Change::SetKind {
+                id: consumer,
+                before,
+                after,
+            });
+        }
+        if self.dag.out_node() != out_node {
+            self.record(Change::SetOutNode {
+                before: out_node,
+                after: self.dag.out_node(),
+            });
+        }
+        self.record(Change::RemoveNode {
+            id,
+            node: node.clone(),
+        });
+        self.end_transaction();
+        Ok(node)
+    }
+
+    pub fn add_input(&mut self, node: u32, input: u32, index: usize) -> Result<(), EdgeError> {
+        self.edit_inputs(node, |dag| dag.add_input(node, input, index))
+    }
+
+    pub fn remove_input(&mut self, node: u32, index: usize) -> Result<(), EdgeError> {
+        self.edit_inputs(node, |dag| dag.remove_input(node, index))
+    }
+
+    /// Records the change that the edit makes to the kind of the node
+    fn edit_inputs(
+        &mut self,
+        node: u32,
+        edit: impl FnOnce(&mut Dag) -> Result<(), EdgeError>,
+    ) -> Result<(), EdgeError> {
+        let before = self
+            .dag
+            .node(node)
+            .ok_or(EdgeError::MissingNode)?
+            .kind
+            .clone();
+        edit(&mut self.dag)?;
+        let after = self.dag.node(node).unwrap().kind.clone();
+        self.record(Change::SetKind {
+            id: node,
+            before,
+            after,
+        });
+        Ok(())
+    }
+
+    pub fn set_out_node(&mut self, node: u32) {
+        let before = self.dag.out_node();
+        self.dag.set_out_node(node);
+        self.record(Change::SetOutNode {
+            before,
+            after: Some(node),
+        });
+    }
+
+    /// Adds a group like [`Dag::add_group`]
+    pub fn add_group(&mut self, group: Group) -> u32 {
+        let id = self.dag.add_group(group.clone());
+        self.record(Change::SetGroup {
+            id,
+            before: None,
+            after: Some(Box::new(group)),
+        });
+        id
+    }
+
+    pub fn instantiate(&mut self, group: u32) -> Result<u32, GroupError> {
+        let id = self.dag.instantiate(group)?;
+        let node = self.dag.node(id).unwrap().clone();
+        self.record(Change::AddNode { id, node });
+        Ok(id)
+    }
+
+    pub fn set_knob(
+        &mut self,
+        node: u32,
+        index: usize,
+        value: Option<f32>,
+    ) -> Result<(), GroupError> {
+        let before = self
+            .dag
+            .node(node)
+            .ok_or(GroupError::MissingNode(node))?
+            .kind
+            .clone();
+        self.dag.set_knob(node, index, value)?;
+        let after = self.dag.node(node).unwrap().kind.clone();
+        self.record(Change::SetKind {
+            id: node,
+            before,
+            after,
+        });
+        Ok(())
+    }
+
+    /// Collapses nodes into a group like [`Dag::collapse`], so that undoing
+    /// it puts them back
+    pub fn collapse(&mut self, nodes: &[u32], name: impl Into<String>) -> Result<u32, GroupError> {
+        self.record_diff(|dag| dag.collapse(nodes, name))
+    }
+
+    /// Expands a group node like [`Dag::expand`], so that undoing it puts the
+    /// group node back
+    pub fn expand(&mut self, node: u32) -> Result<Vec<u32>, GroupError> {
+        self.record_diff(|dag| dag.expand(node))
+    }
+
+    /// Records an edit that changes too much of the DAG to follow by hand as
+    /// one transaction, by comparing the DAG before and after it
+    fn record_diff<T, E>(&mut self, edit: impl FnOnce(&mut Dag) -> Result<T, E>) -> Result<T, E> {
+        let before = self.dag.clone();
+        let result = edit(&mut self.dag)?;
+        self.begin_transaction();
+        for change in diff(&before, &self.dag) {
+            self.record(change);
+        }
+        self.end_transaction();
+        Ok(result)
+    }
+
+    /// Moves a node in the editor. Moves of a node shortly after the last one
+    /// coalesce with it.
+    pub fn set_position(&mut self, id: u32, position: V2) {
+        let Some(node) = self.dag.node(id) else {
+            return;
+        };
+        let before = node.position;
+        self.dag.set_position(id, position);
+        self.record(Change::Move {
+            id,
+            before,
+            after: position,
+        });
+    }
+
+    /// Stops the next edit from coalescing with the last one, such as when a
+    /// drag ends
+    pub fn break_coalescing(&mut self) {
+        self.last_edit = None;
+    }
+
+    /// Starts grouping edits so that they are undone together. Transactions
+    /// can nest, in which case the edits are grouped until the outermost one
+    /// ends.
+    pub fn begin_transaction(&mut self) {
+        self.open_transactions += 1;
+    }
+
+    pub fn end_transaction(&mut self) {
+        self.open_transactions = self.open_transactions.saturating_sub(1);
+        if self.open_transactions == 0 {
+            self.last_edit = None;
+            let transaction = take(&mut self.transaction);
+            if !transaction.is_empty() {
+                self.push(transaction);
+            }
+        }
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty() || !self.transaction.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    /// Reverts the last edit or transaction, ending any open transactions
+    /// first. Returns whether there was anything to undo.
+    pub fn undo(&mut self) -> bool {
+        self.end_transactions();
+        let Some(entry) = self.undo.pop_back() else {
+            return false;
+        };
+        for change in entry.iter().rev() {
+            change.clone().inverse().apply(&mut self.dag);
+        }
+        self.redo.push(entry);
+        true
+    }
+
+    /// Applies the last undone edit or transaction again. Returns whether
+    /// there was anything to redo.
+    pub fn redo(&mut self) -> bool {
+        self.end_transactions();
+        let Some(entry) = self.redo.pop() else {
+            return false;
+        };
+        for change in &entry {
+            change.clone().apply(&mut self.dag);
+        }
+        self.undo.push_back(entry);
+        true
+    }
+
+    fn end_transactions(&mut self) {
+        self.open_transactions = 1;
+        self.end_transaction();
+    }
+
+    fn record(&mut self, change: Change) {
+        self.redo.clear();
+        let now = Instant::now();
+        let coalesce = self
+            .last_edit
+            .is_some_and(|last| now.duration_since(last) < self.options.coalesce_window);
+        self.last_edit = Some(now);
+
+        let last = if self.open_transactions > 0 {
+            self.transaction.last_mut()
+        } else {
+            self.undo.back_mut().and_then(|entry| entry.last_mut())
+        };
+        if let (
+            Change::Move { id, after, .. },
+            Some(Change::Move {
+                id: last_id,
+                after: last_after,
+                ..
+            }),
+        ) = (&change, last)
+        {
+            if coalesce && id == last_id {
+                *last_after = *after;
+                return;
+            }
+        }
+
+        if self.open_transactions > 0 {
+            self.transaction.push(change);
+        } else {
+            self.push(vec![change]);
+        }
+    }
+
+    fn push(&mut self, entry: Vec<Change>) {
+        self.undo.push_back(entry);
+        while self.undo.len() > self.options.depth {
+            self.undo.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dag::Intrinsic;
+
+    const NEVER_COALESCE: HistoryOptions = HistoryOptions {
+        depth: 100,
+        coalesce_window: Duration::ZERO,
+    };
+
+    /// The output node, nodes and groups of a DAG
+    type State = (Option<u32>, Vec<(u32, Node)>, Vec<(u32, Group)>);
+
+    /// Gets everything that undoing restores, which leaves out the next node
+    /// ID since IDs aren't reused
+    fn state(dag: &Dag) -> State {
+        let mut nodes: Vec<_> = dag.iter().map(|(&id, node)| (id, node.clone())).collect();
+        nodes.sort_unstable_by_key(|&(id, _)| id);
+        let mut groups: Vec<_> = dag
+            .groups()
+            .map(|(&id, group)| (id, group.clone()))
+            .collect();
+        groups.sort_unstable_by_key(|&(id, _)| id);
+        (dag.out_node(), nodes, groups)
+    }
+
+    fn add() -> Node {
+        Node::with_kind(NodeKind::Intrinsic(Intrinsic::Add(None, None)))
+    }
+
+    #[test]
+    fn undo_and_redo() {
+        let mut history = History::new(Dag::new(), NEVER_COALESCE);
+        let mut states = vec![state(history.dag())];
+        let input = history.add_node(Node::with_kind(NodeKind::Input));
+        states.push(state(history.dag()));
+        let sum = history.add_node(add());
+        states.push(state(history.dag()));
+        history.add_input(sum, input, 1).unwrap();
+        states.push(state(history.dag()));
+        history.set_out_node(sum);
+        states.push(state(history.dag()));
+        history.set_position(sum, V2 { x: 10, y: 20 });
+        states.push(state(history.dag()));
+        history.remove_input(sum, 1).unwrap();
+        states.push(state(history.dag()));
+        history.remove_vertex(input);
+        states.push(state(history.dag()));
+
+        // Edits that fail or do nothing aren't recorded
+        assert!(history.add_input(input, sum, 0).is_err());
+        history.remove_vertex(input);
+
+        for expected in states.iter().rev().skip(1) {
+            assert!(history.undo());
+            assert_eq!(&state(history.dag()), expected);
+        }
+        assert!(!history.undo());
+        for expected in &states[1..] {
+            assert!(history.redo());
+            assert_eq!(&state(history.dag()), expected);
+        }
+        assert!(!history.redo());
+    }
+
+    #[test]
+    fn new_edits_clear_redo() {
+        let mut history = History::new(Dag::new(), NEVER_COALESCE);
+        let first = history.add_node(add());
+        history.undo();
+        assert!(history.can_redo());
+        let second = history.add_node(add());
+        assert!(!history.can_redo());
+        assert_ne!(first, second);
+        assert!(history.dag().node(first).is_none());
+    }
+
+    #[test]
+    fn coalescing() {
+        let mut history = History::new(Dag::new(), HistoryOptions::default());
+        let node = history.add_node(add());
+        history.break_coalescing();
+        for x in 0..10 {
+            history.set_position(node, V2 { x, y: 0 });
+        }
+        history.break_coalescing();
+        history.set_position(node, V2 { x: 20, y: 0 });
+        let position = |history: &History| history.dag().node(node).unwrap().position;
+        assert_eq!(position(&history), V2 { x: 20, y: 0 });
+        history.undo();
+        assert_eq!(position(&history), V2 { x: 9, y: 0 });
+        history.undo();
+        assert_eq!(position(&history), V2::default());
+        history.undo();
+        assert!(history.dag().node(node).is_none());
+
+        let mut history = History::new(Dag::new(), NEVER_COALESCE);
+        let node = history.add_node(add());
+        history.set_position(node, V2 { x: 1, y: 0 });
+        history.set_position(node, V2 { x: 2, y: 0 });
+        history.undo();
+        assert_eq!(position(&history), V2 { x: 1, y: 0 });
+    }
+
+    #[test]
+    fn transactions() {
+        let mut history = History::new(Dag::new(), NEVER_COALESCE);
+        let input = history.add_node(Node::with_kind(NodeKind::Input));
+        let before = state(history.dag());
+        history.begin_transaction();
+        let sum = history.add_node(add());
+        history.begin_transaction();
+        history.add_input(sum, input, 0).unwrap();
+        history.add_input(sum, input, 1).unwrap();
+        history.end_transaction();
+        history.set_out_node(sum);
+        history.end_transaction();
+        let after = state(history.dag());
+
+        history.undo();
+        assert_eq!(state(history.dag()), before);
+        history.redo();
+        assert_eq!(state(history.dag()), after);
+
+        // Undoing ends the transaction
+        history.begin_transaction();
+        history.remove_vertex(sum);
+        history.remove_vertex(input);
+        assert!(history.undo());
+        assert_eq!(state(history.dag()), after);
+        history.end_transaction();
+        assert!(history.undo());
+        assert_eq!(state(history.dag()), before);
+    }
+
+    #[test]
+    fn remove_node() {
+        let mut history = History::new(Dag::new(), NEVER_COALESCE);
+        let input = history.add_node(Node::with_kind(NodeKind::Input));
+        let first = history.add_node(add());
+        history.add_input(first, input, 0).unwrap();
+        let second = history.add_node(add());
+        history.add_input(second, first, 0).unwrap();
+        history.add_input(second, first, 1).unwrap();
+        history.set_out_node(first);
+        let before = state(history.dag());
+
+        history.remove_node(first, RemoveMode::Splice).unwrap();
+        assert_eq!(history.dag().out_node(), Some(input));
+        assert_eq!(
+            history.dag().node(second).unwrap().kind,
+            NodeKind::Intrinsic(Intrinsic::Add(Some(input), Some(input)))
+        );
+        let after = state(history.dag());
+        history.undo();
+        assert_eq!(state(history.dag()), before);
+        history.redo();
+        assert_eq!(state(history.dag()), after);
+
+        // Refused removals aren't recorded
+        assert!(history.remove_node(input, RemoveMode::Refuse).is_err());
+        history.undo();
+        assert_eq!(state(history.dag()), before);
+    }
+
+    #[test]
+    fn groups() {
+        let mut history = History::new(Dag::new(), NEVER_COALESCE);
+        let x = history.add_node(Node::with_kind(NodeKind::Input));
+        let two = history.add_node(Node::with_kind(NodeKind::Constant(2.)));
+        let product = history.add_node(Node::with_kind(NodeKind::Intrinsic(Intrinsic::Mul(
+            Some(x),
+            Some(two),
+        ))));
+        let sum = history.add_node(add());
+        history.add_input(sum, product, 0).unwrap();
+        history.add_input(sum, x, 1).unwrap();
+        history.set_out_node(sum);
+        let mut states = vec![state(history.dag())];
+
+        let scale = history.collapse(&[two, product], "scale").unwrap();
+        states.push(state(history.dag()));
+        let NodeKind::Group(instance) = &history.dag().node(scale).unwrap().kind else {
+            panic!("Expected a group node");
+        };
+        let mut group = history.dag().group(instance.group).unwrap().clone();
+        let (&constant, _) = group
+            .dag
+            .iter()
+            .find(|(_, node)| node.kind == NodeKind::Constant(2.))
+            .unwrap();
+        group.expose(constant, "factor").unwrap();
+        let group = history.add_group(group);
+        states.push(state(history.dag()));
+        let node = history.instantiate(group).unwrap();
+        states.push(state(history.dag()));
+        history.set_knob(node, 0, Some(3.)).unwrap();
+        states.push(state(history.dag()));
+        history.add_input(node, x, 0).unwrap();
+        states.push(state(history.dag()));
+        history.expand(scale).unwrap();
+        states.push(state(history.dag()));
+        history.expand(node).unwrap();
+        states.push(state(history.dag()));
+
+        // Edits that fail aren't recorded
+        assert!(history.expand(sum).is_err());
+        assert!(history.set_knob(sum, 0, None).is_err());
+        assert!(history.collapse(&[x], "").is_err());
+
+        for expected in states.iter().rev().skip(1) {
+            assert!(history.undo());
+            assert_eq!(&state(history.dag()), expected);
+        }
+        for expected in &states[1..] {
+            assert!(history.redo());
+            assert_eq!(&state(history.dag()), expected);
+        }
+        assert!(history.dag().dangling_references().is_empty());
+    }
+
+    #[test]
+    fn depth() {
+        let options = HistoryOptions {
+            depth: 3,
+            ..NEVER_COALESCE
+        };
+        let mut history = History::new(Dag::new(), options);
+        for _ in 0..5 {
+            history.add_node(add());
+        }
+        while history.undo() {}
+        assert_eq!(history.dag().ids().count(), 2);
+    }
+}
@@ -1,8 +1,12 @@
-use crate::dag::{Dag, Intrinsic, Node, NodeKind};
+use crate::{
+    dag::{Dag, Intrinsic, NodeKind, Port, PortInfo},
+    group::GroupInput,
+    optimize::{optimize, OptimizeOptions},
+};
 use cranelift::prelude::*;
 use cranelift_jit::{JITBuilder, JITModule};
 use cranelift_module::{Linkage, Module, ModuleError};
-use std::collections::HashSet;
+use std::{borrow::Cow, collections::HashSet};
 
 const FLOAT: cranelift::codegen::ir::Type = cranelift::codegen::ir::types::F32;
 
@@ -35,8 +39,14 @@ impl Default for Jit {
 }
 
 impl Jit {
-    pub fn compile(&mut self, dag: &Dag) -> Result<*const u8, ModuleError> {
-        self.translate(dag);
+    /// Compiles the DAG into a function taking one float parameter per input
+    /// node, ordered by node ID, and returning the value of the output node.
+    /// Group nodes are inlined and the result is optimized. Fails if the
+    /// output node depends on a required input that isn't connected or on a
+    /// node or group that doesn't exist, including inside groups.
+    pub fn compile(&mut self, dag: &Dag) -> Result<*const u8, CompileError> {
+        check(dag)?;
+        self.translate(&prepare(dag));
         let id = self
             .module
             // TODO: Pick a proper function name
@@ -62,12 +72,13 @@ impl Jit {
     }
 
     fn translate(&mut self, dag: &Dag) {
-        let inputs: Vec<_> = dag
+        let mut inputs: Vec<_> = dag
             .iter()
             .filter_map(|(id, node)| (node.kind == NodeKind::Input).then_some(*id))
             .collect();
+        inputs.sort_unstable();
 
-        for input in inputs.iter() {
+        for _ in inputs.iter() {
             self.ctx.func.signature.params.push(AbiParam::new(FLOAT));
         }
 
@@ -90,11 +101,8 @@ impl Jit {
             dag,
             defined_variables: HashSet::new(),
         };
-        translator.translate(dag.out_node());
+        let return_value = translator.translate(dag.out_node().unwrap());
         let mut builder = translator.into_builder();
-
-        let return_variable = Variable::from_u32(dag.out_node());
-        let return_value = builder.use_var(return_variable);
         builder.ins().return_(&[return_value]);
         builder.finalize();
     }
@@ -110,10 +118,9 @@ struct Translator<'a> {
 impl<'a> Translator<'a> {
     pub fn translate(&mut self, node: u32) -> Value {
         let node_id = node;
-        let binding = Node::with_kind(NodeKind::Constant(0.));
-        let node = self.dag.node(node).unwrap_or(&binding);
+        let node = self.dag.node(node).unwrap();
         match node.kind {
-            NodeKind::Passthrough(input) => self.translate(input),
+            NodeKind::Passthrough(input) => self.input(input, &node.kind.ports()[0]),
 
             NodeKind::Constant(constant) => self.builder.ins().f32const(constant),
 
@@ -122,27 +129,18 @@ impl<'a> Translator<'a> {
                 if !self.defined_variables.contains(&node_id) {
                     self.defined_variables.insert(node_id);
                     self.builder.declare_var(variable, FLOAT);
+                    let (Intrinsic::Add(a, b)
+                    | Intrinsic::Sub(a, b)
+                    | Intrinsic::Mul(a, b)
+                    | Intrinsic::Div(a, b)) = intrinsic;
+                    let ports = intrinsic.ports();
+                    let a = self.input(a, &ports[0]);
+                    let b = self.input(b, &ports[1]);
                     let value = match intrinsic {
-                        Intrinsic::Add(a, b) => {
-                            let a = self.translate(a);
-                            let b = self.translate(b);
-                            self.builder.ins().fadd(a, b)
-                        }
-                        Intrinsic::Sub(a, b) => {
-                            let a = self.translate(a);
-                            let b = self.translate(b);
-                            self.builder.ins().fsub(a, b)
-                        }
-                        Intrinsic::Mul(a, b) => {
-                            let a = self.translate(a);
-                            let b = self.translate(b);
-                            self.builder.ins().fmul(a, b)
-                        }
-                        Intrinsic::Div(a, b) => {
-                            let a = self.translate(a);
-                            let b = self.translate(b);
-                            self.builder.ins().fdiv(a, b)
-                        }
+                        Intrinsic::Add(..) => self.builder.ins().fadd(a, b),
+                        Intrinsic::Sub(..) => self.builder.ins().fsub(a, b),
+                        Intrinsic::Mul(..) => self.builder.ins().fmul(a, b),
+                        Intrinsic::Div(..) => self.builder.ins().fdiv(a, b),
                     };
                     self.builder.def_var(variable, value);
                 }
@@ -153,6 +151,17 @@ impl<'a> Translator<'a> {
                 let variable = Variable::from_u32(node_id);
                 self.builder.use_var(variable)
             }
+
+            NodeKind::Group(_) => unreachable!("Groups are inlined before translating"),
+        }
+    }
+
+    /// Translates the node connected to the port, or its default value if it
+    /// isn't connected, which [`check`] made sure it has
+    fn input(&mut self, input: Port, port: &PortInfo) -> Value {
+        match input {
+            Some(input) => self.translate(input),
+            None => self.builder.ins().f32const(port.default.unwrap()),
         }
     }
 
@@ -161,8 +170,239 @@ impl<'a> Translator<'a> {
     }
 }
 
-#[derive(Debug, thiserror::Error, PartialEq, Eq, Clone, Copy)]
+/// Makes sure that every node the output node depends on can be translated,
+/// checking the groups of group nodes in the same way
+fn check(dag: &Dag) -> Result<(), TranslationError> {
+    check_dag(dag, None)
+}
+
+/// Checks the DAG of a group, or the outer DAG if there are no group inputs.
+/// Input nodes of a group that aren't among its inputs aren't connected to
+/// anything once the group is inlined.
+fn check_dag(dag: &Dag, group_inputs: Option<&[GroupInput]>) -> Result<(), TranslationError> {
+    let connected = |id: u32| match (group_inputs, &dag.node(id).unwrap().kind) {
+        (Some(inputs), NodeKind::Input) => inputs.iter().any(|input| input.node == id),
+        _ => true,
+    };
+    let out_node = dag
+        .out_node()
+        .filter(|&id| dag.node(id).is_some() && connected(id))
+        .ok_or(TranslationError::MissingOutput)?;
+    for id in dag.upstream(out_node).into_iter().chain([out_node]) {
+        let kind = &dag.node(id).unwrap().kind;
+        if let NodeKind::Group(node) = kind {
+            let group = dag
+                .group(node.group)
+                .ok_or(TranslationError::MissingGroup(id))?;
+            check_dag(&group.dag, Some(&group.inputs)).map_err(|error| {
+                TranslationError::InGroup {
+                    node: id,
+                    error: Box::new(error),
+                }
+            })?;
+        }
+        // Group nodes may have fewer inputs than their group
+        let mut inputs = kind.inputs();
+        for port in dag.ports(kind).iter() {
+            let missing = match inputs.next().flatten() {
+                Some(input) if dag.node(input).is_none() => true,
+                Some(input) if connected(input) => false,
+                _ => port.is_required(),
+            };
+            if missing {
+                return Err(TranslationError::MissingInput {
+                    node: id,
+                    port: port.name.to_string(),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Inlines groups and optimizes the result. Optimizing after inlining also
+/// merges the nodes that instances of the same group have in common.
+fn prepare(dag: &Dag) -> Dag {
+    optimize(&inline_groups(dag), OptimizeOptions::default()).dag
+}
+
+/// Replaces the group nodes that the output node depends on with the nodes of
+/// their groups, until none are left
+fn inline_groups(dag: &Dag) -> Cow<'_, Dag> {
+    let is_group = |dag: &Dag, id: &u32| matches!(dag.node(*id).unwrap().kind, NodeKind::Group(_));
+    let out_node = dag.out_node().unwrap();
+    let mut groups: Vec<_> = dag
+        .upstream(out_node)
+        .into_iter()
+        .chain([out_node])
+        .filter(|id| is_group(dag, id))
+        .collect();
+    if groups.is_empty() {
+        return Cow::Borrowed(dag);
+    }
+    let mut dag = dag.clone();
+    while let Some(id) = groups.pop() {
+        // Checking the DAG made sure that every group exists
+        let added = dag.expand(id).unwrap();
+        groups.extend(added.into_iter().filter(|id| is_group(&dag, id)));
+    }
+    Cow::Owned(dag)
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CompileError {
+    #[error("{0}")]
+    Module(#[from] Box<ModuleError>),
+    #[error("{0}")]
+    Translation(#[from] TranslationError),
+}
+
+impl From<ModuleError> for CompileError {
+    fn from(error: ModuleError) -> Self {
+        Self::Module(Box::new(error))
+    }
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq, Clone)]
 pub enum TranslationError {
-    #[error("Node {0} is missing an input")]
-    MissingInput(u32),
+    #[error("The output node is missing")]
+    MissingOutput,
+    /// A required input isn't connected, or is connected to a node that
+    /// doesn't exist
+    #[error("Node {node} is missing its {port} input")]
+    MissingInput { node: u32, port: String },
+    #[error("The group of node {0} is missing")]
+    MissingGroup(u32),
+    #[error("In group node {node}: {error}")]
+    InGroup {
+        node: u32,
+        error: Box<TranslationError>,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{dag::Node, group::Group};
+
+    #[test]
+    fn unconnected_inputs() {
+        let mut dag = Dag::new();
+        let x = dag.add_node(Node::with_kind(NodeKind::Input));
+        let quotient = dag.add_node(Node::with_kind(NodeKind::Intrinsic(Intrinsic::Div(
+            Some(x),
+            None,
+        ))));
+        let sum = dag.add_node(Node::with_kind(NodeKind::Intrinsic(Intrinsic::Add(
+            Some(quotient),
+            None,
+        ))));
+        let compile = |dag: &Dag| {
+            Jit::default().compile(dag).map_err(|error| match error {
+                CompileError::Translation(error) => error,
+                CompileError::Module(error) => panic!("{error}"),
+            })
+        };
+        assert_eq!(compile(&dag), Err(TranslationError::MissingOutput));
+
+        // Optional inputs take their defaults
+        dag.set_out_node(sum);
+        let code = compile(&dag).unwrap();
+        let f = unsafe { std::mem::transmute::<*const u8, extern "C" fn(f32) -> f32>(code) };
+        assert_eq!(f(2.5), 2.5);
+
+        dag.remove_input(quotient, 0).unwrap();
+        assert_eq!(
+            compile(&dag),
+            Err(TranslationError::MissingInput {
+                node: quotient,
+                port: "a".to_string()
+            })
+        );
+        dag.add_input(quotient, x, 0).unwrap();
+        dag.remove_vertex(x);
+        assert_eq!(
+            compile(&dag),
+            Err(TranslationError::MissingInput {
+                node: quotient,
+                port: "a".to_string()
+            })
+        );
+
+        // Nodes that the output doesn't depend on aren't checked
+        dag.add_node(Node::default());
+        dag.add_node(Node::with_kind(NodeKind::Passthrough(Some(x))));
+        let one = dag.add_node(Node::with_kind(NodeKind::Constant(1.)));
+        dag.set_out_node(one);
+        assert!(compile(&dag).is_ok());
+
+        // Problems inside groups are reported for the group node
+        let group = dag.add_group(Group::new("empty", Dag::new()));
+        let node = dag.instantiate(group).unwrap();
+        dag.set_out_node(node);
+        assert_eq!(
+            compile(&dag),
+            Err(TranslationError::InGroup {
+                node,
+                error: Box::new(TranslationError::MissingOutput)
+            })
+        );
+
+        // Input nodes that the group doesn't expose aren't connected
+        let mut inner = Dag::new();
+        let hidden = inner.add_node(Node::with_kind(NodeKind::Input));
+        let quotient = inner.add_node(Node::with_kind(NodeKind::Intrinsic(Intrinsic::Div(
+            Some(hidden),
+            None,
+        ))));
+        inner.set_out_node(quotient);
+        let group = dag.add_group(Group::new("hidden", inner));
+        let node = dag.instantiate(group).unwrap();
+        dag.set_out_node(node);
+        assert_eq!(
+            compile(&dag),
+            Err(TranslationError::InGroup {
+                node,
+                error: Box::new(TranslationError::MissingInput {
+                    node: quotient,
+                    port: "a".to_string()
+                })
+            })
+        );
+    }
+    #[test]
+    fn merges_group_instances() {
+        let mut inner = Dag::new();
+        let x = inner.add_node(Node::with_kind(NodeKind::Input));
+        let square = inner.add_node(Node::with_kind(NodeKind::Intrinsic(Intrinsic::Mul(
+            Some(x),
+            Some(x),
+        ))));
+        inner.set_out_node(square);
+        let mut group = Group::new("square", inner);
+        group.inputs.push(GroupInput {
+            name: "x".to_string(),
+            node: x,
+            default: None,
+        });
+
+        let mut dag = Dag::new();
+        let x = dag.add_node(Node::with_kind(NodeKind::Input));
+        let group = dag.add_group(group);
+        let a = dag.instantiate(group).unwrap();
+        let b = dag.instantiate(group).unwrap();
+        dag.add_input(a, x, 0).unwrap();
+        dag.add_input(b, x, 0).unwrap();
+        let sum = dag.add_node(Node::with_kind(NodeKind::Intrinsic(Intrinsic::Add(
+            Some(a),
+            Some(b),
+        ))));
+        dag.set_out_node(sum);
+
+        // x, one x * x, and the sum
+        assert_eq!(prepare(&dag).ids().count(), 3);
+        let code = Jit::default().compile(&dag).unwrap();
+        let f = unsafe { std::mem::transmute::<*const u8, extern "C" fn(f32) -> f32>(code) };
+        assert_eq!(f(3.), 18.);
+    }
 }
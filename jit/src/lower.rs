@@ -0,0 +1,277 @@
+use crate::dag::{Dag, Intrinsic, Node, NodeKind};
+use madeline_parser::{ast::Ast, lexer::Id, lexer::Span, types::Type, NodeKind as AstKind};
+use std::collections::HashMap;
+
+/// A DAG lowered from an expression
+pub struct Lowered {
+    pub dag: Dag,
+    /// The node for each free identifier in the order they first appear, which
+    /// is also the order the compiled function takes them as parameters
+    pub inputs: Vec<(Id, u32)>,
+}
+
+/// Lowers the final expression of a program to a DAG whose output node is the
+/// value of the expression. Calls are inlined and identical subexpressions are
+/// shared. Identifiers that are not bound to a parameter or let statement
+/// become input nodes. The AST must have been type checked, and only float
+/// and integer expressions are supported so far.
+pub fn lower(ast: &Ast) -> Result<Lowered, LowerError> {
+    Lowerer::new(ast).lower()
+}
+
+/// Identifies structurally identical DAG nodes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Key {
+    Constant(u32),
+    Input(Id),
+    Add(u32, u32),
+    Sub(u32, u32),
+    Mul(u32, u32),
+    Div(u32, u32),
+}
+
+enum Task {
+    /// Lowers the children of a node in the given environment
+    Enter(u32, usize),
+    /// Combines the lowered children of a node
+    Exit(u32, usize),
+}
+
+struct Lowerer<'a> {
+    ast: &'a Ast,
+    dag: Dag,
+    /// The lowered value of each parameter and let statement node for each
+    /// inlined call, with the top level at zero
+    environments: Vec<HashMap<u32, u32>>,
+    existing: HashMap<Key, u32>,
+    inputs: Vec<(Id, u32)>,
+}
+
+impl<'a> Lowerer<'a> {
+    fn new(ast: &'a Ast) -> Self {
+        Self {
+            ast,
+            dag: Dag::new(),
+            environments: vec![HashMap::new()],
+            existing: HashMap::new(),
+            inputs: vec![],
+        }
+    }
+
+    fn lower(mut self) -> Result<Lowered, LowerError> {
+        // Expressions can nest deeply, so keep an explicit stack rather than
+        // recursing
+        let mut tasks = vec![Task::Enter(self.ast.root, 0)];
+        let mut values = vec![];
+        while let Some(task) = tasks.pop() {
+            match task {
+                Task::Enter(node, environment) => {
+                    tasks.push(Task::Exit(node, environment));
+                    let node = self.ast.node(node);
+                    let children = match node.kind {
+                        AstKind::Call => self.ast.list(node.children.0).to_vec(),
+                        _ => self.ast.children(node),
+                    };
+                    tasks.extend(
+                        children
+                            .into_iter()
+                            .rev()
+                            .map(|child| Task::Enter(child, environment)),
+                    );
+                }
+
+                Task::Exit(index, environment) => {
+                    let node = self.ast.node(index);
+                    // Integers are represented as floats, which only differ
+                    // for division
+                    match (node.kind, self.ast.types[index as usize]) {
+                        (AstKind::Quotient, Type::Int) => {
+                            return Err(LowerError::Unsupported(node.span))
+                        }
+                        (_, Type::Float | Type::Int) => {}
+                        _ => return Err(LowerError::Unsupported(node.span)),
+                    }
+                    let mut binary = |f: fn(u32, u32) -> Key| {
+                        let b = values.pop().unwrap();
+                        let a = values.pop().unwrap();
+                        f(a, b)
+                    };
+                    let key = match node.kind {
+                        AstKind::Int => {
+                            Key::Constant((self.ast.int(node).unwrap() as f32).to_bits())
+                        }
+                        AstKind::Float => Key::Constant(self.ast.float(node).unwrap().to_bits()),
+                        AstKind::Ident => match self.ast.bindings.get(&index) {
+                            Some(binding) => {
+                                values.push(self.environments[environment][binding]);
+                                continue;
+                            }
+                            None => Key::Input(self.ast.id(node).unwrap()),
+                        },
+                        AstKind::Let => {
+                            let value = values.pop().unwrap();
+                            self.environments[environment].insert(index, value);
+                            continue;
+                        }
+                        // The value of the final expression is already on
+                        // the stack
+                        AstKind::Block => continue,
+                        AstKind::Sum => binary(Key::Add),
+                        AstKind::Difference => binary(Key::Sub),
+                        AstKind::Product => binary(Key::Mul),
+                        AstKind::Quotient => binary(Key::Div),
+                        AstKind::Negation => {
+                            let child = values.pop().unwrap();
+                            let negative_one = self.node(Key::Constant((-1f32).to_bits()));
+                            Key::Mul(negative_one, child)
+                        }
+                        AstKind::Call => {
+                            let function =
+                                self.ast.id(node).and_then(|id| self.ast.symbols.get(&id));
+                            let Some(&function) = function else {
+                                return Err(LowerError::Invalid(node.span));
+                            };
+                            let function = self.ast.node(function);
+                            let parameters = self.ast.list(function.children.0);
+                            if self.ast.list(node.children.0).len() != parameters.len() {
+                                return Err(LowerError::Invalid(node.span));
+                            }
+                            let arguments = values.split_off(values.len() - parameters.len());
+                            let bindings = parameters.iter().copied().zip(arguments).collect();
+                            self.environments.push(bindings);
+                            let body = function.children.1;
+                            tasks.push(Task::Enter(body, self.environments.len() - 1));
+                            continue;
+                        }
+                        AstKind::Not
+                        | AstKind::Less
+                        | AstKind::LessEqual
+                        | AstKind::Greater
+                        | AstKind::GreaterEqual
+                        | AstKind::Equal
+                        | AstKind::NotEqual
+                        | AstKind::And
+                        | AstKind::Or
+                        | AstKind::If
+                        | AstKind::Construct
+                        | AstKind::Swizzle
+                        | AstKind::String => return Err(LowerError::Unsupported(node.span)),
+                        AstKind::Function | AstKind::Parameter | AstKind::Error => {
+                            return Err(LowerError::Invalid(node.span))
+                        }
+                    };
+                    values.push(self.node(key));
+                }
+            }
+        }
+
+        let out = values.pop().unwrap();
+        self.dag.set_out_node(out);
+        Ok(Lowered {
+            dag: self.dag,
+            inputs: self.inputs,
+        })
+    }
+
+    /// Gets the node for the key, adding it to the DAG if it doesn't exist yet
+    fn node(&mut self, key: Key) -> u32 {
+        if let Some(&existing) = self.existing.get(&key) {
+            return existing;
+        }
+        let kind = match key {
+            Key::Constant(bits) => NodeKind::Constant(f32::from_bits(bits)),
+            Key::Input(_) => NodeKind::Input,
+            Key::Add(a, b) => NodeKind::Intrinsic(Intrinsic::Add(Some(a), Some(b))),
+            Key::Sub(a, b) => NodeKind::Intrinsic(Intrinsic::Sub(Some(a), Some(b))),
+            Key::Mul(a, b) => NodeKind::Intrinsic(Intrinsic::Mul(Some(a), Some(b))),
+            Key::Div(a, b) => NodeKind::Intrinsic(Intrinsic::Div(Some(a), Some(b))),
+        };
+        let id = self.dag.add_node(Node::with_kind(kind));
+        if let Key::Input(input) = key {
+            self.inputs.push((input, id));
+        }
+        self.existing.insert(key, id);
+        id
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum LowerError {
+    #[error("The expression cannot be lowered yet")]
+    Unsupported(Span),
+    #[error("The expression contains errors")]
+    Invalid(Span),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jit::Jit;
+    use madeline_parser::parse;
+
+    fn lower_source(s: &str) -> Lowered {
+        lower(&parse(s).into_result().unwrap()).unwrap()
+    }
+
+    fn compile(s: &str) -> (*const u8, Lowered) {
+        let lowered = lower_source(s);
+        let code = Jit::default().compile(&lowered.dag).unwrap();
+        (code, lowered)
+    }
+
+    #[test]
+    fn constant() {
+        let (code, lowered) = compile("1.5 * 2");
+        assert!(lowered.inputs.is_empty());
+        let f = unsafe { std::mem::transmute::<*const u8, extern "C" fn() -> f32>(code) };
+        assert_eq!(f(), 3.);
+    }
+
+    #[test]
+    fn end_to_end() {
+        let s = "fn sq(x) x * x; fn lerp(a, b, t) a + (b - a) * t; lerp(b, a, 0.25) - -sq(a)";
+        let ast = parse(s).into_result().unwrap();
+        let (code, lowered) = compile(s);
+        let names: Vec<_> = lowered.inputs.iter().map(|&(id, _)| ast.name(id)).collect();
+        assert_eq!(names, ["b", "a"]);
+        let f = unsafe { std::mem::transmute::<*const u8, extern "C" fn(f32, f32) -> f32>(code) };
+        assert_eq!(f(2., 6.), 3. + 36.);
+    }
+
+    #[test]
+    fn shares_subexpressions() {
+        // x, 1, x + 1, and the product
+        let lowered = lower_source("(x + 1) * (x + 1)");
+        assert_eq!(lowered.dag.ids().count(), 4);
+
+        // Inlined calls with the same arguments are shared too
+        let lowered = lower_source("fn f(a) a / 2; f(y) - f(y)");
+        assert_eq!(lowered.dag.ids().count(), 4);
+    }
+
+    #[test]
+    fn blocks() {
+        let s = "fn f(x) { let y = x * 2; { let y = y + 1; y } * y }; { let a = f(x); let x = 3; a - x }";
+        let (code, lowered) = compile(s);
+        assert_eq!(lowered.inputs.len(), 1);
+        let f = unsafe { std::mem::transmute::<*const u8, extern "C" fn(f32) -> f32>(code) };
+        // y = 4, (4 + 1) * 4 - 3
+        assert_eq!(f(2.), 17.);
+    }
+
+    #[test]
+    fn unsupported() {
+        for s in ["x < 1", "fn f(v: vec3) v; f(x) * 2", "2 / 3 + 1"] {
+            let ast = parse(s).into_result().unwrap();
+            assert!(matches!(lower(&ast), Err(LowerError::Unsupported(_))));
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        for s in ["fn f(x, y) x; f(1)", "fn f(x) x; f(1, 2)"] {
+            let ast = parse(s).ast;
+            assert!(matches!(lower(&ast), Err(LowerError::Invalid(_))), "{s}");
+        }
+    }
+}
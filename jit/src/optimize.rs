@@ -0,0 +1,438 @@
+use crate::dag::{Dag, Intrinsic, NodeKind, Port, PortInfo};
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct OptimizeOptions {
+    /// Assume that values are never infinite or NaN and that the sign of zero
+    /// doesn't matter. This allows x * 0 to fold to 0 and x + 0 to x, which
+    /// doesn't hold for every IEEE 754 value.
+    pub finite_math: bool,
+}
+
+/// Something that [`optimize`] changed about a DAG
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Change {
+    /// The node was replaced by a constant
+    Folded { node: u32, value: f32 },
+    /// Consumers of the node use one of its inputs instead
+    Simplified { node: u32, replacement: u32 },
+    /// Consumers of the passthrough node use the node at the end of the chain
+    Collapsed { node: u32, replacement: u32 },
+    /// Consumers of the node use an identical node instead
+    Merged { node: u32, replacement: u32 },
+    /// The node no longer contributes to the output
+    Removed(u32),
+}
+
+/// A DAG that computes the same output as the one it was optimized from
+pub struct Optimized {
+    pub dag: Dag,
+    /// Every change in the order it was made, with removals last
+    pub changes: Vec<Change>,
+}
+
+/// Folds intrinsics with constant inputs, applies arithmetic identities,
+/// collapses passthrough chains, merges nodes that compute the same value and
+/// removes the nodes that the output node no longer depends on. Remaining nodes
+/// keep their IDs and positions. Input nodes are never removed since they are
+/// the parameters of the compiled function.
+pub fn optimize(dag: &Dag, options: OptimizeOptions) -> Optimized {
+    let mut dag = dag.clone();
+    let mut changes = vec![];
+    // The node that consumers of each replaced node should use instead
+    let mut replacements = HashMap::new();
+    let mut existing = HashMap::new();
+    for id in post_order(&dag, dag.out_node()) {
+        let resolve = |input: Port| {
+            input.map(|input| match replacements.get(&input) {
+                Some(&replacement) => replacement,
+                None => input,
+            })
+        };
+        // Unconnected ports take their default value
+        let constant = |input: Port, port: &PortInfo| match input {
+            Some(input) => match dag.node(input)?.kind {
+                NodeKind::Constant(value) => Some(value),
+                _ => None,
+            },
+            None => port.default,
+        };
+        // Unconnected and missing nodes are left alone so that the output
+        // node stays valid
+        let replace = |replacement: Port| replacement.filter(|&id| dag.node(id).is_some());
+
+        let kind = match dag.node(id).unwrap().kind.clone() {
+            NodeKind::Passthrough(input) => {
+                if let Some(replacement) = replace(resolve(input)) {
+                    replacements.insert(id, replacement);
+                    changes.push(Change::Collapsed {
+                        node: id,
+                        replacement,
+                    });
+                }
+                continue;
+            }
+            NodeKind::Intrinsic(intrinsic) => {
+                let intrinsic = intrinsic.map_inputs(resolve);
+                let (a, b) = operands(intrinsic);
+                let ports = intrinsic.ports();
+                let (a, b) = (constant(a, &ports[0]), constant(b, &ports[1]));
+                match simplify(intrinsic, a, b, options) {
+                    Some(Simplification::Constant(value)) => {
+                        changes.push(Change::Folded { node: id, value });
+                        NodeKind::Constant(value)
+                    }
+                    Some(Simplification::Operand(operand)) => {
+                        if let Some(replacement) = replace(operand) {
+                            replacements.insert(id, replacement);
+                            changes.push(Change::Simplified {
+                                node: id,
+                                replacement,
+                            });
+                            continue;
+                        }
+                        NodeKind::Intrinsic(intrinsic)
+                    }
+                    None => NodeKind::Intrinsic(intrinsic),
+                }
+            }
+            NodeKind::Constant(value) => NodeKind::Constant(value),
+            // Groups are only optimized once they are inlined
+            kind @ NodeKind::Group(_) => kind.map_inputs(resolve),
+            NodeKind::Input => continue,
+        };
+
+        // Inputs are visited first, so identical nodes have the same key
+        match key(&kind).map(|key| *existing.entry(key).or_insert(id)) {
+            Some(replacement) if replacement != id => {
+                replacements.insert(id, replacement);
+                changes.push(Change::Merged {
+                    node: id,
+                    replacement,
+                });
+            }
+            _ => dag.set_kind(id, kind),
+        }
+    }
+
+    if let Some(&out) = dag.out_node().and_then(|out| replacements.get(&out)) {
+        dag.set_out_node(out);
+    }
+    let used: HashSet<_> = post_order(&dag, dag.out_node()).into_iter().collect();
+    let mut unused: Vec<_> = dag
+        .iter()
+        .filter(|(id, node)| !used.contains(id) && node.kind != NodeKind::Input)
+        .map(|(&id, _)| id)
+        .collect();
+    unused.sort_unstable();
+    for id in unused {
+        dag.remove_vertex(id);
+        changes.push(Change::Removed(id));
+    }
+    Optimized { dag, changes }
+}
+
+/// Identifies structurally identical nodes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Key {
+    Constant(u32),
+    Intrinsic(Intrinsic),
+}
+
+fn key(kind: &NodeKind) -> Option<Key> {
+    Some(match *kind {
+        NodeKind::Constant(value) => Key::Constant(value.to_bits()),
+        // Order the operands of commutative intrinsics so that a + b and
+        // b + a get the same key. Only the second operand is optional, so
+        // swapping an unconnected one would change the value.
+        NodeKind::Intrinsic(Intrinsic::Add(Some(a), Some(b))) => {
+            Key::Intrinsic(Intrinsic::Add(Some(a.min(b)), Some(a.max(b))))
+        }
+        NodeKind::Intrinsic(Intrinsic::Mul(Some(a), Some(b))) => {
+            Key::Intrinsic(Intrinsic::Mul(Some(a.min(b)), Some(a.max(b))))
+        }
+        NodeKind::Intrinsic(intrinsic) => Key::Intrinsic(intrinsic),
+        // Every input is a different parameter
+        NodeKind::Input | NodeKind::Passthrough(_) | NodeKind::Group(_) => return None,
+    })
+}
+
+enum Simplification {
+    Constant(f32),
+    Operand(Port),
+}
+
+fn simplify(
+    intrinsic: Intrinsic,
+    a: Option<f32>,
+    b: Option<f32>,
+    options: OptimizeOptions,
+) -> Option<Simplification> {
+    if let (Some(a), Some(b)) = (a, b) {
+        return Some(Simplification::Constant(match intrinsic {
+            Intrinsic::Add(..) => a + b,
+            Intrinsic::Sub(..) => a - b,
+            Intrinsic::Mul(..) => a * b,
+            Intrinsic::Div(..) => a / b,
+        }));
+    }
+
+    // Compare bits to tell the zeros apart
+    let is =
+        |constant: Option<f32>, value: f32| constant.map(f32::to_bits) == Some(value.to_bits());
+    let finite_zero = |constant: Option<f32>| options.finite_math && constant == Some(0.);
+    Some(match intrinsic {
+        // Only -0 is an identity for addition since -0 + 0 = 0
+        Intrinsic::Add(x, _) if is(b, -0.) || finite_zero(b) => Simplification::Operand(x),
+        Intrinsic::Add(_, y) if is(a, -0.) || finite_zero(a) => Simplification::Operand(y),
+        Intrinsic::Sub(x, _) if is(b, 0.) || finite_zero(b) => Simplification::Operand(x),
+        Intrinsic::Mul(x, _) if is(b, 1.) => Simplification::Operand(x),
+        Intrinsic::Mul(_, y) if is(a, 1.) => Simplification::Operand(y),
+        Intrinsic::Mul(..) if finite_zero(a) || finite_zero(b) => Simplification::Constant(0.),
+        Intrinsic::Div(x, _) if is(b, 1.) => Simplification::Operand(x),
+        _ => return None,
+    })
+}
+
+fn operands(intrinsic: Intrinsic) -> (Port, Port) {
+    match intrinsic {
+        Intrinsic::Add(a, b)
+        | Intrinsic::Sub(a, b)
+        | Intrinsic::Mul(a, b)
+        | Intrinsic::Div(a, b) => (a, b),
+    }
+}
+
+/// Gets the nodes that the root depends on, including itself, with each node
+/// after all of its inputs
+fn post_order(dag: &Dag, root: Option<u32>) -> Vec<u32> {
+    let mut order = vec![];
+    let mut visited = HashSet::new();
+    // A node is pushed again with the flag set once its inputs are pushed, so
+    // it is popped and ordered after all of them
+    let mut stack: Vec<_> = root.map(|root| (root, false)).into_iter().collect();
+    while let Some((id, inputs_done)) = stack.pop() {
+        if inputs_done {
+            order.push(id);
+            continue;
+        }
+        let Some(node) = dag.node(id) else {
+            continue;
+        };
+        if visited.insert(id) {
+            stack.push((id, true));
+            stack.extend(node.inputs().flatten().map(|input| (input, false)));
+        }
+    }
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{dag::Node, jit::Jit, lower::lower};
+    use madeline_parser::parse;
+
+    fn optimize_source(s: &str, options: OptimizeOptions) -> (Dag, Optimized) {
+        let dag = lower(&parse(s).into_result().unwrap()).unwrap().dag;
+        let optimized = optimize(&dag, options);
+        (dag, optimized)
+    }
+
+    fn out_kind(dag: &Dag) -> NodeKind {
+        dag.node(dag.out_node().unwrap()).unwrap().kind.clone()
+    }
+
+    #[test]
+    fn folds_constants() {
+        let (before, optimized) =
+            optimize_source("x + 1.5 * 2 / (4 - 3)", OptimizeOptions::default());
+        assert_eq!(before.ids().count(), 9);
+        // x, 3 and the sum
+        assert_eq!(optimized.dag.ids().count(), 3);
+        let NodeKind::Intrinsic(Intrinsic::Add(_, Some(three))) = out_kind(&optimized.dag) else {
+            panic!("Expected a sum");
+        };
+        assert_eq!(
+            optimized.dag.node(three).unwrap().kind,
+            NodeKind::Constant(3.)
+        );
+        // The folded product is merged into the 3 of the difference
+        assert!(optimized.changes.iter().any(|change| matches!(
+            change,
+            Change::Folded { node, value: 3. } if *node != three
+        )));
+    }
+
+    #[test]
+    fn identities() {
+        let input = |dag: &Dag| {
+            dag.iter()
+                .find(|(_, node)| node.kind == NodeKind::Input)
+                .map(|(&id, _)| id)
+                .unwrap()
+        };
+        for s in [
+            "x * 1",
+            "1 * x",
+            "x / 1",
+            "x - 0",
+            "x + -0.",
+            "-0. + (x * 1 - 0) / 1",
+        ] {
+            let (_, optimized) = optimize_source(s, OptimizeOptions::default());
+            assert_eq!(optimized.dag.out_node(), Some(input(&optimized.dag)), "{s}");
+            assert_eq!(optimized.dag.ids().count(), 1, "{s}");
+        }
+
+        // These change the result for some values
+        let finite_math = OptimizeOptions { finite_math: true };
+        for s in ["x + 0", "0 + x", "x - -0."] {
+            let (_, optimized) = optimize_source(s, OptimizeOptions::default());
+            assert!(
+                matches!(out_kind(&optimized.dag), NodeKind::Intrinsic(_)),
+                "{s}"
+            );
+            let (_, optimized) = optimize_source(s, finite_math);
+            assert_eq!(optimized.dag.out_node(), Some(input(&optimized.dag)), "{s}");
+        }
+        for s in ["x * 0", "0 * x"] {
+            let (_, optimized) = optimize_source(s, OptimizeOptions::default());
+            assert!(
+                matches!(out_kind(&optimized.dag), NodeKind::Intrinsic(_)),
+                "{s}"
+            );
+            let (_, optimized) = optimize_source(s, finite_math);
+            assert_eq!(out_kind(&optimized.dag), NodeKind::Constant(0.), "{s}");
+            // The unused input is still a parameter
+            assert_eq!(optimized.dag.ids().count(), 2, "{s}");
+        }
+    }
+
+    #[test]
+    fn passthrough_chains() {
+        let mut dag = Dag::new();
+        let x = dag.add_node(Node::with_kind(NodeKind::Input));
+        let first = dag.add_node(Node::with_kind(NodeKind::Passthrough(Some(x))));
+        let second = dag.add_node(Node::with_kind(NodeKind::Passthrough(Some(first))));
+        let sum = dag.add_node(Node::with_kind(NodeKind::Intrinsic(Intrinsic::Add(
+            Some(second),
+            Some(first),
+        ))));
+        let out = dag.add_node(Node::with_kind(NodeKind::Passthrough(Some(sum))));
+        let unused = dag.add_node(Node::with_kind(NodeKind::Constant(1.)));
+        dag.set_out_node(out);
+
+        let optimized = optimize(&dag, OptimizeOptions::default());
+        assert_eq!(optimized.dag.out_node(), Some(sum));
+        assert_eq!(
+            out_kind(&optimized.dag),
+            NodeKind::Intrinsic(Intrinsic::Add(Some(x), Some(x)))
+        );
+        assert_eq!(
+            optimized.changes,
+            [
+                Change::Collapsed {
+                    node: first,
+                    replacement: x
+                },
+                Change::Collapsed {
+                    node: second,
+                    replacement: x
+                },
+                Change::Collapsed {
+                    node: out,
+                    replacement: sum
+                },
+                Change::Removed(first),
+                Change::Removed(second),
+                Change::Removed(out),
+                Change::Removed(unused),
+            ]
+        );
+    }
+
+    #[test]
+    fn merges_duplicates() {
+        let mut dag = Dag::new();
+        let mut add = |kind| dag.add_node(Node::with_kind(kind));
+        let x = add(NodeKind::Input);
+        let y = add(NodeKind::Input);
+        let first_sum = add(NodeKind::Intrinsic(Intrinsic::Add(Some(x), Some(y))));
+        let second_sum = add(NodeKind::Intrinsic(Intrinsic::Add(Some(y), Some(x))));
+        let first_two = add(NodeKind::Constant(2.));
+        let second_two = add(NodeKind::Constant(2.));
+        let first_product = add(NodeKind::Intrinsic(Intrinsic::Mul(
+            Some(first_sum),
+            Some(first_two),
+        )));
+        let second_product = add(NodeKind::Intrinsic(Intrinsic::Mul(
+            Some(second_two),
+            Some(second_sum),
+        )));
+        let first_difference = add(NodeKind::Intrinsic(Intrinsic::Sub(Some(x), Some(y))));
+        let second_difference = add(NodeKind::Intrinsic(Intrinsic::Sub(Some(y), Some(x))));
+        let products = add(NodeKind::Intrinsic(Intrinsic::Div(
+            Some(first_product),
+            Some(second_product),
+        )));
+        let differences = add(NodeKind::Intrinsic(Intrinsic::Div(
+            Some(first_difference),
+            Some(second_difference),
+        )));
+        let out = add(NodeKind::Intrinsic(Intrinsic::Add(
+            Some(products),
+            Some(differences),
+        )));
+        dag.set_out_node(out);
+
+        let optimized = optimize(&dag, OptimizeOptions::default());
+        let merged: Vec<_> = optimized
+            .changes
+            .iter()
+            .filter_map(|change| match *change {
+                Change::Merged { node, .. } => Some(node),
+                _ => None,
+            })
+            .collect();
+        // Whichever node is visited first is kept
+        assert_eq!(merged.len(), 3);
+        for pair in [
+            [first_sum, second_sum],
+            [first_two, second_two],
+            [first_product, second_product],
+        ] {
+            assert!(merged.contains(&pair[0]) != merged.contains(&pair[1]));
+        }
+        let kind = |id| optimized.dag.node(id).unwrap().kind.clone();
+        let NodeKind::Intrinsic(Intrinsic::Div(a, b)) = kind(products) else {
+            panic!("Expected a quotient");
+        };
+        assert_eq!(a, b);
+        assert_eq!(
+            kind(differences),
+            NodeKind::Intrinsic(Intrinsic::Div(
+                Some(first_difference),
+                Some(second_difference)
+            ))
+        );
+        // x, y, one sum, two and product, both differences, both quotients
+        // and the output
+        assert_eq!(optimized.dag.ids().count(), 10);
+    }
+
+    #[test]
+    fn same_result() {
+        let s = "fn sq(x) x * x; fn lerp(a, b, t) a + (b - a) * t; lerp(b, a, 0.25 * 2 - 0) * 1 - -sq(a) / (3 - 2)";
+        let (before, optimized) = optimize_source(s, OptimizeOptions::default());
+        assert!(optimized.dag.ids().count() < before.ids().count());
+        let results = [before, optimized.dag].map(|dag| {
+            let code = Jit::default().compile(&dag).unwrap();
+            let f =
+                unsafe { std::mem::transmute::<*const u8, extern "C" fn(f32, f32) -> f32>(code) };
+            [f(2., 6.), f(-1.5, 0.), f(0., f32::INFINITY)]
+        });
+        assert_eq!(results[0], results[1]);
+        assert_eq!(results[0], [4. + 36., -0.75, f32::INFINITY]);
+    }
+}
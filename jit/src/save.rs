@@ -0,0 +1,521 @@
+use crate::{
+    dag::{Dag, Node, NodeKind, Port, V2},
+    group::{Group, GroupInput, Knob},
+};
+use ron::ser::PrettyConfig;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fmt::{self, Write},
+};
+
+/// The version of the format that DAGs are saved with. New fields don't
+/// change it since loading ignores fields it doesn't know about, so only
+/// changes that older versions would misread need a new version.
+pub const VERSION: u32 = 3;
+
+/// The layout of a saved DAG, shared by the text and binary formats
+#[derive(Debug, Serialize, Deserialize)]
+struct Document {
+    version: u32,
+    out_node: Option<u32>,
+    next_node: u32,
+    /// Sorted by ID so that saving a DAG always gives the same result
+    nodes: Vec<SavedNode>,
+    /// Also sorted by ID
+    groups: Vec<SavedGroup>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedNode {
+    id: u32,
+    kind: NodeKind,
+    #[serde(default)]
+    position: V2,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedGroup {
+    id: u32,
+    name: String,
+    inputs: Vec<GroupInput>,
+    #[serde(default)]
+    knobs: Vec<Knob>,
+    dag: Document,
+}
+
+/// Read ahead of the rest of the document, which a newer version may have
+/// laid out differently
+#[derive(Deserialize)]
+struct Header {
+    version: u32,
+}
+
+/// Saves the DAG as RON text with one line per node, including the nodes of
+/// groups
+pub fn save_text(dag: &Dag) -> String {
+    let mut text = String::new();
+    // Writing to a string can't fail
+    Document::new(dag).write_text(&mut text, "").unwrap();
+    text
+}
+
+pub fn load_text(text: &str) -> Result<Dag, LoadError> {
+    match check_version(ron::from_str::<Header>(text)?.version)? {
+        1 => ron::from_str::<v1::Document>(text)?.upgrade(),
+        2 => ron::from_str::<v2::Document>(text)?.upgrade(),
+        _ => ron::from_str::<Document>(text)?,
+    }
+    .into_dag()
+}
+
+/// Saves the DAG as CBOR, which is smaller than the text format but keeps
+/// field names so that it can be extended in the same way
+pub fn save_binary(dag: &Dag) -> Vec<u8> {
+    let mut bytes = vec![];
+    // Writing to a vector can't fail
+    ciborium::into_writer(&Document::new(dag), &mut bytes).unwrap();
+    bytes
+}
+
+pub fn load_binary(bytes: &[u8]) -> Result<Dag, LoadError> {
+    match check_version(ciborium::from_reader::<Header, _>(bytes)?.version)? {
+        1 => ciborium::from_reader::<v1::Document, _>(bytes)?.upgrade(),
+        2 => ciborium::from_reader::<v2::Document, _>(bytes)?.upgrade(),
+        _ => ciborium::from_reader::<Document, _>(bytes)?,
+    }
+    .into_dag()
+}
+
+fn check_version(version: u32) -> Result<u32, LoadError> {
+    if version > VERSION {
+        Err(LoadError::UnsupportedVersion(version))
+    } else {
+        Ok(version)
+    }
+}
+
+/// Version 1 stored ports as node IDs, with zero for unconnected ports and
+/// for a missing output node
+mod v1 {
+    use super::{Port, SavedNode, V2};
+    use crate::dag;
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    pub struct Document {
+        out_node: u32,
+        next_node: u32,
+        nodes: Vec<Node>,
+    }
+
+    #[derive(Deserialize)]
+    struct Node {
+        id: u32,
+        kind: NodeKind,
+        #[serde(default)]
+        position: V2,
+    }
+
+    #[derive(Deserialize)]
+    enum NodeKind {
+        Passthrough(u32),
+        Intrinsic(Intrinsic),
+        Input,
+        Constant(f32),
+    }
+
+    #[derive(Deserialize)]
+    enum Intrinsic {
+        Add(u32, u32),
+        Sub(u32, u32),
+        Mul(u32, u32),
+        Div(u32, u32),
+    }
+
+    fn port(id: u32) -> Port {
+        (id != 0).then_some(id)
+    }
+
+    impl Document {
+        pub fn upgrade(self) -> super::Document {
+            let nodes = self
+                .nodes
+                .into_iter()
+                .map(|node| SavedNode {
+                    id: node.id,
+                    kind: node.kind.upgrade(),
+                    position: node.position,
+                })
+                .collect();
+            super::Document {
+                version: super::VERSION,
+                out_node: port(self.out_node),
+                next_node: self.next_node,
+                nodes,
+                groups: vec![],
+            }
+        }
+    }
+
+    impl NodeKind {
+        fn upgrade(self) -> dag::NodeKind {
+            match self {
+                Self::Passthrough(input) => dag::NodeKind::Passthrough(port(input)),
+                Self::Intrinsic(intrinsic) => dag::NodeKind::Intrinsic(match intrinsic {
+                    Intrinsic::Add(a, b) => dag::Intrinsic::Add(port(a), port(b)),
+                    Intrinsic::Sub(a, b) => dag::Intrinsic::Sub(port(a), port(b)),
+                    Intrinsic::Mul(a, b) => dag::Intrinsic::Mul(port(a), port(b)),
+                    Intrinsic::Div(a, b) => dag::Intrinsic::Div(port(a), port(b)),
+                }),
+                Self::Input => dag::NodeKind::Input,
+                Self::Constant(value) => dag::NodeKind::Constant(value),
+            }
+        }
+    }
+}
+
+/// Version 2 had no groups, which it would ignore rather than reject
+mod v2 {
+    use super::SavedNode;
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    pub struct Document {
+        out_node: Option<u32>,
+        next_node: u32,
+        nodes: Vec<SavedNode>,
+    }
+
+    impl Document {
+        pub fn upgrade(self) -> super::Document {
+            super::Document {
+                version: super::VERSION,
+                out_node: self.out_node,
+                next_node: self.next_node,
+                nodes: self.nodes,
+                groups: vec![],
+            }
+        }
+    }
+}
+
+/// Serializes a value as RON on a single line
+fn line(value: &impl Serialize) -> String {
+    // Serializing to memory only fails for types that RON can't represent
+    ron::ser::to_string_pretty(value, PrettyConfig::new().depth_limit(0)).unwrap()
+}
+
+/// Writes a field holding a list with one item per line
+fn write_list<T>(
+    out: &mut String,
+    indent: &str,
+    name: &str,
+    items: &[T],
+    mut write: impl FnMut(&mut String, &T, &str) -> fmt::Result,
+) -> fmt::Result {
+    if items.is_empty() {
+        return writeln!(out, "{indent}{name}: [],");
+    }
+    let inner = format!("{indent}    ");
+    writeln!(out, "{indent}{name}: [")?;
+    for item in items {
+        out.push_str(&inner);
+        write(out, item, &inner)?;
+        out.push_str(",\n");
+    }
+    writeln!(out, "{indent}],")
+}
+
+impl Document {
+    fn new(dag: &Dag) -> Self {
+        let mut nodes: Vec<_> = dag
+            .iter()
+            .map(|(&id, node)| SavedNode {
+                id,
+                kind: node.kind.clone(),
+                position: node.position,
+            })
+            .collect();
+        nodes.sort_unstable_by_key(|node| node.id);
+        let mut groups: Vec<_> = dag
+            .groups()
+            .map(|(&id, group)| SavedGroup {
+                id,
+                name: group.name.clone(),
+                inputs: group.inputs.clone(),
+                knobs: group.knobs.clone(),
+                dag: Self::new(&group.dag),
+            })
+            .collect();
+        groups.sort_unstable_by_key(|group| group.id);
+        Self {
+            version: VERSION,
+            out_node: dag.out_node(),
+            next_node: dag.next_node(),
+            nodes,
+            groups,
+        }
+    }
+
+    /// Writes the document like RON's pretty printer, but with every node on
+    /// a line of its own however deeply its group is nested, which a depth
+    /// limit can't do
+    fn write_text(&self, out: &mut String, indent: &str) -> fmt::Result {
+        let inner = format!("{indent}    ");
+        writeln!(out, "(")?;
+        writeln!(out, "{inner}version: {},", self.version)?;
+        writeln!(out, "{inner}out_node: {},", line(&self.out_node))?;
+        writeln!(out, "{inner}next_node: {},", self.next_node)?;
+        write_list(out, &inner, "nodes", &self.nodes, |out, node, _| {
+            out.push_str(&line(node));
+            Ok(())
+        })?;
+        write_list(out, &inner, "groups", &self.groups, |out, group, indent| {
+            group.write_text(out, indent)
+        })?;
+        write!(out, "{indent})")
+    }
+
+    fn into_dag(self) -> Result<Dag, LoadError> {
+        let mut nodes = HashMap::with_capacity(self.nodes.len());
+        for node in self.nodes {
+            // Otherwise adding a node could replace this one
+            if node.id >= self.next_node {
+                return Err(LoadError::NodeId(node.id));
+            }
+            match nodes.entry(node.id) {
+                Entry::Occupied(_) => return Err(LoadError::DuplicateNode(node.id)),
+                Entry::Vacant(entry) => {
+                    entry.insert(Node::with_kind(node.kind).positioned(node.position));
+                }
+            }
+        }
+        let mut groups = HashMap::with_capacity(self.groups.len());
+        for group in self.groups {
+            match groups.entry(group.id) {
+                Entry::Occupied(_) => return Err(LoadError::DuplicateGroup(group.id)),
+                Entry::Vacant(entry) => {
+                    entry.insert(Group {
+                        name: group.name,
+                        dag: group.dag.into_dag()?,
+                        inputs: group.inputs,
+                        knobs: group.knobs,
+                    });
+                }
+            }
+        }
+        Dag::from_parts(self.out_node, self.next_node, nodes, groups).map_err(LoadError::Cycle)
+    }
+}
+
+impl SavedGroup {
+    fn write_text(&self, out: &mut String, indent: &str) -> fmt::Result {
+        let inner = format!("{indent}    ");
+        writeln!(out, "(")?;
+        writeln!(out, "{inner}id: {},", self.id)?;
+        writeln!(out, "{inner}name: {},", line(&self.name))?;
+        writeln!(out, "{inner}inputs: {},", line(&self.inputs))?;
+        writeln!(out, "{inner}knobs: {},", line(&self.knobs))?;
+        write!(out, "{inner}dag: ")?;
+        self.dag.write_text(out, &inner)?;
+        writeln!(out, ",")?;
+        write!(out, "{indent})")
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum LoadError {
+    #[error("{0}")]
+    Text(#[from] ron::error::SpannedError),
+    #[error("{0}")]
+    Binary(#[from] ciborium::de::Error<std::io::Error>),
+    #[error("Version {0} was saved by a newer version of Madeline")]
+    UnsupportedVersion(u32),
+    #[error("Node {0} appears more than once")]
+    DuplicateNode(u32),
+    #[error("Group {0} appears more than once")]
+    DuplicateGroup(u32),
+    #[error("Node {0} is not below the next node ID")]
+    NodeId(u32),
+    #[error("Node {0} is part of a cycle")]
+    Cycle(u32),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dag::Intrinsic;
+
+    fn dag() -> Dag {
+        let mut dag = Dag::new();
+        let mut add = |kind, x, y| dag.add_node(Node::with_kind(kind).positioned(V2 { x, y }));
+        let x = add(NodeKind::Input, 0, 0);
+        let tenth = add(NodeKind::Constant(0.1), 0, 100);
+        let removed = add(NodeKind::Constant(-2.5e-7), 0, 200);
+        let sum = add(
+            NodeKind::Intrinsic(Intrinsic::Add(Some(x), Some(tenth))),
+            150,
+            -50,
+        );
+        // Unconnected
+        let passthrough = add(NodeKind::Passthrough(None), -300, 0);
+        let out = add(
+            NodeKind::Intrinsic(Intrinsic::Div(Some(sum), Some(passthrough))),
+            300,
+            0,
+        );
+        dag.set_out_node(out);
+        dag.remove_vertex(removed);
+        dag
+    }
+
+    #[test]
+    fn text_round_trip() {
+        let dag = dag();
+        let text = save_text(&dag);
+        assert_eq!(load_text(&text).unwrap(), dag);
+        assert_eq!(save_text(&load_text(&text).unwrap()), text);
+        assert_eq!(
+            text.lines().find(|line| line.contains("id: 4")),
+            Some("        (id: 4, kind: Intrinsic(Add(Some(1), Some(2))), position: (x: 150, y: -50)),")
+        );
+        assert_eq!(load_text(&save_text(&Dag::new())).unwrap(), Dag::new());
+
+        let mut grouped = dag;
+        grouped.collapse(&[2, 4], "sum").unwrap();
+        let text = save_text(&grouped);
+        assert_eq!(load_text(&text).unwrap(), grouped);
+        assert_eq!(save_text(&load_text(&text).unwrap()), text);
+        assert_eq!(
+            text.lines()
+                .find(|line| line.contains("kind: Constant(0.1)")),
+            Some("                    (id: 2, kind: Constant(0.1), position: (x: -75, y: 75)),")
+        );
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        let dag = dag();
+        let bytes = save_binary(&dag);
+        assert_eq!(load_binary(&bytes).unwrap(), dag);
+        assert!(bytes.len() < save_text(&dag).len());
+        assert_eq!(load_binary(&save_binary(&Dag::new())).unwrap(), Dag::new());
+    }
+
+    #[test]
+    fn unknown_fields() {
+        let text = "(
+            version: 2,
+            out_node: Some(2),
+            next_node: 3,
+            author: \"someone\",
+            nodes: [
+                (id: 1, kind: Input, color: (1, 0, 0)),
+                (id: 2, kind: Passthrough(Some(1)), position: (x: 5, y: 6, z: 7)),
+            ],
+        )";
+        let dag = load_text(text).unwrap();
+        assert_eq!(dag.node(1).unwrap().position, V2::default());
+        assert_eq!(dag.node(2).unwrap().position, V2 { x: 5, y: 6 });
+
+        #[derive(Serialize)]
+        struct Newer {
+            version: u32,
+            out_node: Option<u32>,
+            next_node: u32,
+            nodes: Vec<SavedNode>,
+            groups: Vec<SavedGroup>,
+            comments: Vec<String>,
+        }
+        let mut bytes = vec![];
+        let newer = Newer {
+            version: VERSION,
+            out_node: Some(1),
+            next_node: 2,
+            nodes: vec![SavedNode {
+                id: 1,
+                kind: NodeKind::Constant(1.),
+                position: V2::default(),
+            }],
+            groups: vec![],
+            comments: vec!["comment".to_string()],
+        };
+        ciborium::into_writer(&newer, &mut bytes).unwrap();
+        let dag = load_binary(&bytes).unwrap();
+        assert_eq!(dag.node(1).unwrap().kind, NodeKind::Constant(1.));
+    }
+
+    #[test]
+    fn version_1() {
+        let text = "(
+            version: 1,
+            out_node: 3,
+            next_node: 4,
+            nodes: [
+                (id: 1, kind: Input),
+                (id: 2, kind: Passthrough(0)),
+                (id: 3, kind: Intrinsic(Sub(1, 0))),
+            ],
+        )";
+        let dag = load_text(text).unwrap();
+        assert_eq!(dag.out_node(), Some(3));
+        assert_eq!(dag.node(2).unwrap().kind, NodeKind::Passthrough(None));
+        assert_eq!(
+            dag.node(3).unwrap().kind,
+            NodeKind::Intrinsic(Intrinsic::Sub(Some(1), None))
+        );
+        let dag = load_text("(version: 1, out_node: 0, next_node: 1, nodes: [])").unwrap();
+        assert_eq!(dag, Dag::new());
+        assert!(save_text(&dag).contains(&format!("version: {VERSION},")));
+    }
+
+    #[test]
+    fn version_2() {
+        let text = "(
+            version: 2,
+            out_node: Some(2),
+            next_node: 3,
+            nodes: [
+                (id: 1, kind: Input),
+                (id: 2, kind: Intrinsic(Mul(Some(1), Some(1)))),
+            ],
+        )";
+        let dag = load_text(text).unwrap();
+        assert_eq!(dag.out_node(), Some(2));
+        assert_eq!(dag.groups().count(), 0);
+        assert!(save_text(&dag).contains(&format!("version: {VERSION},")));
+    }
+
+    #[test]
+    fn invalid() {
+        let load = |nodes: &str| {
+            load_text(&format!(
+                "(version: 2, out_node: Some(1), next_node: 3, nodes: [{nodes}])"
+            ))
+        };
+        assert!(matches!(
+            load_text("(version: 4, graph: {})"),
+            Err(LoadError::UnsupportedVersion(4))
+        ));
+        assert!(matches!(
+            load("(id: 1, kind: Input), (id: 1, kind: Input)"),
+            Err(LoadError::DuplicateNode(1))
+        ));
+        assert!(matches!(
+            load("(id: 3, kind: Input)"),
+            Err(LoadError::NodeId(3))
+        ));
+        assert!(matches!(
+            load(
+                "(id: 1, kind: Passthrough(Some(2))), (id: 2, kind: Intrinsic(Mul(Some(1), None)))"
+            ),
+            Err(LoadError::Cycle(_))
+        ));
+        assert!(matches!(
+            load("(id: 1, kind: Sine(2))"),
+            Err(LoadError::Text(_))
+        ));
+        assert!(matches!(load_binary(b"text"), Err(LoadError::Binary(_))));
+    }
+}
@@ -0,0 +1,136 @@
+//! Compares the struct-of-arrays token and node storage against the
+//! equivalent array-of-structs layout on a multi-megabyte program.
+//!
+//! Run with `cargo bench -p madeline-parser`.
+
+use madeline_parser::{
+    ast::{Node, NodeKind},
+    incremental::{Edit, Lexed},
+    lexer::{Lexer, Span, Token, TokenKind},
+    parse,
+};
+use std::{
+    hint::black_box,
+    mem::size_of,
+    time::{Duration, Instant},
+};
+
+const TARGET_LEN: usize = 4 << 20;
+const ITERATIONS: u32 = 5;
+
+/// Builds a program of roughly the target length out of varied expressions
+fn program() -> String {
+    let mut s = String::from("fn lerp(a, b, t) a + (b - a) * t;\nfn sq(x) x * x;\n");
+    let mut i = 0u32;
+    while s.len() < TARGET_LEN {
+        s.push_str(&format!(
+            "lerp(r{i}, g{i}, 0.5) * sq(-b{i} / 2_000.25e-1) - {{ let y = y > {i}; (x + {i}) * if !y {{ 1 }} else {{ 2 }} }} +\n",
+        ));
+        i += 1;
+    }
+    s.push('0');
+    s
+}
+
+fn time<T>(mut f: impl FnMut() -> T) -> (Duration, T) {
+    let mut best = Duration::MAX;
+    let mut out = None;
+    for _ in 0..ITERATIONS {
+        let start = Instant::now();
+        out = Some(black_box(f()));
+        best = best.min(start.elapsed());
+    }
+    (best, out.unwrap())
+}
+
+fn mb_per_second(bytes: usize, duration: Duration) -> f64 {
+    bytes as f64 / duration.as_secs_f64() / (1 << 20) as f64
+}
+
+fn main() {
+    let s = program();
+    println!("input: {:.1} MiB", s.len() as f64 / (1 << 20) as f64);
+
+    let (lex_time, (tokens, _)) = time(|| Lexer::new(&s).tokens());
+    println!(
+        "lex:         {:>8.1?} ({:.0} MiB/s)",
+        lex_time,
+        mb_per_second(s.len(), lex_time)
+    );
+
+    // Retyping a literal in the middle of the source
+    let mut lexed = Lexed::new(&s);
+    let start = s[s.len() / 2..].find("0.5").unwrap() + s.len() / 2;
+    let edit = Edit::new(Span::new(start as u32, start as u32 + 3), "0.5");
+    let (relex_time, relexed) = time(|| lexed.edit(&s, &edit));
+    println!(
+        "relex:       {:>8.1?} ({} tokens)",
+        relex_time,
+        relexed.len()
+    );
+
+    let (parse_time, parse) = time(|| parse(&s));
+    assert!(parse.errors.is_empty());
+    println!(
+        "lex + parse: {:>8.1?} ({:.0} MiB/s)",
+        parse_time,
+        mb_per_second(s.len(), parse_time)
+    );
+
+    let ast = parse.ast;
+    let token_soa = tokens.size_in_bytes();
+    let token_aos = tokens.len() * size_of::<Token>();
+    let node_soa = ast.nodes.size_in_bytes();
+    let node_aos = ast.nodes.len() * size_of::<Node>();
+    println!();
+    println!(
+        "tokens: {} ({} bytes each as a struct)",
+        tokens.len(),
+        size_of::<Token>()
+    );
+    println!(
+        "  columns: {:>10} bytes ({:.0}% of structs)",
+        token_soa,
+        100. * token_soa as f64 / token_aos as f64
+    );
+    println!("  structs: {:>10} bytes", token_aos);
+    println!(
+        "nodes: {} ({} bytes each as a struct)",
+        ast.nodes.len(),
+        size_of::<Node>()
+    );
+    println!(
+        "  columns: {:>10} bytes ({:.0}% of structs)",
+        node_soa,
+        100. * node_soa as f64 / node_aos as f64
+    );
+    println!("  structs: {:>10} bytes", node_aos);
+
+    // Passes that only look at kinds, such as counting literals or finding
+    // calls, touch one dense column rather than striding over whole structs.
+    let token_structs: Vec<Token> = tokens.iter().collect();
+    let node_structs: Vec<Node> = (0..ast.nodes.len() as u32)
+        .map(|i| ast.nodes.get(i))
+        .collect();
+    let is_float = |kind: &TokenKind| matches!(kind, TokenKind::Float(_));
+    let (token_soa_time, a) = time(|| tokens.kinds().iter().filter(|k| is_float(k)).count());
+    let (token_aos_time, b) = time(|| token_structs.iter().filter(|t| is_float(&t.kind)).count());
+    assert_eq!(a, b);
+    let (node_soa_time, a) = time(|| {
+        ast.nodes
+            .kinds()
+            .iter()
+            .filter(|&&k| k == NodeKind::Call)
+            .count()
+    });
+    let (node_aos_time, b) = time(|| {
+        node_structs
+            .iter()
+            .filter(|n| n.kind == NodeKind::Call)
+            .count()
+    });
+    assert_eq!(a, b);
+    println!();
+    println!("scan token kinds: columns {token_soa_time:>8.1?}, structs {token_aos_time:>8.1?}");
+    println!("scan node kinds:  columns {node_soa_time:>8.1?}, structs {node_aos_time:>8.1?}");
+}
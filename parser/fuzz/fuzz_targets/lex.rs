@@ -0,0 +1,29 @@
+//! Lexes arbitrary source a token at a time, checking that lexing always makes
+//! progress and that token spans are in order and on character boundaries.
+//!
+//! Run with `cargo +nightly fuzz run lex` from the `parser` directory.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use madeline_parser::lexer::Lexer;
+
+fuzz_target!(|s: &str| {
+    let mut lexer = Lexer::new(s);
+    let mut end = 0;
+    loop {
+        let span = match lexer.token() {
+            Ok(Some(token)) => token.span(),
+            Ok(None) => break,
+            Err(error) => error.span,
+        };
+        assert!(span.start >= end, "Tokens overlap at {span}");
+        assert!(span.start <= span.end && span.end as usize <= s.len());
+        assert!(s.is_char_boundary(span.start as usize));
+        assert!(s.is_char_boundary(span.end as usize));
+        end = span.end;
+    }
+
+    let (tokens, _) = Lexer::new(s).tokens();
+    assert_eq!(tokens.last().map(|token| token.start as usize), Some(s.len()));
+});
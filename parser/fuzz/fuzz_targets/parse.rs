@@ -0,0 +1,24 @@
+//! Runs arbitrary source through every stage of parsing and the tools built on
+//! it, checking that none of them panic.
+//!
+//! Run with `cargo +nightly fuzz run parse` from the `parser` directory.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use madeline_parser::{format, parse, FormatOptions};
+
+fuzz_target!(|s: &str| {
+    let parse = parse(s);
+    for error in parse.errors.iter().chain(&parse.warnings) {
+        error.diagnostic().render(s, &parse.lines);
+    }
+
+    // Formatting formatted source changes nothing
+    if let Ok(formatted) = format(s, &FormatOptions::default()) {
+        assert_eq!(
+            format(&formatted, &FormatOptions::default()).as_ref(),
+            Ok(&formatted)
+        );
+    }
+});
@@ -0,0 +1,433 @@
+use crate::{
+    interner::Interner,
+    lexer::{Id, Span, Token, TokenKind, TokenList, Trivia},
+    types::Type,
+};
+use std::{collections::HashMap, mem::size_of};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeKind {
+    Ident,
+    Int,
+    Float,
+    String,
+    Sum,
+    Difference,
+    Product,
+    Negation,
+    Not,
+    Quotient,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Equal,
+    NotEqual,
+    /// Logical and. Like the other boolean operators, it is only parsed and
+    /// type checked so far, since lowering doesn't support it yet.
+    And,
+    /// Logical or, which is only parsed and type checked so far
+    Or,
+    /// A conditional expression. The token is `if`, the first child is the
+    /// condition, and the second child is a list holding the branches taken
+    /// when the condition is true and false. Like the boolean operators, it
+    /// is only parsed and type checked so far.
+    If,
+    /// A call to a user-defined function. The token is the function name and
+    /// the first child is the list of arguments.
+    Call,
+    /// A vector constructor such as `vec3(1, 2, 3)`. The token is the type
+    /// name and the first child is the list of arguments. Constructors take
+    /// precedence over functions with the same name.
+    Construct,
+    /// Component access such as `c.rgb`. The token holds the component names
+    /// and the first child is the vector.
+    Swizzle,
+    /// A function declaration. The token is the function name, the first
+    /// child is the list of parameters, and the second child is the body.
+    Function,
+    /// A function parameter. The token is the parameter name, which may be
+    /// followed by a type annotation.
+    Parameter,
+    /// A block of let statements ending in an expression. The token is the
+    /// opening brace, the first child is the list of let statements, and the
+    /// second child is the final expression.
+    Block,
+    /// A let statement. The token is the variable name, which may be followed
+    /// by a type annotation or a write mask, and the first child is its value.
+    /// With a write mask, the statement shadows the variable with a copy whose
+    /// masked components are replaced by the value.
+    Let,
+    /// Source that could not be parsed. The error has already been reported.
+    Error,
+}
+
+/// How many children a node stores, which decides where they are kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Arity {
+    Leaf,
+    Unary,
+    Binary,
+}
+
+impl NodeKind {
+    /// How tightly the node binds to its operands, with higher precedences
+    /// binding more tightly. Nodes that are not operators have the highest
+    /// precedence since they never need parentheses.
+    pub fn precedence(self) -> u8 {
+        match self {
+            NodeKind::Or => 1,
+            NodeKind::And => 2,
+            NodeKind::Less
+            | NodeKind::LessEqual
+            | NodeKind::Greater
+            | NodeKind::GreaterEqual
+            | NodeKind::Equal
+            | NodeKind::NotEqual => 3,
+            NodeKind::Sum | NodeKind::Difference => 4,
+            NodeKind::Product | NodeKind::Quotient => 5,
+            NodeKind::Negation | NodeKind::Not => 6,
+            NodeKind::Swizzle => 7,
+            _ => 8,
+        }
+    }
+
+    fn arity(self) -> Arity {
+        match self {
+            NodeKind::Ident
+            | NodeKind::Int
+            | NodeKind::Float
+            | NodeKind::String
+            | NodeKind::Parameter
+            | NodeKind::Error => Arity::Leaf,
+            NodeKind::Negation
+            | NodeKind::Not
+            | NodeKind::Call
+            | NodeKind::Construct
+            | NodeKind::Swizzle
+            | NodeKind::Let => Arity::Unary,
+            NodeKind::Sum
+            | NodeKind::Difference
+            | NodeKind::Product
+            | NodeKind::Quotient
+            | NodeKind::Less
+            | NodeKind::LessEqual
+            | NodeKind::Greater
+            | NodeKind::GreaterEqual
+            | NodeKind::Equal
+            | NodeKind::NotEqual
+            | NodeKind::And
+            | NodeKind::Or
+            | NodeKind::Function
+            | NodeKind::Block
+            | NodeKind::If => Arity::Binary,
+        }
+    }
+}
+
+/// A node gathered from the columns of [`Nodes`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Node {
+    pub kind: NodeKind,
+    /// The token the node was created from, such as the operator of a binary
+    /// expression or the literal of a leaf.
+    pub token_index: u32,
+    /// Unused children are zero. Leaves have no children and unaries only use
+    /// the first. Nodes with a variable number of children store an index into
+    /// [`Ast::lists`] instead.
+    pub children: (u32, u32),
+    /// The source covered by the node and all of its children, including any
+    /// enclosing parentheses
+    pub span: Span,
+}
+
+// TODO: Is node kind needed or can we reuse the token?
+/// Nodes stored as separate columns. Children live in separate storage for
+/// unary and binary nodes so that leaves don't pay for them.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Nodes {
+    kinds: Vec<NodeKind>,
+    token_indices: Vec<u32>,
+    spans: Vec<Span>,
+    /// An index into `unaries` or `binaries` depending on the arity of the
+    /// node kind, or zero for leaves
+    children: Vec<u32>,
+    unaries: Vec<u32>,
+    binaries: Vec<[u32; 2]>,
+}
+
+impl Nodes {
+    pub fn push(&mut self, node: Node) -> u32 {
+        let (a, b) = node.children;
+        let children = match node.kind.arity() {
+            Arity::Leaf => 0,
+            Arity::Unary => {
+                self.unaries.push(a);
+                self.unaries.len() - 1
+            }
+            Arity::Binary => {
+                self.binaries.push([a, b]);
+                self.binaries.len() - 1
+            }
+        };
+        self.kinds.push(node.kind);
+        self.token_indices.push(node.token_index);
+        self.spans.push(node.span);
+        self.children.push(children as u32);
+        self.kinds.len() as u32 - 1
+    }
+
+    pub fn get(&self, index: u32) -> Node {
+        Node {
+            kind: self.kind(index),
+            token_index: self.token_index(index),
+            children: self.children(index),
+            span: self.span(index),
+        }
+    }
+
+    pub fn kind(&self, index: u32) -> NodeKind {
+        self.kinds[index as usize]
+    }
+
+    pub fn token_index(&self, index: u32) -> u32 {
+        self.token_indices[index as usize]
+    }
+
+    pub fn span(&self, index: u32) -> Span {
+        self.spans[index as usize]
+    }
+
+    pub fn set_span(&mut self, index: u32, span: Span) {
+        self.spans[index as usize] = span;
+    }
+
+    pub fn children(&self, index: u32) -> (u32, u32) {
+        let i = self.children[index as usize] as usize;
+        match self.kind(index).arity() {
+            Arity::Leaf => (0, 0),
+            Arity::Unary => (self.unaries[i], 0),
+            Arity::Binary => (self.binaries[i][0], self.binaries[i][1]),
+        }
+    }
+
+    pub fn kinds(&self) -> &[NodeKind] {
+        &self.kinds
+    }
+
+    pub fn len(&self) -> usize {
+        self.kinds.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.kinds.is_empty()
+    }
+
+    /// The number of bytes occupied by the nodes, excluding spare capacity
+    pub fn size_in_bytes(&self) -> usize {
+        self.kinds.len() * size_of::<NodeKind>()
+            + self.token_indices.len() * size_of::<u32>()
+            + self.spans.len() * size_of::<Span>()
+            + self.children.len() * size_of::<u32>()
+            + self.unaries.len() * size_of::<u32>()
+            + self.binaries.len() * size_of::<[u32; 2]>()
+    }
+}
+
+/// The result of parsing a program.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ast {
+    pub tokens: TokenList,
+    pub nodes: Nodes,
+    /// Length-prefixed lists of node indices, such as call arguments
+    pub lists: Vec<u32>,
+    /// The text of each identifier [`Id`]
+    pub interner: Interner,
+    /// The value of each string literal, indexed by the payload of
+    /// [`TokenKind::Str`]
+    pub strings: Vec<String>,
+    /// Comments in source order
+    pub trivia: Vec<Trivia>,
+    /// Function declaration nodes in source order
+    pub functions: Vec<u32>,
+    /// Function declaration nodes by name, filled in by
+    /// [`resolve`](crate::resolve::resolve)
+    pub symbols: HashMap<Id, u32>,
+    /// The let statement or parameter node that each identifier node refers
+    /// to, filled in by [`resolve`](crate::resolve::resolve). Identifiers that
+    /// are not bound to anything are inputs. Let statements with a write mask
+    /// are bound to the variable they update in the same way.
+    pub bindings: HashMap<u32, u32>,
+    /// The type of each node, filled in by [`check`](crate::types::check)
+    pub types: Vec<Type>,
+    /// The index of the final expression in `nodes`
+    pub root: u32,
+}
+
+impl Ast {
+    pub fn node(&self, index: u32) -> Node {
+        self.nodes.get(index)
+    }
+
+    pub fn root(&self) -> Node {
+        self.node(self.root)
+    }
+
+    pub fn token(&self, node: Node) -> Token {
+        self.tokens.get(node.token_index)
+    }
+
+    /// Gets the value of an integer literal node
+    pub fn int(&self, node: Node) -> Option<i32> {
+        match self.token(node).kind {
+            TokenKind::Int(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Gets the value of a string literal node
+    pub fn string(&self, node: Node) -> Option<&str> {
+        match self.token(node).kind {
+            TokenKind::Str(index) => Some(&self.strings[index as usize]),
+            _ => None,
+        }
+    }
+
+    /// Gets the value of a float literal node
+    pub fn float(&self, node: Node) -> Option<f32> {
+        match self.token(node).kind {
+            TokenKind::Float(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Gets the identifier of an identifier, call, constructor, swizzle,
+    /// function, parameter, or let node
+    pub fn id(&self, node: Node) -> Option<Id> {
+        match self.token(node).kind {
+            TokenKind::Ident(id) => Some(id),
+            _ => None,
+        }
+    }
+
+    /// Gets the type name token following `:` after the name of a parameter or
+    /// let node
+    pub fn annotation(&self, node: Node) -> Option<Token> {
+        let colon = node.token_index + 1;
+        match self.tokens.kind(colon) {
+            TokenKind::Colon => Some(self.tokens.get(colon + 1)),
+            _ => None,
+        }
+    }
+
+    /// Gets the component names following `.` after the name of a let node
+    pub fn mask(&self, node: Node) -> Option<Token> {
+        let dot = node.token_index + 1;
+        match self.tokens.kind(dot) {
+            TokenKind::Dot => Some(self.tokens.get(dot + 1)),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self, id: Id) -> &str {
+        self.interner.resolve(id)
+    }
+
+    /// Gets the indices of the nodes directly beneath the given node
+    pub fn children(&self, node: Node) -> Vec<u32> {
+        let (a, b) = node.children;
+        match node.kind {
+            NodeKind::Ident
+            | NodeKind::Int
+            | NodeKind::Float
+            | NodeKind::String
+            | NodeKind::Parameter
+            | NodeKind::Error => vec![],
+            NodeKind::Negation | NodeKind::Not | NodeKind::Swizzle | NodeKind::Let => vec![a],
+            NodeKind::Sum
+            | NodeKind::Difference
+            | NodeKind::Product
+            | NodeKind::Quotient
+            | NodeKind::Less
+            | NodeKind::LessEqual
+            | NodeKind::Greater
+            | NodeKind::GreaterEqual
+            | NodeKind::Equal
+            | NodeKind::NotEqual
+            | NodeKind::And
+            | NodeKind::Or => vec![a, b],
+            NodeKind::Call | NodeKind::Construct => self.list(a).to_vec(),
+            NodeKind::Function | NodeKind::Block => {
+                let mut children = self.list(a).to_vec();
+                children.push(b);
+                children
+            }
+            NodeKind::If => {
+                let mut children = vec![a];
+                children.extend_from_slice(self.list(b));
+                children
+            }
+        }
+    }
+
+    /// Gets the node indices of a list such as call arguments or function
+    /// parameters
+    pub fn list(&self, index: u32) -> &[u32] {
+        let start = index as usize + 1;
+        let len = self.lists[index as usize] as usize;
+        &self.lists[start..start + len]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn columns_round_trip() {
+        let mut nodes = Nodes::default();
+        let leaf = Node {
+            kind: NodeKind::Float,
+            token_index: 0,
+            children: (0, 0),
+            span: Span::new(0, 1),
+        };
+        let unary = Node {
+            kind: NodeKind::Negation,
+            token_index: 1,
+            children: (0, 0),
+            span: Span::new(0, 2),
+        };
+        let binary = Node {
+            kind: NodeKind::Sum,
+            token_index: 2,
+            children: (0, 1),
+            span: Span::new(0, 5),
+        };
+        let indices = [leaf, unary, binary, leaf].map(|node| nodes.push(node));
+        assert_eq!(indices, [0, 1, 2, 3]);
+        assert_eq!(nodes.get(0), leaf);
+        assert_eq!(nodes.get(1), unary);
+        assert_eq!(nodes.get(2), binary);
+        assert_eq!(nodes.get(3), leaf);
+        assert_eq!(nodes.len(), 4);
+    }
+
+    #[test]
+    fn leaves_store_no_children() {
+        let mut nodes = Nodes::default();
+        let leaf = Node {
+            kind: NodeKind::Ident,
+            token_index: 0,
+            children: (0, 0),
+            span: Span::default(),
+        };
+        nodes.push(leaf);
+        let leaf_size = nodes.size_in_bytes();
+        nodes.push(Node {
+            kind: NodeKind::Product,
+            ..leaf
+        });
+        assert_eq!(nodes.size_in_bytes() - leaf_size, leaf_size + 8);
+    }
+}
@@ -0,0 +1,135 @@
+use crate::lexer::{Span, TokenList, Trivia, TriviaKind};
+
+/// A piece of the source in a [`Cst`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Element {
+    pub kind: ElementKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElementKind {
+    /// A token, holding its index in the token list
+    Token(u32),
+    /// A comment. Trailing comments share a line with the token before them
+    /// and belong to it, while other comments belong to the token after them.
+    Comment { kind: TriviaKind, trailing: bool },
+    /// Whitespace between tokens and comments
+    Whitespace,
+    /// Characters that the lexer dropped after reporting an error
+    Skipped,
+}
+
+/// Every token and comment of a source in order, along with the text between
+/// them, so that the source can be reproduced exactly. AST nodes refer to
+/// tokens by index, so together with the AST this forms a lossless concrete
+/// syntax tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cst {
+    elements: Vec<Element>,
+}
+
+impl Cst {
+    pub fn new(source: &str, tokens: &TokenList, trivia: &[Trivia]) -> Self {
+        let mut elements = vec![];
+        let mut offset = 0;
+        let gap = |elements: &mut Vec<Element>, offset: &mut u32, end: u32| {
+            if *offset < end {
+                let span = Span::new(*offset, end);
+                let kind = if source[span.range()].chars().all(char::is_whitespace) {
+                    ElementKind::Whitespace
+                } else {
+                    ElementKind::Skipped
+                };
+                elements.push(Element { kind, span });
+            }
+            *offset = end;
+        };
+
+        let mut trivia = trivia.iter().peekable();
+        let mut previous_token_end = None;
+        for (index, token) in tokens.iter().enumerate() {
+            while let Some(comment) = trivia.next_if(|comment| comment.span.start < token.start) {
+                gap(&mut elements, &mut offset, comment.span.start);
+                let trailing = previous_token_end.is_some_and(|end: u32| {
+                    !source[end as usize..comment.span.start as usize].contains('\n')
+                });
+                elements.push(Element {
+                    kind: ElementKind::Comment {
+                        kind: comment.kind,
+                        trailing,
+                    },
+                    span: comment.span,
+                });
+                offset = comment.span.end;
+            }
+            gap(&mut elements, &mut offset, token.start);
+            elements.push(Element {
+                kind: ElementKind::Token(index as u32),
+                span: token.span(),
+            });
+            offset = token.end;
+            previous_token_end = Some(token.end);
+        }
+        gap(&mut elements, &mut offset, source.len() as u32);
+        Self { elements }
+    }
+
+    pub fn elements(&self) -> &[Element] {
+        &self.elements
+    }
+
+    /// Reproduces the source the tree was built from
+    pub fn text(&self, source: &str) -> String {
+        self.elements
+            .iter()
+            .map(|element| &source[element.span.range()])
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+    use proptest::prelude::*;
+
+    fn cst(s: &str) -> Cst {
+        let ast = parse(s).ast;
+        Cst::new(s, &ast.tokens, &ast.trivia)
+    }
+
+    #[test]
+    fn lossless() {
+        for s in [
+            "",
+            "  fn f(a) a * 2; // double\n/* nested /* comment */ */ f(1)\n",
+            "1 $ 2 # 3",
+            "\"unterminated\n1",
+            "x /* unterminated",
+        ] {
+            assert_eq!(cst(s).text(s), s);
+        }
+    }
+
+    #[test]
+    fn trailing_comments() {
+        let s = "// leading\na /* trailing */ + // trailing\n/* leading */ b";
+        let comments: Vec<_> = cst(s)
+            .elements()
+            .iter()
+            .filter_map(|element| match element.kind {
+                ElementKind::Comment { trailing, .. } => Some(trailing),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(comments, [false, true, true, false]);
+    }
+
+    proptest! {
+        #[test]
+        fn lossless_for_any_source(s in any::<String>()) {
+            prop_assert_eq!(cst(&s).text(&s), s);
+        }
+    }
+}
@@ -0,0 +1,188 @@
+use crate::{lexer::Span, line_index::LineIndex, Error};
+use std::fmt::Write;
+
+/// A presentable description of an error in the source
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: &'static str,
+    pub message: String,
+    pub help: Option<&'static str>,
+    pub span: Span,
+}
+
+impl Diagnostic {
+    /// Prints the diagnostic along with the offending source line, underlining
+    /// the span with carets. Columns are counted in characters.
+    ///
+    /// ```text
+    /// error[E0102]: Expected a closing parenthesis
+    ///  --> 1:7
+    ///   |
+    /// 1 | (1 + 2
+    ///   |       ^
+    ///   = help: Add a `)` to close the parenthesis
+    /// ```
+    pub fn render(&self, source: &str, lines: &LineIndex) -> String {
+        let start = lines.line_col(self.span.start);
+        let line_span = lines.line_span(start.line).unwrap_or_default();
+        let line_text = source[line_span.range()].trim_end_matches('\r');
+        let line = start.line + 1;
+        let prefix = &source[line_span.start as usize..self.span.start as usize];
+        let column = prefix.chars().count() + 1;
+
+        let underline_end = self.span.end.clamp(self.span.start, line_span.end);
+        let underline = &source[self.span.start as usize..underline_end as usize];
+        let underline_width = underline.chars().count().max(1);
+        let gutter = " ".repeat(line.to_string().len());
+
+        let mut out = String::new();
+        let _ = writeln!(out, "{}[{}]: {}", self.severity, self.code, self.message);
+        let _ = writeln!(out, "{gutter}--> {line}:{column}");
+        let _ = writeln!(out, "{gutter} |");
+        let _ = writeln!(out, "{line} | {line_text}");
+        let _ = writeln!(
+            out,
+            "{gutter} | {}{}",
+            " ".repeat(column - 1),
+            "^".repeat(underline_width)
+        );
+        if let Some(help) = self.help {
+            let _ = writeln!(out, "{gutter} = help: {help}");
+        }
+        out
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+impl From<&Error> for Diagnostic {
+    fn from(error: &Error) -> Self {
+        Self {
+            severity: if error.is_warning() {
+                Severity::Warning
+            } else {
+                Severity::Error
+            },
+            code: error.code(),
+            message: error.message(),
+            help: error.help(),
+            span: error.span(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parse;
+
+    fn render(s: &str) -> String {
+        let parse = parse(s);
+        parse.errors[0].diagnostic().render(s, &parse.lines)
+    }
+
+    #[test]
+    fn lexer_error() {
+        assert_eq!(
+            render("x * 1e+"),
+            "\
+error[E0002]: Expected digits in the exponent of a number
+ --> 1:5
+  |
+1 | x * 1e+
+  |     ^^^
+  = help: Add digits after the exponent, as in `1e3`
+"
+        );
+    }
+
+    #[test]
+    fn parser_error() {
+        assert_eq!(
+            render("fn f(a) a;\n(1 + 2 3"),
+            "\
+error[E0102]: Expected a closing parenthesis
+ --> 2:8
+  |
+2 | (1 + 2 3
+  |        ^
+  = help: Add a `)` to close the parenthesis
+"
+        );
+    }
+
+    #[test]
+    fn end_of_input() {
+        assert_eq!(
+            render("1 +"),
+            "\
+error[E0101]: Expected a number, identifier, or parenthesized expression
+ --> 1:4
+  |
+1 | 1 +
+  |    ^
+"
+        );
+    }
+
+    #[test]
+    fn resolve_error() {
+        assert_eq!(
+            render("fn f(a, b) a;\nf(1)"),
+            "\
+error[E0204]: Expected 2 arguments but found 1
+ --> 2:1
+  |
+2 | f(1)
+  | ^^^^
+"
+        );
+    }
+
+    #[test]
+    fn wide_characters() {
+        let s = "é + 1__0";
+        let parse = parse(s);
+        assert_eq!(
+            parse.errors[0].diagnostic().render(s, &parse.lines),
+            "\
+error[E0003]: Underscores in a number must separate two digits
+ --> 1:6
+  |
+1 | é + 1__0
+  |      ^
+  = help: Remove the underscore or put a digit after it
+"
+        );
+    }
+
+    #[test]
+    fn warning() {
+        let s = "{ let a = 1; 2 }";
+        let parse = parse(s);
+        assert_eq!(
+            parse.warnings[0].diagnostic().render(s, &parse.lines),
+            "\
+warning[W0201]: This variable is never used
+ --> 1:7
+  |
+1 | { let a = 1; 2 }
+  |       ^
+  = help: Remove the variable or start its name with an underscore
+"
+        );
+    }
+}
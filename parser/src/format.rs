@@ -0,0 +1,747 @@
+use crate::{
+    ast::{Ast, NodeKind},
+    cst::{Cst, ElementKind},
+    lexer::TriviaKind,
+    parse, Error,
+};
+use std::iter;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FormatOptions {
+    /// The column to wrap lines at where possible
+    pub width: usize,
+    /// The number of spaces to indent by
+    pub indent: usize,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self {
+            width: 80,
+            indent: 4,
+        }
+    }
+}
+
+/// Prints a program canonically: operators are spaced, parentheses are only
+/// kept where precedence requires them, and expressions that don't fit in the
+/// configured width are wrapped. Comments are kept next to the tokens they
+/// belong to. Formatting already formatted source changes nothing.
+///
+/// Source with syntax errors is not formatted since parts of it would be lost.
+/// The syntax errors are returned instead.
+pub fn format(source: &str, options: &FormatOptions) -> Result<String, Vec<Error>> {
+    let parse = parse(source);
+    let errors: Vec<_> = parse
+        .errors
+        .into_iter()
+        .filter(|error| matches!(error, Error::Lexer(_) | Error::Parser(_)))
+        .collect();
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let ast = &parse.ast;
+    let cst = Cst::new(source, &ast.tokens, &ast.trivia);
+    let mut formatter = Formatter {
+        source,
+        ast,
+        cst: &cst,
+        element: 0,
+        last_end: 0,
+    };
+    let doc = formatter.program();
+    let mut out = print(&doc, options);
+    let len = out.trim_end().len();
+    out.truncate(len);
+    out.push('\n');
+    Ok(out)
+}
+
+/// A layout-independent description of formatted output, which is fitted to
+/// the line width when printed
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Doc {
+    Text(String),
+    /// A space, or a line break if the enclosing group is broken
+    Line,
+    /// Nothing, or a line break if the enclosing group is broken
+    SoftLine,
+    /// A line break that always breaks the enclosing groups
+    HardLine,
+    /// A line break unless the current line is still empty, for comments that
+    /// are on a line of their own
+    FreshLine,
+    /// A line comment at the end of the current line, which always breaks
+    /// after it
+    TrailingComment(String),
+    Indent(Box<Doc>),
+    /// Contents that are printed on one line if they fit, or with every line
+    /// of the group broken otherwise
+    Group(Vec<Doc>),
+    Concat(Vec<Doc>),
+}
+
+fn text(s: &str) -> Doc {
+    Doc::Text(s.to_string())
+}
+
+fn indent(docs: Vec<Doc>) -> Doc {
+    Doc::Indent(Box::new(Doc::Concat(docs)))
+}
+
+struct Formatter<'a> {
+    source: &'a str,
+    ast: &'a Ast,
+    cst: &'a Cst,
+    /// The next CST element that may hold a comment to print
+    element: usize,
+    /// The end of the last token or comment printed
+    last_end: u32,
+}
+
+impl<'a> Formatter<'a> {
+    fn program(&mut self) -> Doc {
+        let ast = self.ast;
+        let mut docs = vec![];
+        let items = ast.functions.iter().copied().chain([ast.root]);
+        for (i, item) in items.enumerate() {
+            if i > 0 {
+                docs.push(Doc::HardLine);
+                if self.blank_line_before(ast.node(item).span.start) {
+                    docs.push(Doc::HardLine);
+                }
+            }
+            if item == ast.root {
+                docs.push(self.expression(item));
+            } else {
+                docs.push(self.function(item));
+            }
+        }
+
+        let end = self.source.len() as u32;
+        let mut comments = self.comments_before(end + 1).into_iter().peekable();
+        if let Some(comment) = comments.next_if(|doc| matches!(doc, Doc::TrailingComment(_))) {
+            docs.push(comment);
+        }
+        if comments.peek().is_some() {
+            docs.push(Doc::HardLine);
+            docs.extend(comments);
+        }
+        Doc::Concat(docs)
+    }
+
+    /// "fn" name "(" parameters ")" body ";"
+    fn function(&mut self, function: u32) -> Doc {
+        let ast = self.ast;
+        let node = ast.node(function);
+        let name = node.token_index;
+        let parameters = ast.list(node.children.0);
+        let leading = self.comments_before(node.span.start);
+        let mut docs = vec![self.token(name - 1), text(" "), self.token(name)];
+        let mut parameter_docs = vec![self.token(name + 1)];
+        let mut inner = vec![Doc::SoftLine];
+        for (i, &parameter) in parameters.iter().enumerate() {
+            let token = ast.node(parameter).token_index;
+            inner.push(self.token(token));
+            if ast.annotation(ast.node(parameter)).is_some() {
+                inner.extend([self.token(token + 1), text(" "), self.token(token + 2)]);
+            }
+            if i + 1 < parameters.len() {
+                inner.push(self.token(self.last_token(parameter) + 1));
+                inner.push(Doc::Line);
+            }
+        }
+        let paren_right = match parameters.last() {
+            Some(&parameter) => self.last_token(parameter) + 1,
+            None => name + 2,
+        };
+        if !parameters.is_empty() {
+            parameter_docs.push(indent(inner));
+            parameter_docs.push(Doc::SoftLine);
+        }
+        parameter_docs.push(self.token(paren_right));
+        docs.push(Doc::Group(parameter_docs));
+
+        let body = node.children.1;
+        match ast.node(body).kind {
+            NodeKind::Block | NodeKind::If => {
+                docs.push(text(" "));
+                docs.push(self.expression(body));
+            }
+            _ => docs.push(indent(vec![Doc::Line, self.expression(body)])),
+        }
+        // Keep the semicolon and its comments out of the group so that a
+        // trailing comment doesn't break the declaration
+        let group = Doc::Group(docs);
+        let semicolon = self.token(self.last_token(function));
+        Doc::Concat(leading.into_iter().chain([group, semicolon]).collect())
+    }
+
+    fn expression(&mut self, index: u32) -> Doc {
+        // Print comments before the expression outside of its group so that
+        // they don't break it
+        let leading = self.comments_before(self.ast.node(index).span.start);
+        let doc = self.expression_inner(index);
+        if leading.is_empty() {
+            doc
+        } else {
+            Doc::Concat(leading.into_iter().chain([doc]).collect())
+        }
+    }
+
+    fn expression_inner(&mut self, index: u32) -> Doc {
+        let ast = self.ast;
+        let node = ast.node(index);
+        let (a, b) = node.children;
+        match node.kind {
+            NodeKind::Ident | NodeKind::Int | NodeKind::Float | NodeKind::String => {
+                self.token(node.token_index)
+            }
+
+            NodeKind::Sum
+            | NodeKind::Difference
+            | NodeKind::Product
+            | NodeKind::Quotient
+            | NodeKind::Less
+            | NodeKind::LessEqual
+            | NodeKind::Greater
+            | NodeKind::GreaterEqual
+            | NodeKind::Equal
+            | NodeKind::NotEqual
+            | NodeKind::And
+            | NodeKind::Or => {
+                // Gather a left-associative chain of operators at the same
+                // precedence so that it wraps as one unit
+                let precedence = node.kind.precedence();
+                let mut operations = vec![];
+                let mut first = index;
+                loop {
+                    let node = ast.node(first);
+                    if !is_binary(node.kind) || node.kind.precedence() != precedence {
+                        break;
+                    }
+                    operations.push((node.token_index, node.children.1));
+                    first = node.children.0;
+                }
+                let first = self.operand(first, precedence, false);
+                let mut rest = vec![];
+                for &(operator, rhs) in operations.iter().rev() {
+                    rest.push(text(" "));
+                    rest.push(self.token(operator));
+                    rest.push(Doc::Line);
+                    rest.push(self.operand(rhs, precedence, true));
+                }
+                Doc::Group(vec![first, indent(rest)])
+            }
+
+            NodeKind::Negation | NodeKind::Not => {
+                let operator = self.token(node.token_index);
+                Doc::Concat(vec![
+                    operator,
+                    self.operand(a, node.kind.precedence(), false),
+                ])
+            }
+
+            NodeKind::Swizzle => {
+                // A dot straight after an integer would make it a float
+                let base = if ast.node(a).kind == NodeKind::Int {
+                    Doc::Concat(vec![text("("), self.expression(a), text(")")])
+                } else {
+                    self.operand(a, node.kind.precedence(), false)
+                };
+                let dot = self.token(node.token_index - 1);
+                Doc::Concat(vec![base, dot, self.token(node.token_index)])
+            }
+
+            NodeKind::Call | NodeKind::Construct => {
+                let name = node.token_index;
+                let arguments = ast.list(a);
+                let mut docs = vec![self.token(name), self.token(name + 1)];
+                if !arguments.is_empty() {
+                    let mut inner = vec![Doc::SoftLine];
+                    for (i, &argument) in arguments.iter().enumerate() {
+                        inner.push(self.expression(argument));
+                        if i + 1 < arguments.len() {
+                            inner.push(self.token(self.last_token(argument) + 1));
+                            inner.push(Doc::Line);
+                        }
+                    }
+                    docs.push(indent(inner));
+                    docs.push(Doc::SoftLine);
+                }
+                let paren_right = match arguments.last() {
+                    Some(&argument) => self.last_token(argument) + 1,
+                    None => name + 2,
+                };
+                docs.push(self.token(paren_right));
+                Doc::Group(docs)
+            }
+
+            NodeKind::Block => {
+                let brace = self.token(node.token_index);
+                let mut inner = vec![];
+                for &statement in ast.list(a) {
+                    inner.push(Doc::Line);
+                    inner.push(self.let_statement(statement));
+                }
+                inner.push(Doc::Line);
+                inner.push(self.expression(b));
+                let docs = vec![
+                    brace,
+                    indent(inner),
+                    Doc::Line,
+                    self.token(self.last_token(b) + 1),
+                ];
+                Doc::Group(docs)
+            }
+
+            NodeKind::If => {
+                let &[then, otherwise] = ast.list(b) else {
+                    unreachable!("Conditionals have two branches");
+                };
+                let keyword = self.token(node.token_index);
+                let condition = self.expression(a);
+                let then_doc = self.expression(then);
+                let else_keyword = self.token(self.last_token(then) + 1);
+                let otherwise = self.expression(otherwise);
+                Doc::Concat(vec![
+                    keyword,
+                    text(" "),
+                    condition,
+                    text(" "),
+                    then_doc,
+                    text(" "),
+                    else_keyword,
+                    text(" "),
+                    otherwise,
+                ])
+            }
+
+            NodeKind::Let | NodeKind::Function | NodeKind::Parameter | NodeKind::Error => {
+                unreachable!("{:?} is not an expression", node.kind)
+            }
+        }
+    }
+
+    /// "let" name ("." mask | ":" type)? "=" value ";"
+    fn let_statement(&mut self, statement: u32) -> Doc {
+        let ast = self.ast;
+        let node = ast.node(statement);
+        let name = node.token_index;
+        let mut docs = vec![self.token(name - 1), text(" "), self.token(name)];
+        let mut equals = name + 1;
+        if ast.mask(node).is_some() {
+            docs.extend([self.token(name + 1), self.token(name + 2)]);
+            equals = name + 3;
+        } else if ast.annotation(node).is_some() {
+            docs.extend([self.token(name + 1), text(" "), self.token(name + 2)]);
+            equals = name + 3;
+        }
+        docs.extend([text(" "), self.token(equals), text(" ")]);
+        docs.push(self.expression(node.children.0));
+        docs.push(self.token(self.last_token(statement)));
+        Doc::Concat(docs)
+    }
+
+    /// Formats an operand, adding parentheses if it binds less tightly than
+    /// its parent. Since binary operators are left associative, a right
+    /// operand at the same precedence needs them too.
+    fn operand(&mut self, index: u32, parent: u8, right: bool) -> Doc {
+        let precedence = self.ast.node(index).kind.precedence();
+        if precedence < parent || (right && precedence == parent) {
+            Doc::Concat(vec![text("("), self.expression(index), text(")")])
+        } else {
+            self.expression(index)
+        }
+    }
+
+    /// Prints a token along with the comments before it and the trailing
+    /// comments after it
+    fn token(&mut self, index: u32) -> Doc {
+        let span = self.ast.tokens.span(index);
+        let mut docs = self.comments_before(span.start);
+        docs.push(text(&self.source[span.range()]));
+        self.last_end = span.end;
+
+        let elements = self.cst.elements();
+        while let Some(element) = elements.get(self.element) {
+            match element.kind {
+                ElementKind::Token(token) if token <= index => self.element += 1,
+                ElementKind::Comment { trailing: true, .. } => docs.extend(self.comment(true)),
+                ElementKind::Whitespace | ElementKind::Skipped => self.element += 1,
+                _ => break,
+            }
+        }
+        Doc::Concat(docs)
+    }
+
+    /// Prints the comments that start before the offset which haven't been
+    /// printed yet
+    fn comments_before(&mut self, offset: u32) -> Vec<Doc> {
+        let mut docs = vec![];
+        let elements = self.cst.elements();
+        while let Some(element) = elements.get(self.element) {
+            if element.span.start >= offset {
+                break;
+            }
+            match element.kind {
+                // The token a trailing comment belongs to may not have been
+                // printed, as with parentheses that were removed. Line
+                // comments can still trail whatever comes before them.
+                ElementKind::Comment { kind, trailing } => {
+                    let trailing = trailing && kind == TriviaKind::LineComment;
+                    docs.extend(self.comment(trailing));
+                }
+                _ => self.element += 1,
+            }
+        }
+        docs
+    }
+
+    /// Prints the comment at the current element, either after the preceding
+    /// text on the same line or before the following text
+    fn comment(&mut self, trailing: bool) -> Vec<Doc> {
+        let element = self.cst.elements()[self.element];
+        self.element += 1;
+        let ElementKind::Comment {
+            kind,
+            trailing: same_line,
+        } = element.kind
+        else {
+            unreachable!("Expected a comment");
+        };
+        let comment = self.source[element.span.range()].trim_end();
+        self.last_end = element.span.end;
+
+        if trailing {
+            return match kind {
+                TriviaKind::LineComment => vec![Doc::TrailingComment(comment.to_string())],
+                TriviaKind::BlockComment => vec![text(" "), text(comment)],
+            };
+        }
+        let mut docs = vec![text(comment)];
+        if !same_line {
+            docs.insert(0, Doc::FreshLine);
+        }
+        let next = self.cst.elements()[self.element..]
+            .iter()
+            .find(|element| !matches!(element.kind, ElementKind::Whitespace))
+            .map_or(self.source.len(), |element| element.span.start as usize);
+        let newlines = self.source[element.span.end as usize..next]
+            .matches('\n')
+            .count();
+        match (kind, newlines) {
+            (TriviaKind::BlockComment, 0) => docs.push(text(" ")),
+            (_, 0 | 1) => docs.push(Doc::HardLine),
+            _ => docs.extend([Doc::HardLine, Doc::HardLine]),
+        }
+        docs
+    }
+
+    /// Whether the source has a blank line between what was last printed and
+    /// the next comment or the given offset
+    fn blank_line_before(&self, offset: u32) -> bool {
+        let next = self.cst.elements()[self.element..]
+            .iter()
+            .find(|element| matches!(element.kind, ElementKind::Comment { .. }))
+            .map_or(offset, |element| element.span.start.min(offset));
+        self.source[self.last_end as usize..next as usize]
+            .matches('\n')
+            .count()
+            > 1
+    }
+
+    /// Gets the index of the last token of a node. The spans of
+    /// parenthesized expressions include the parentheses, so this finds the
+    /// closing parenthesis of those.
+    fn last_token(&self, node: u32) -> u32 {
+        let end = self.ast.node(node).span.end;
+        self.ast.tokens.first_ending_at(end) as u32
+    }
+}
+
+fn is_binary(kind: NodeKind) -> bool {
+    matches!(
+        kind,
+        NodeKind::Sum
+            | NodeKind::Difference
+            | NodeKind::Product
+            | NodeKind::Quotient
+            | NodeKind::Less
+            | NodeKind::LessEqual
+            | NodeKind::Greater
+            | NodeKind::GreaterEqual
+            | NodeKind::Equal
+            | NodeKind::NotEqual
+            | NodeKind::And
+            | NodeKind::Or
+    )
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Flat,
+    Break,
+}
+
+fn print(doc: &Doc, options: &FormatOptions) -> String {
+    let mut out = String::new();
+    let mut column = 0;
+    // Whether a trailing comment ended the line, so that the next document
+    // has to start a new one
+    let mut comment_ended_line = false;
+    let mut stack = vec![(0, Mode::Break, doc)];
+    while let Some((indentation, mode, doc)) = stack.pop() {
+        match doc {
+            Doc::Text(s) => {
+                let s = if comment_ended_line {
+                    new_line(&mut out, indentation);
+                    column = indentation;
+                    comment_ended_line = false;
+                    s.trim_start()
+                } else {
+                    s
+                };
+                out.push_str(s);
+                column += s.chars().count();
+            }
+            Doc::Line | Doc::SoftLine if mode == Mode::Flat && !comment_ended_line => {
+                if *doc == Doc::Line {
+                    out.push(' ');
+                    column += 1;
+                }
+            }
+            Doc::FreshLine if line_is_empty(&out) && !comment_ended_line => {
+                let len = out.trim_end_matches(' ').len();
+                out.truncate(len);
+                out.extend(iter::repeat_n(' ', indentation));
+                column = indentation;
+            }
+            Doc::Line | Doc::SoftLine | Doc::HardLine | Doc::FreshLine => {
+                new_line(&mut out, indentation);
+                column = indentation;
+                comment_ended_line = false;
+            }
+            Doc::TrailingComment(comment) => {
+                if comment_ended_line {
+                    new_line(&mut out, indentation);
+                } else if !line_is_empty(&out) {
+                    let len = out.trim_end_matches(' ').len();
+                    out.truncate(len);
+                    out.push(' ');
+                }
+                out.push_str(comment);
+                comment_ended_line = true;
+            }
+            Doc::Indent(doc) => stack.push((indentation + options.indent, mode, doc)),
+            Doc::Concat(docs) => {
+                stack.extend(docs.iter().rev().map(|doc| (indentation, mode, doc)));
+            }
+            Doc::Group(docs) => {
+                let remaining = options.width as isize - column as isize;
+                let mode = if mode == Mode::Flat || fits(docs, &stack, remaining) {
+                    Mode::Flat
+                } else {
+                    Mode::Break
+                };
+                stack.extend(docs.iter().rev().map(|doc| (indentation, mode, doc)));
+            }
+        }
+    }
+    out
+}
+
+fn new_line(out: &mut String, indentation: usize) {
+    let len = out.trim_end_matches(' ').len();
+    out.truncate(len);
+    out.push('\n');
+    out.extend(iter::repeat_n(' ', indentation));
+}
+
+/// Whether nothing but indentation has been printed on the current line
+fn line_is_empty(out: &str) -> bool {
+    out.rsplit('\n')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .is_empty()
+}
+
+/// Whether the documents fit on the rest of the line when printed flat,
+/// along with whatever follows them up to the next line break. A trailing
+/// comment fits as long as nothing else in the documents comes after it.
+fn fits(docs: &[Doc], rest: &[(usize, Mode, &Doc)], mut remaining: isize) -> bool {
+    let mut stack: Vec<_> = docs.iter().rev().map(|doc| (Mode::Flat, doc)).collect();
+    let mut rest = rest.iter().rev();
+    let mut comment_ended_line = false;
+    loop {
+        let Some((mode, doc)) = stack
+            .pop()
+            .or_else(|| rest.next().map(|&(_, mode, doc)| (mode, doc)))
+        else {
+            return true;
+        };
+        match doc {
+            Doc::Text(_) if comment_ended_line => return mode == Mode::Break,
+            Doc::Text(s) => remaining -= s.chars().count() as isize,
+            Doc::Line | Doc::SoftLine if mode == Mode::Break => return true,
+            Doc::Line | Doc::SoftLine if comment_ended_line => {}
+            Doc::Line => remaining -= 1,
+            Doc::SoftLine => {}
+            Doc::HardLine | Doc::FreshLine => return mode == Mode::Break,
+            Doc::TrailingComment(_) if mode == Mode::Break => return true,
+            Doc::TrailingComment(_) => comment_ended_line = true,
+            Doc::Indent(doc) => stack.push((mode, doc)),
+            Doc::Concat(docs) | Doc::Group(docs) => {
+                stack.extend(docs.iter().rev().map(|doc| (mode, doc)))
+            }
+        }
+        if remaining < 0 {
+            return false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format_width(s: &str, width: usize) -> String {
+        let options = FormatOptions {
+            width,
+            ..Default::default()
+        };
+        let formatted = format(s, &options).unwrap();
+        assert_eq!(
+            format(&formatted, &options).unwrap(),
+            formatted,
+            "Formatting is not idempotent for {s:?}"
+        );
+        formatted
+    }
+
+    fn assert_formats_to(s: &str, expected: &str) {
+        assert_eq!(format_width(s, 80), expected);
+    }
+
+    #[test]
+    fn spacing() {
+        assert_formats_to("1+2*-x", "1 + 2 * -x\n");
+        assert_formats_to(
+            "fn f( a:vec3,b )a*b;f(1,2)",
+            "fn f(a: vec3, b) a * b;\nf(1, 2)\n",
+        );
+        assert_formats_to("{let a=1;let c.rg=a;c}", "{ let a = 1; let c.rg = a; c }\n");
+        assert_formats_to(
+            "if a<b{1}else if a>b{2}else{3}",
+            "if a < b { 1 } else if a > b { 2 } else { 3 }\n",
+        );
+        assert_formats_to(
+            "vec3( 1_000 ,2.5e3,\"a\\n\" ).zyx",
+            "vec3(1_000, 2.5e3, \"a\\n\").zyx\n",
+        );
+    }
+
+    #[test]
+    fn parentheses() {
+        assert_formats_to("((a))", "a\n");
+        assert_formats_to("({ a }) + (f(x)) * (g())", "{ a } + f(x) * g()\n");
+        assert_formats_to("(a + b) * c", "(a + b) * c\n");
+        assert_formats_to("(a * b) + c", "a * b + c\n");
+        assert_formats_to("(a - b) - c", "a - b - c\n");
+        assert_formats_to("a - (b - c)", "a - (b - c)\n");
+        assert_formats_to("-(a)", "-a\n");
+        assert_formats_to("-(a + b)", "-(a + b)\n");
+        assert_formats_to("(-a).x + (a + b).y", "(-a).x + (a + b).y\n");
+        assert_formats_to("0 .x + (1_0).y + (2.).z", "(0).x + (1_0).y + 2..z\n");
+        assert_formats_to("(a < b) == (c || d)", "a < b == (c || d)\n");
+        assert_formats_to("!(a && b) || c", "!(a && b) || c\n");
+    }
+
+    #[test]
+    fn wrapping() {
+        assert_eq!(
+            format_width("alpha + beta * gamma - delta", 20),
+            "alpha +\n    beta * gamma -\n    delta\n"
+        );
+        assert_eq!(
+            format_width(
+                "fn lerp(a, b, t) a + (b - a) * t; lerp(first, second, 0.5)",
+                20
+            ),
+            "\
+fn lerp(a, b, t)
+    a + (b - a) * t;
+lerp(
+    first,
+    second,
+    0.5
+)
+"
+        );
+        assert_eq!(
+            format_width("{ let a = 1; let b = 2; a + b }", 20),
+            "{\n    let a = 1;\n    let b = 2;\n    a + b\n}\n"
+        );
+    }
+
+    #[test]
+    fn comments() {
+        assert_formats_to(
+            "// Header\n\nfn f(a) a*2; // Doubles\n\n/* Result */ f( 1 )",
+            "// Header\n\nfn f(a) a * 2; // Doubles\n\n/* Result */ f(1)\n",
+        );
+        assert_formats_to("a + // Why\nb", "a + // Why\n    b\n");
+        assert_formats_to(
+            "{\n  // First\n  let a = 1; /* one */\n  a\n}",
+            "{\n    // First\n    let a = 1; /* one */\n    a\n}\n",
+        );
+        assert_formats_to("(/* inner */ a)", "/* inner */ a\n");
+        assert_formats_to("1 // end\n// trailing", "1 // end\n// trailing\n");
+        assert_formats_to("(a) /* a */ + (b) // b", "a /* a */ + b // b\n");
+        assert_formats_to(
+            "a + b // b\n// c\n+ c",
+            "a +\n    b // b\n    // c\n    +\n    c\n",
+        );
+    }
+
+    #[test]
+    fn idempotent() {
+        for s in [
+            "fn f(x) { let y = x * x; let y.x = 1; y }; f(vec2(1, 2)).y",
+            "a /* b */ + /* c */ c // d\n",
+            "if x { /* nothing */ 1 } else { // two\n 2 }",
+            "fn long_function_name(first_parameter, second_parameter) first_parameter * second_parameter + first_parameter / second_parameter; 1",
+            "h / (0 // a\n).x + (b) // b",
+            "0 / 0 / 2 //\n / 0",
+            "(3 / (3) //\n) // a\n// b",
+        ] {
+            for width in [10, 40, 80] {
+                format_width(s, width);
+            }
+        }
+    }
+
+    #[test]
+    fn syntax_errors() {
+        assert!(format("1 +", &FormatOptions::default()).is_err());
+        assert!(format("1 $ 2", &FormatOptions::default()).is_err());
+        // Other errors don't prevent formatting
+        assert!(format("f(1)", &FormatOptions::default()).is_ok());
+    }
+
+    #[test]
+    fn deep_nesting() {
+        // Expressions nested as deeply as the parser allows are formatted,
+        // and deeper ones are syntax errors
+        let nested = |depth| format!("{}1{}", "(1 - ".repeat(depth), ")".repeat(depth));
+        let formatted = format(&nested(60), &FormatOptions::default()).unwrap();
+        // The outermost parentheses aren't needed
+        assert_eq!(formatted.matches('(').count(), 59);
+        assert!(format(&nested(10_000), &FormatOptions::default()).is_err());
+        let swizzles = |depth| format!("x{}", ".x".repeat(depth));
+        assert!(format(&swizzles(100), &FormatOptions::default()).is_ok());
+        assert!(format(&swizzles(5_000), &FormatOptions::default()).is_err());
+    }
+}
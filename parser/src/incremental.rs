@@ -0,0 +1,331 @@
+use crate::{
+    interner::Interner,
+    lexer::{Error, Lexer, Span, TokenKind, TokenList, Trivia},
+};
+use std::{mem::take, ops::Range};
+
+/// A replacement of part of the source
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edit {
+    /// The replaced bytes of the source before the edit
+    pub range: Span,
+    pub text: String,
+}
+
+impl Edit {
+    pub fn new(range: Span, text: impl Into<String>) -> Self {
+        Self {
+            range,
+            text: text.into(),
+        }
+    }
+
+    pub fn apply(&self, source: &mut String) {
+        source.replace_range(self.range.range(), &self.text);
+    }
+
+    /// The change in the length of the source
+    fn shift(&self) -> i64 {
+        self.text.len() as i64 - (self.range.end - self.range.start) as i64
+    }
+}
+
+/// Everything the lexer produces for a source, kept so that it can be
+/// updated when the source is edited rather than lexed again in full
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lexed {
+    pub tokens: TokenList,
+    pub errors: Vec<Error>,
+    /// The value of each string literal, indexed by the payload of
+    /// [`TokenKind::Str`]
+    pub strings: Vec<String>,
+    pub trivia: Vec<Trivia>,
+    pub interner: Interner,
+}
+
+impl Lexed {
+    pub fn new(source: &str) -> Self {
+        Self::with_interner(source, Interner::new())
+    }
+
+    pub fn with_interner(source: &str, interner: Interner) -> Self {
+        let mut lexer = Lexer::with_interner(source, interner);
+        let (tokens, errors) = lexer.tokens();
+        Self {
+            tokens,
+            errors,
+            strings: lexer.strings().to_vec(),
+            trivia: lexer.trivia().to_vec(),
+            interner: lexer.into_interner(),
+        }
+    }
+
+    /// Updates the tokens after an edit, given the source with the edit
+    /// applied. Lexing restarts at the last token that ends before the edit
+    /// and stops as soon as it reaches the end of an old token after the edit,
+    /// beyond which nothing can have changed. Returns the indices of the
+    /// tokens that were lexed again.
+    pub fn edit(&mut self, source: &str, edit: &Edit) -> Range<usize> {
+        let shift = edit.shift();
+        let old_len = self.tokens.len() - 1;
+        debug_assert_eq!(
+            source.len() as i64,
+            self.tokens.span(old_len as u32).end as i64 + shift,
+            "The source must have the edit applied"
+        );
+
+        // Tokens that end before the edit were lexed without looking at it.
+        // Lexing can only restart after a valid token though, since the lexer
+        // skips the rest of malformed literals past the end of the token.
+        let old_kinds = self.tokens.kinds();
+        let mut first = self.tokens.first_ending_at(edit.range.start).min(old_len);
+        while first > 0 && old_kinds[first - 1] == TokenKind::Invalid {
+            first -= 1;
+        }
+        let restart = first
+            .checked_sub(1)
+            .map_or(0, |i| self.tokens.span(i as u32).end);
+        let edit_end = edit.range.start as i64 + edit.text.len() as i64;
+
+        let mut lexer = Lexer::with_interner(source, take(&mut self.interner));
+        lexer.seek(restart as usize);
+        let mut tokens = TokenList::default();
+        let mut errors = vec![];
+        // The old token index and offset to resume from, if lexing caught up
+        let mut resume = None;
+        while lexer.push_token(&mut tokens, &mut errors) {
+            let Some(last) = tokens.last() else {
+                continue;
+            };
+            if (last.end as i64) < edit_end || last.kind == TokenKind::Invalid {
+                continue;
+            }
+            // Both lexers are between tokens at the same point in the same
+            // text, so they would produce the same tokens from here on
+            let old_end = (last.end as i64 - shift) as u32;
+            let i = self.tokens.first_ending_at(old_end);
+            if (first..old_len).contains(&i)
+                && self.tokens.span(i as u32).end == old_end
+                && old_kinds[i] != TokenKind::Invalid
+            {
+                resume = Some((i + 1, old_end));
+                break;
+            }
+        }
+        let (old_resume, old_offset) = resume.unwrap_or((old_len, u32::MAX));
+
+        // Strings are numbered in source order, so the replaced tokens own a
+        // run of them that ends where the next old string starts
+        let next_string = |kinds: &[TokenKind]| {
+            kinds.iter().find_map(|kind| match kind {
+                TokenKind::Str(i) => Some(*i as usize),
+                _ => None,
+            })
+        };
+        let strings_start = next_string(&old_kinds[first..]).unwrap_or(self.strings.len());
+        let strings_end = next_string(&old_kinds[old_resume..]).unwrap_or(self.strings.len());
+        let string_shift = lexer.strings().len() as i64 - (strings_end - strings_start) as i64;
+        self.strings
+            .splice(strings_start..strings_end, lexer.strings().iter().cloned());
+        for kind in tokens.kinds_mut() {
+            if let TokenKind::Str(i) = kind {
+                *i += strings_start as u32;
+            }
+        }
+        let relexed = first..first + tokens.len();
+        self.tokens.splice(first..old_resume, tokens, shift);
+        for kind in &mut self.tokens.kinds_mut()[relexed.end..] {
+            if let TokenKind::Str(i) = kind {
+                *i = (*i as i64 + string_shift) as u32;
+            }
+        }
+
+        let replaced = restart..old_offset;
+        splice(&mut self.errors, errors, &replaced, shift);
+        splice(&mut self.trivia, lexer.trivia().to_vec(), &replaced, shift);
+        self.interner = lexer.into_interner();
+        relexed
+    }
+}
+
+/// Something found by the lexer alongside the tokens
+trait Spanned {
+    fn span(&self) -> Span;
+    fn span_mut(&mut self) -> &mut Span;
+}
+
+impl Spanned for Error {
+    fn span(&self) -> Span {
+        self.span
+    }
+
+    fn span_mut(&mut self) -> &mut Span {
+        &mut self.span
+    }
+}
+
+impl Spanned for Trivia {
+    fn span(&self) -> Span {
+        self.span
+    }
+
+    fn span_mut(&mut self) -> &mut Span {
+        &mut self.span
+    }
+}
+
+/// Replaces the items that start in the relexed part of the source and moves
+/// the items after them. The items must be in source order.
+fn splice<T: Spanned>(items: &mut Vec<T>, replacement: Vec<T>, replaced: &Range<u32>, shift: i64) {
+    let start = items.partition_point(|item| item.span().start < replaced.start);
+    let end = start
+        + items[start..]
+            .iter()
+            .take_while(|item| item.span().start < replaced.end)
+            .count();
+    let after = start + replacement.len();
+    items.splice(start..end, replacement);
+    for item in &mut items[after..] {
+        let span = item.span_mut();
+        span.start = (span.start as i64 + shift) as u32;
+        span.end = (span.end as i64 + shift) as u32;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_matches_full_lex(lexed: &Lexed, source: &str) {
+        let full = Lexed::with_interner(source, lexed.interner.clone());
+        assert_eq!(lexed.tokens, full.tokens, "Tokens differ for {source:?}");
+        assert_eq!(lexed.strings, full.strings, "Strings differ for {source:?}");
+        assert_eq!(lexed.errors, full.errors, "Errors differ for {source:?}");
+        assert_eq!(lexed.trivia, full.trivia, "Trivia differ for {source:?}");
+    }
+
+    /// A xorshift generator, so that failures can be reproduced
+    struct Random(u64);
+
+    impl Random {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+
+        /// Picks a character boundary of the source
+        fn boundary(&mut self, source: &str) -> u32 {
+            let mut i = self.below(source.len() + 1);
+            while !source.is_char_boundary(i) {
+                i -= 1;
+            }
+            i as u32
+        }
+    }
+
+    #[test]
+    fn local_edits() {
+        let mut source = String::from("fn f(a) a * 2; // Doubles\nf(alpha) + f(beta) + f(gamma)");
+        let mut lexed = Lexed::new(&source);
+        let start = source.find("beta").unwrap() as u32;
+        let edit = Edit::new(Span::new(start, start + 4), "delta_2");
+        edit.apply(&mut source);
+        let relexed = lexed.edit(&source, &edit);
+        assert_eq!(relexed.len(), 2);
+        assert_matches_full_lex(&lexed, &source);
+
+        // Joining two tokens
+        let start = source.find(" 2").unwrap() as u32;
+        let edit = Edit::new(Span::new(start - 2, start + 1), "");
+        edit.apply(&mut source);
+        lexed.edit(&source, &edit);
+        assert_matches_full_lex(&lexed, &source);
+        assert_eq!(lexed.tokens.len(), 22);
+    }
+
+    #[test]
+    fn long_tokens() {
+        let long = "b".repeat(300);
+        let mut source = format!("a + {long} * \"{long}\" - {long}");
+        let mut lexed = Lexed::new(&source);
+        for (range, text) in [(0..1, "ccc"), (10..10, "d"), (6..400, ""), (4..4, &*long)] {
+            let edit = Edit::new(Span::new(range.start, range.end), text);
+            edit.apply(&mut source);
+            lexed.edit(&source, &edit);
+            assert_matches_full_lex(&lexed, &source);
+        }
+    }
+
+    #[test]
+    fn spreading_edits() {
+        // Edits that change how everything after them is lexed
+        for (before, range, text) in [
+            ("a + b // c\nd", 5..5, "/*"),
+            ("a /* b */ c", 2..4, ""),
+            ("a \"b\" c \"d\"", 2..3, ""),
+            ("a \"b\nc\" d", 4..5, " "),
+            ("1 . 5", 1..2, ""),
+            ("x = = y", 3..4, ""),
+            ("1e+ x", 3..4, ""),
+            ("\"\\q\" 1", 4..4, " 2"),
+        ] {
+            let mut source = before.to_string();
+            let mut lexed = Lexed::new(&source);
+            let edit = Edit::new(Span::new(range.start, range.end), text);
+            edit.apply(&mut source);
+            lexed.edit(&source, &edit);
+            assert_matches_full_lex(&lexed, &source);
+        }
+    }
+
+    #[test]
+    fn random_edits() {
+        const SNIPPETS: [&str; 24] = [
+            "",
+            " ",
+            "\n",
+            "a",
+            "fn",
+            "1",
+            "2.5",
+            "e",
+            "_",
+            ".",
+            "=",
+            "!",
+            "/",
+            "*",
+            "//",
+            "/*",
+            "*/",
+            "\"",
+            "\\",
+            "é",
+            "€",
+            "&",
+            "(a + b)",
+            "let x = 1;",
+        ];
+        let mut random = Random(0x2545f4914f6cdd1d);
+        for initial in [
+            "",
+            "fn lerp(a, b, t) a + (b - a) * t; lerp(x, y, 0.5)",
+            "{ let s = \"a\\n\"; /* c /* d */ */ s } // e\n1_000 + 2.5e3",
+            "é + €€ # 1e+ 99999999999 \"\\q\" /* unterminated",
+        ] {
+            let mut source = initial.to_string();
+            let mut lexed = Lexed::new(&source);
+            for _ in 0..500 {
+                let a = random.boundary(&source);
+                let b = random.boundary(&source);
+                let text = SNIPPETS[random.below(SNIPPETS.len())];
+                let edit = Edit::new(Span::new(a.min(b), a.max(b)), text);
+                edit.apply(&mut source);
+                lexed.edit(&source, &edit);
+                assert_matches_full_lex(&lexed, &source);
+            }
+        }
+    }
+}
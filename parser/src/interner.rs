@@ -0,0 +1,84 @@
+use crate::lexer::Id;
+use std::collections::HashMap;
+
+/// Assigns each distinct identifier an [`Id`] and maps it back to its text.
+/// Ids are never reassigned, so an interner can be passed from one source to
+/// the next with [`parse_with`](crate::parse_with) and every AST parsed with
+/// it agrees on which name each id stands for.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Interner {
+    ids: HashMap<Box<str>, Id>,
+    names: Vec<Box<str>>,
+}
+
+impl Interner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Gets the id for the name, assigning the next one if the name hasn't
+    /// been seen before
+    pub fn intern(&mut self, name: &str) -> Id {
+        if let Some(&id) = self.ids.get(name) {
+            return id;
+        }
+        let id = Id(self.names.len() as u32);
+        self.names.push(name.into());
+        self.ids.insert(name.into(), id);
+        id
+    }
+
+    /// Gets the id of a name that has already been interned
+    pub fn get(&self, name: &str) -> Option<Id> {
+        self.ids.get(name).copied()
+    }
+
+    /// Gets the text of an id from this interner
+    pub fn resolve(&self, id: Id) -> &str {
+        &self.names[id.0 as usize]
+    }
+
+    /// Gets the name of every id in order
+    pub fn names(&self) -> impl Iterator<Item = &str> + '_ {
+        self.names.iter().map(|name| &**name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse, parse_with};
+
+    #[test]
+    fn interns() {
+        let mut interner = Interner::new();
+        let a = interner.intern("a");
+        let b = interner.intern("b");
+        assert_eq!(interner.intern("a"), a);
+        assert_ne!(a, b);
+        assert_eq!(interner.resolve(b), "b");
+        assert_eq!(interner.get("b"), Some(b));
+        assert_eq!(interner.get("c"), None);
+        assert_eq!(interner.names().collect::<Vec<_>>(), ["a", "b"]);
+    }
+
+    #[test]
+    fn shared_between_sources() {
+        let first = parse("x * y");
+        let second = parse_with("y + z", first.ast.interner.clone());
+        let id = |ast: &crate::Ast, index| ast.id(ast.node(index)).unwrap();
+        // The y of each source
+        assert_eq!(id(&first.ast, 1), id(&second.ast, 0));
+        assert_ne!(id(&second.ast, 0), id(&second.ast, 1));
+        assert_eq!(second.ast.interner.len(), 3);
+        assert_eq!(second.ast.name(id(&second.ast, 1)), "z");
+    }
+}
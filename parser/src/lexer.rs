@@ -1,168 +1,684 @@
+use crate::interner::Interner;
 use std::{
-    collections::HashMap,
-    iter::Peekable,
-    str::{from_utf8_unchecked, CharIndices},
+    borrow::Cow,
+    fmt::{self, Display, Formatter},
+    mem::size_of,
+    ops::Range,
+    str::from_utf8_unchecked,
 };
 
-// TODO: SOA
+/// A token gathered from the columns of a [`TokenList`]
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Token {
     pub kind: TokenKind,
+    /// Byte offset of the first character of the token
     pub start: u32,
+    /// Byte offset one past the last character of the token
+    pub end: u32,
+}
+
+impl Token {
+    pub fn span(&self) -> Span {
+        Span::new(self.start, self.end)
+    }
+}
+
+/// Tokens stored as separate columns. Ends aren't stored since most tokens are
+/// short, so each token has a byte for its length instead, and the few tokens
+/// too long for that have their lengths in a separate sorted list.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TokenList {
+    kinds: Vec<TokenKind>,
+    starts: Vec<u32>,
+    lengths: Vec<u8>,
+    /// The index and length of each token whose length is at least `LONG`
+    long: Vec<(u32, u32)>,
+}
+
+/// The length byte of tokens whose lengths are in the separate list
+const LONG: u8 = u8::MAX;
+
+impl TokenList {
+    pub fn push(&mut self, token: Token) {
+        let length = token.end - token.start;
+        if length >= LONG as u32 {
+            self.long.push((self.len() as u32, length));
+        }
+        self.kinds.push(token.kind);
+        self.starts.push(token.start);
+        self.lengths.push(length.min(LONG as u32) as u8);
+    }
+
+    pub fn get(&self, index: u32) -> Token {
+        Token {
+            kind: self.kind(index),
+            start: self.starts[index as usize],
+            end: self.end(index),
+        }
+    }
+
+    pub fn last(&self) -> Option<Token> {
+        self.len().checked_sub(1).map(|i| self.get(i as u32))
+    }
+
+    pub fn kind(&self, index: u32) -> TokenKind {
+        self.kinds[index as usize]
+    }
+
+    pub fn span(&self, index: u32) -> Span {
+        Span::new(self.starts[index as usize], self.end(index))
+    }
+
+    fn end(&self, index: u32) -> u32 {
+        let i = index as usize;
+        let length = match self.lengths[i] {
+            LONG => {
+                let long = self.long.binary_search_by_key(&index, |&(i, _)| i);
+                self.long[long.unwrap()].1
+            }
+            length => length as u32,
+        };
+        self.starts[i] + length
+    }
+
+    /// Gets the index of the first token that ends at or after the offset, or
+    /// the number of tokens if there is none
+    pub fn first_ending_at(&self, offset: u32) -> usize {
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let middle = (low + high) / 2;
+            if self.end(middle as u32) < offset {
+                low = middle + 1;
+            } else {
+                high = middle;
+            }
+        }
+        low
+    }
+
+    pub fn kinds(&self) -> &[TokenKind] {
+        &self.kinds
+    }
+
+    pub(crate) fn kinds_mut(&mut self) -> &mut [TokenKind] {
+        &mut self.kinds
+    }
+
+    pub fn starts(&self) -> &[u32] {
+        &self.starts
+    }
+
+    pub fn len(&self) -> usize {
+        self.kinds.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.kinds.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Token> + '_ {
+        (0..self.len() as u32).map(|i| self.get(i))
+    }
+
+    /// Replaces a range of tokens, moving the tokens after them by the given
+    /// number of bytes
+    pub fn splice(&mut self, range: Range<usize>, replacement: TokenList, shift: i64) {
+        let after = range.start + replacement.len();
+        let index_shift = replacement.len() as i64 - range.len() as i64;
+        let long_range = self
+            .long
+            .partition_point(|&(i, _)| (i as usize) < range.start)
+            ..self
+                .long
+                .partition_point(|&(i, _)| (i as usize) < range.end);
+        let long_after = long_range.start + replacement.long.len();
+        self.long.splice(
+            long_range,
+            replacement
+                .long
+                .into_iter()
+                .map(|(i, length)| (i + range.start as u32, length)),
+        );
+        for (i, _) in &mut self.long[long_after..] {
+            *i = (*i as i64 + index_shift) as u32;
+        }
+        self.kinds.splice(range.clone(), replacement.kinds);
+        self.starts.splice(range.clone(), replacement.starts);
+        self.lengths.splice(range, replacement.lengths);
+        for start in &mut self.starts[after..] {
+            *start = (*start as i64 + shift) as u32;
+        }
+    }
+
+    /// The number of bytes occupied by the tokens, excluding spare capacity
+    pub fn size_in_bytes(&self) -> usize {
+        self.kinds.len() * size_of::<TokenKind>()
+            + self.starts.len() * size_of::<u32>()
+            + self.lengths.len() * size_of::<u8>()
+            + self.long.len() * size_of::<(u32, u32)>()
+    }
+}
+
+/// A range of byte offsets into the source
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub start: u32,
+    pub end: u32,
+}
+
+impl Span {
+    pub fn new(start: u32, end: u32) -> Self {
+        Self { start, end }
+    }
+
+    /// Gets the smallest span that covers both spans
+    pub fn to(self, other: Span) -> Self {
+        Self::new(self.start.min(other.start), self.end.max(other.end))
+    }
+
+    pub fn range(self) -> Range<usize> {
+        self.start as usize..self.end as usize
+    }
+}
+
+impl Display for Span {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}..{}", self.start, self.end)
+    }
+}
+
+/// Source between tokens that is kept for tools such as formatters.
+/// Whitespace is not recorded since it can be recovered from the gaps between
+/// tokens and trivia.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Trivia {
+    pub kind: TriviaKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriviaKind {
+    /// A comment from `//` to the end of the line, excluding the newline
+    LineComment,
+    /// A comment between `/*` and `*/`, which may nest
+    BlockComment,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum TokenKind {
     Eof,
-    Ident,
-    Float,
+    Ident(Id),
+    /// A string literal, holding an index into the string table of the lexer
+    Str(u32),
+    /// A number literal without a fractional part or exponent
+    Int(i32),
+    Float(f32),
     ParenLeft,
     ParenRight,
+    BraceLeft,
+    BraceRight,
+    Comma,
+    Colon,
+    Dot,
+    Semicolon,
+    Equals,
     Fn,
+    Let,
+    If,
+    Else,
     Plus,
     Minus,
     Asterisk,
     Slash,
     Exclamation,
+    Less,
+    LessEquals,
+    Greater,
+    GreaterEquals,
+    EqualsEquals,
+    ExclamationEquals,
+    AmpersandAmpersand,
+    PipePipe,
+    /// Source that could not be lexed. The error has already been reported.
+    Invalid,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Id(pub u32);
 
+/// Lexes UTF-8 source a byte at a time. ASCII is handled directly, and only
+/// other characters are decoded.
 pub struct Lexer<'a> {
-    pub line: u32,
-    pub column: u32,
+    /// The bytes of a `str`, so that every character boundary is valid UTF-8
     input: &'a [u8],
-    iter: Peekable<CharIndices<'a>>,
-    identifiers: HashMap<&'a str, Id>,
+    /// The byte offset of the next character
+    offset: usize,
+    interner: Interner,
+    strings: Vec<String>,
+    trivia: Vec<Trivia>,
 }
 
 impl<'a> Lexer<'a> {
-    // TODO: Take byte slice instead to avoid a second pass
     pub fn new(s: &'a str) -> Self {
+        Self::with_interner(s, Interner::new())
+    }
+
+    /// Creates a lexer that adds identifiers to an existing interner, so that
+    /// they get the same ids as in other sources lexed with it
+    pub fn with_interner(s: &'a str, interner: Interner) -> Self {
         Self {
-            iter: s.char_indices().peekable(),
             input: s.as_bytes(),
-            identifiers: HashMap::new(),
-            line: 1,
-            column: 1,
+            offset: 0,
+            interner,
+            strings: vec![],
+            trivia: vec![],
         }
     }
 
+    /// Lexes the rest of the input, ending with an `Eof` token. Lexing
+    /// continues past errors. Stray characters and unterminated comments are
+    /// dropped, but malformed literals are kept as `Invalid` tokens since they
+    /// still take up space in the expression.
+    pub fn tokens(&mut self) -> (TokenList, Vec<Error>) {
+        let mut tokens = TokenList::default();
+        let mut errors = vec![];
+        while self.push_token(&mut tokens, &mut errors) {}
+        let end = self.input.len() as u32;
+        tokens.push(Token {
+            kind: TokenKind::Eof,
+            start: end,
+            end,
+        });
+        (tokens, errors)
+    }
+
+    /// Lexes the next token into the list, or records the error found in its
+    /// place. Returns false once the input is exhausted.
+    pub(crate) fn push_token(&mut self, tokens: &mut TokenList, errors: &mut Vec<Error>) -> bool {
+        match self.token() {
+            Ok(Some(token)) => tokens.push(token),
+            Ok(None) => return false,
+            Err(error) => {
+                if !matches!(
+                    error.kind,
+                    ErrorKind::TokenStart(_) | ErrorKind::UnterminatedComment
+                ) {
+                    tokens.push(Token {
+                        kind: TokenKind::Invalid,
+                        start: error.span.start,
+                        end: error.span.end,
+                    });
+                }
+                errors.push(error);
+            }
+        }
+        true
+    }
+
+    /// Continues lexing from the byte offset, which must be the end of a
+    /// token or the start of the input
+    pub(crate) fn seek(&mut self, offset: usize) {
+        self.offset = offset;
+    }
+
     pub fn token(&mut self) -> Result<Option<Token>, Error> {
-        self.take_whitespace();
+        self.take_trivia()?;
         let Some((start, c)) = self.take() else {
             return Ok(None);
         };
         let kind = match c {
             '(' => TokenKind::ParenLeft,
             ')' => TokenKind::ParenRight,
+            '{' => TokenKind::BraceLeft,
+            '}' => TokenKind::BraceRight,
+            '=' => self.then_equals(TokenKind::Equals, TokenKind::EqualsEquals),
+            '<' => self.then_equals(TokenKind::Less, TokenKind::LessEquals),
+            '>' => self.then_equals(TokenKind::Greater, TokenKind::GreaterEquals),
+            '&' if self.take_if('&') => TokenKind::AmpersandAmpersand,
+            '|' if self.take_if('|') => TokenKind::PipePipe,
+            ',' => TokenKind::Comma,
+            ':' => TokenKind::Colon,
+            ';' => TokenKind::Semicolon,
             '+' => TokenKind::Plus,
             '-' => TokenKind::Minus,
             '*' => TokenKind::Asterisk,
             '/' => TokenKind::Slash,
-            '!' => TokenKind::Exclamation,
-            '_' | 'a'..='z' | 'A'..='Z' => self.ident_or_keyword(start),
+            '!' => self.then_equals(TokenKind::Exclamation, TokenKind::ExclamationEquals),
+            '"' => self.string(start)?,
+            '0'..='9' => self.number_or_skip(start)?,
+            '.' if matches!(self.peek(), Some((_, '0'..='9'))) => self.number_or_skip(start)?,
+            '.' => TokenKind::Dot,
+            c if c == '_' || unicode_ident::is_xid_start(c) => self.ident_or_keyword(start),
             c => {
-                return Err(self.error(ErrorKind::TokenStart(c)));
+                return Err(self.error(ErrorKind::TokenStart(c), start));
             }
         };
         Ok(Some(Token {
-            start: c.into(),
             kind,
+            start: start as u32,
+            end: self.offset() as u32,
         }))
     }
 
-    fn float(&mut self) -> Result<TokenKind, Error> {
-        // TODO: Support underscores
-        // TODO: Support signed ints
-        // TODO: Support floats
+    /// Chooses between a one-character operator and the same operator
+    /// followed by `=`
+    fn then_equals(&mut self, single: TokenKind, with_equals: TokenKind) -> TokenKind {
+        if self.take_if('=') {
+            with_equals
+        } else {
+            single
+        }
+    }
+
+    /// Lexes a number, skipping the rest of the literal if it is malformed so
+    /// that lexing can resume afterward
+    fn number_or_skip(&mut self, start: usize) -> Result<TokenKind, Error> {
+        self.number(start).inspect_err(|_| {
+            while let Some((_, '_' | '.' | '0'..='9' | 'a'..='z' | 'A'..='Z')) = self.peek() {
+                self.take();
+            }
+        })
+    }
+
+    /// digits ("." digits?)? (("e" | "E") ("+" | "-")? digits)?
+    ///
+    /// The first character has already been taken and is either a digit or a
+    /// "." that is known to be followed by a digit. Numbers with neither a
+    /// "." nor an exponent are integers.
+    fn number(&mut self, start: usize) -> Result<TokenKind, Error> {
+        let mut is_float = true;
+        if self.input[start] != b'.' {
+            self.digits_after_first()?;
+            if let Some((_, '.')) = self.peek() {
+                self.take();
+                self.digits()?;
+            } else {
+                is_float = false;
+            }
+        } else {
+            self.digits()?;
+        }
+
+        if let Some((_, 'e' | 'E')) = self.peek() {
+            is_float = true;
+            self.take();
+            if let Some((_, '+' | '-')) = self.peek() {
+                self.take();
+            }
+            if !self.digits()? {
+                return Err(self.error(ErrorKind::Exponent, start));
+            }
+        }
+
+        let text = unsafe { from_utf8_unchecked(&self.input[start..self.offset()]) };
+        let s = if text.contains('_') {
+            Cow::Owned(text.replace('_', ""))
+        } else {
+            Cow::Borrowed(text)
+        };
+        if !is_float {
+            return match s.parse() {
+                Ok(value) => Ok(TokenKind::Int(value)),
+                Err(_) => Err(self.error(ErrorKind::IntegerRange, start)),
+            };
+        }
+        // The grammar above only admits strings that Rust also accepts
+        let value = s.parse().unwrap_or(f32::NAN);
+        Ok(TokenKind::Float(value))
+    }
+
+    /// Takes a run of digits with single underscores between them, returning
+    /// whether any digits were taken.
+    fn digits(&mut self) -> Result<bool, Error> {
+        match self.peek() {
+            Some((_, '0'..='9')) => {
+                self.take();
+                self.digits_after_first()?;
+                Ok(true)
+            }
+            Some((start, '_')) => {
+                self.take();
+                Err(self.error(ErrorKind::Underscore, start))
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn digits_after_first(&mut self) -> Result<(), Error> {
         loop {
             match self.peek() {
                 Some((_, '0'..='9')) => {
                     self.take();
-                    continue;
                 }
-                _ => break,
+                Some((start, '_')) => {
+                    self.take();
+                    match self.peek() {
+                        Some((_, '0'..='9')) => {
+                            self.take();
+                        }
+                        _ => return Err(self.error(ErrorKind::Underscore, start)),
+                    }
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    /// Lexes a string literal whose opening quote has been taken, adding its
+    /// value to the string table. Strings cannot contain newlines, which keeps
+    /// a missing quote from swallowing the rest of the source.
+    fn string(&mut self, start: usize) -> Result<TokenKind, Error> {
+        let mut value = String::new();
+        let mut error = None;
+        loop {
+            let Some((offset, c)) = self.peek() else {
+                return Err(self.error(ErrorKind::UnterminatedString, start));
             };
+            if c == '\n' {
+                return Err(self.error(ErrorKind::UnterminatedString, start));
+            }
+            self.take();
+            match c {
+                '"' => break,
+                '\\' => match self.escape() {
+                    Some(c) => value.push(c),
+                    // Finish the string so that lexing resumes after it
+                    None => {
+                        error.get_or_insert_with(|| self.error(ErrorKind::Escape, offset));
+                    }
+                },
+                c => value.push(c),
+            }
+        }
+        if let Some(error) = error {
+            return Err(error);
         }
-        Ok(TokenKind::Float)
+        self.strings.push(value);
+        Ok(TokenKind::Str(self.strings.len() as u32 - 1))
     }
 
-    fn ident_or_keyword(&mut self, start: usize) -> TokenKind {
-        let s = loop {
-            match self.peek() {
-                Some((_, '_' | 'a'..='z' | 'A'..='Z' | '0'..='9')) => {
+    /// Lexes the rest of an escape sequence after the backslash
+    fn escape(&mut self) -> Option<char> {
+        let c = match self.peek()? {
+            (_, '\n') => return None,
+            (_, c) => c,
+        };
+        self.take();
+        match c {
+            'n' => Some('\n'),
+            'r' => Some('\r'),
+            't' => Some('\t'),
+            '0' => Some('\0'),
+            '\\' | '"' | '\'' => Some(c),
+            'u' => {
+                // \u{XXXX} with one to six hex digits
+                if !self.take_if('{') {
+                    return None;
+                }
+                let mut code = 0u32;
+                let mut digits = 0;
+                while let Some((_, c)) = self.peek() {
+                    let Some(digit) = c.to_digit(16) else {
+                        break;
+                    };
                     self.take();
-                    continue;
+                    code = code * 16 + digit;
+                    digits += 1;
+                    if digits > 6 {
+                        return None;
+                    }
+                }
+                if digits == 0 || !self.take_if('}') {
+                    return None;
                 }
-                Some((end, _)) => break &self.input[start..end],
-                None => break &self.input[start..],
+                char::from_u32(code)
             }
-        };
-        match unsafe { from_utf8_unchecked(s) } {
+            _ => None,
+        }
+    }
+
+    fn ident_or_keyword(&mut self, start: usize) -> TokenKind {
+        loop {
+            match self.input.get(self.offset) {
+                Some(b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'_') => self.offset += 1,
+                Some(byte) if !byte.is_ascii() => match self.peek() {
+                    Some((_, c)) if unicode_ident::is_xid_continue(c) => {
+                        self.take();
+                    }
+                    _ => break,
+                },
+                _ => break,
+            }
+        }
+        match unsafe { from_utf8_unchecked(&self.input[start..self.offset]) } {
             "fn" => TokenKind::Fn,
-            _ => TokenKind::Ident,
+            "let" => TokenKind::Let,
+            "if" => TokenKind::If,
+            "else" => TokenKind::Else,
+            s => TokenKind::Ident(self.interner.intern(s)),
         }
     }
 
-    fn error(&self, kind: ErrorKind) -> Error {
+    /// Gets the value of each string literal seen so far, indexed by the
+    /// payload of [`TokenKind::Str`]
+    pub fn strings(&self) -> &[String] {
+        &self.strings
+    }
+
+    /// Gets the comments seen so far in source order
+    pub fn trivia(&self) -> &[Trivia] {
+        &self.trivia
+    }
+
+    /// Gets the interner holding the identifiers seen so far
+    pub fn interner(&self) -> &Interner {
+        &self.interner
+    }
+
+    pub fn into_interner(self) -> Interner {
+        self.interner
+    }
+
+    /// Creates an error spanning from the given byte offset to the current one
+    fn error(&mut self, kind: ErrorKind, start: usize) -> Error {
         Error {
-            line: self.line,
-            column: self.column,
+            span: Span::new(start as u32, self.offset() as u32),
             kind,
         }
     }
 
+    /// Gets the byte offset of the next character
+    fn offset(&self) -> usize {
+        self.offset
+    }
+
     fn take(&mut self) -> Option<(usize, char)> {
-        self.column += 1;
-        self.iter.next()
+        let next = self.peek()?;
+        self.offset += next.1.len_utf8();
+        Some(next)
     }
 
-    fn peek(&mut self) -> Option<(usize, char)> {
-        self.iter.peek().cloned()
+    fn peek(&self) -> Option<(usize, char)> {
+        let &byte = self.input.get(self.offset)?;
+        let c = if byte.is_ascii() {
+            byte as char
+        } else {
+            // The offset is always on a character boundary
+            let rest = unsafe { from_utf8_unchecked(&self.input[self.offset..]) };
+            rest.chars().next()?
+        };
+        Some((self.offset, c))
     }
 
-    fn take_whitespace(&mut self) {
-        loop {
-            match self.peek() {
-                Some((_, '\n')) => {
-                    self.take();
-                    self.line += 1;
-                    self.column = 1;
-                }
-                Some((_, c)) if c.is_whitespace() => {
-                    self.take();
-                }
-                _ => break,
+    /// Takes the next character if it matches, returning whether it did
+    fn take_if(&mut self, c: char) -> bool {
+        match self.peek() {
+            Some((_, next)) if next == c => {
+                self.offset += c.len_utf8();
+                true
             }
+            _ => false,
         }
     }
-}
 
-// TODO: Store line starts to accelerate file position lookups
+    /// Skips whitespace and comments, recording the comments as trivia
+    fn take_trivia(&mut self) -> Result<(), Error> {
+        loop {
+            match self.input.get(self.offset) {
+                Some(b'\t'..=b'\r' | b' ') => self.offset += 1,
+                Some(byte) if !byte.is_ascii() => match self.peek() {
+                    Some((_, c)) if c.is_whitespace() => {
+                        self.take();
+                    }
+                    _ => return Ok(()),
+                },
+                Some(b'/') => match self.input.get(self.offset + 1) {
+                    Some(b'/') => {
+                        // Newlines never occur within multibyte characters
+                        let start = self.offset;
+                        self.offset = self.input[start..]
+                            .iter()
+                            .position(|&byte| byte == b'\n')
+                            .map_or(self.input.len(), |end| start + end);
+                        self.push_trivia(TriviaKind::LineComment, start);
+                    }
+                    Some(b'*') => self.block_comment(self.offset)?,
+                    _ => return Ok(()),
+                },
+                _ => return Ok(()),
+            }
+        }
+    }
 
-/// Gets the line and column number of the token at the given byte offset.
-///
-/// SAFETY: token_start must index the start of a UTF-8 char
-pub unsafe fn token_line_and_column(s: &str, token_start: usize) -> (u32, u32) {
-    let s = &s.as_bytes()[..token_start];
-    let s = unsafe { from_utf8_unchecked(s) };
-    last_line_and_column(s)
-}
+    fn block_comment(&mut self, start: usize) -> Result<(), Error> {
+        self.take();
+        self.take();
+        let mut depth = 1u32;
+        while depth > 0 {
+            match self.take() {
+                Some((_, '/')) if self.take_if('*') => depth += 1,
+                Some((_, '*')) if self.take_if('/') => depth -= 1,
+                Some(_) => {}
+                None => {
+                    self.push_trivia(TriviaKind::BlockComment, start);
+                    return Err(self.error(ErrorKind::UnterminatedComment, start));
+                }
+            }
+        }
+        self.push_trivia(TriviaKind::BlockComment, start);
+        Ok(())
+    }
 
-pub fn last_line_and_column(s: &str) -> (u32, u32) {
-    s.chars().fold((1, 1), |(line, column), c| match c {
-        '\n' => (line + 1, 1),
-        _ => (line, column + 1),
-    })
+    fn push_trivia(&mut self, kind: TriviaKind, start: usize) {
+        let span = Span::new(start as u32, self.offset() as u32);
+        self.trivia.push(Trivia { kind, span });
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
-#[error("Lexer error at {line}:{column}:\n{kind}")]
+#[error("Lexer error at {span}:\n{kind}")]
 pub struct Error {
-    pub line: u32,
-    pub column: u32,
+    pub span: Span,
     pub kind: ErrorKind,
 }
 
@@ -171,12 +687,58 @@ pub struct Error {
 pub enum ErrorKind {
     #[error("Unexpected {0} where a token was expected to start")]
     TokenStart(char),
+    #[error("Expected digits in the exponent of a number")]
+    Exponent,
+    #[error("Underscores in a number must separate two digits")]
+    Underscore,
+    #[error("The integer is too large")]
+    IntegerRange,
+    #[error("Expected `*/` to close the comment")]
+    UnterminatedComment,
+    #[error("Expected `\"` to close the string")]
+    UnterminatedString,
+    #[error("Unknown escape sequence")]
+    Escape,
+}
+
+impl ErrorKind {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ErrorKind::TokenStart(_) => "E0001",
+            ErrorKind::Exponent => "E0002",
+            ErrorKind::Underscore => "E0003",
+            ErrorKind::IntegerRange => "E0004",
+            ErrorKind::UnterminatedComment => "E0005",
+            ErrorKind::UnterminatedString => "E0006",
+            ErrorKind::Escape => "E0007",
+        }
+    }
+
+    pub fn help(&self) -> Option<&'static str> {
+        match self {
+            ErrorKind::TokenStart('&' | '|') => Some("Logical operators are written `&&` and `||`"),
+            ErrorKind::TokenStart(_) => None,
+            ErrorKind::Exponent => Some("Add digits after the exponent, as in `1e3`"),
+            ErrorKind::Underscore => Some("Remove the underscore or put a digit after it"),
+            ErrorKind::IntegerRange => Some("Add a `.` to make the number a float"),
+            ErrorKind::UnterminatedComment => {
+                Some("Block comments nest, so each `/*` needs its own `*/`")
+            }
+            ErrorKind::UnterminatedString => {
+                Some("Strings end on the line they start; write `\\n` for a line break")
+            }
+            ErrorKind::Escape => Some(
+                "The escapes are `\\n`, `\\r`, `\\t`, `\\0`, `\\\\`, `\\\"`, `\\'`, and `\\u{...}`",
+            ),
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::TokenKind::*;
     use super::*;
+    use proptest::{collection::vec, prelude::*, sample::select};
 
     fn token_kinds(s: &str) -> Box<[TokenKind]> {
         let mut v = Vec::new();
@@ -198,6 +760,290 @@ mod tests {
 
     #[test]
     fn words() {
-        assert_tokens_match(" hi hello fn ", [Ident, Ident, Fn])
+        assert_tokens_match(" hi hello fn ", [Ident(Id(0)), Ident(Id(1)), Fn])
+    }
+
+    #[test]
+    fn blocks() {
+        assert_tokens_match(
+            "{ let x = 1; x }",
+            [
+                BraceLeft,
+                Let,
+                Ident(Id(0)),
+                Equals,
+                Int(1),
+                Semicolon,
+                Ident(Id(0)),
+                BraceRight,
+            ],
+        )
+    }
+
+    #[test]
+    fn comparisons() {
+        assert_tokens_match(
+            "< <= > >= = == ! != && || if else",
+            [
+                Less,
+                LessEquals,
+                Greater,
+                GreaterEquals,
+                Equals,
+                EqualsEquals,
+                Exclamation,
+                ExclamationEquals,
+                AmpersandAmpersand,
+                PipePipe,
+                If,
+                Else,
+            ],
+        );
+        assert_tokens_match("a<=-b", [Ident(Id(0)), LessEquals, Minus, Ident(Id(1))]);
+        assert_tokens_match("!!=", [Exclamation, ExclamationEquals]);
+    }
+
+    #[test]
+    fn swizzles() {
+        assert_tokens_match("c.rgb", [Ident(Id(0)), Dot, Ident(Id(1))]);
+        assert_tokens_match("c. .5", [Ident(Id(0)), Dot, Float(0.5)]);
+    }
+
+    #[test]
+    fn interning() {
+        let s = "b a b_2 a b";
+        assert_tokens_match(
+            s,
+            [
+                Ident(Id(0)),
+                Ident(Id(1)),
+                Ident(Id(2)),
+                Ident(Id(1)),
+                Ident(Id(0)),
+            ],
+        );
+        let mut lexer = Lexer::new(s);
+        while let Ok(Some(_)) = lexer.token() {}
+        let names: Vec<_> = lexer.interner().names().collect();
+        assert_eq!(names, ["b", "a", "b_2"]);
+
+        // Identifiers keep their ids from earlier sources
+        let mut lexer = Lexer::with_interner("a c", lexer.into_interner());
+        let (tokens, _) = lexer.tokens();
+        assert_eq!(tokens.kinds(), [Ident(Id(1)), Ident(Id(3)), Eof]);
+    }
+
+    #[test]
+    fn punctuation() {
+        assert_tokens_match(
+            "f(a: c, b);",
+            [
+                Ident(Id(0)),
+                ParenLeft,
+                Ident(Id(1)),
+                Colon,
+                Ident(Id(2)),
+                Comma,
+                Ident(Id(3)),
+                ParenRight,
+                Semicolon,
+            ],
+        )
+    }
+
+    #[test]
+    fn numbers() {
+        assert_tokens_match(
+            "1 2.5 .5 3. 1e3 1E-2 2.5e+1 1_000.000_1 1_000 2147483647",
+            [
+                Int(1),
+                Float(2.5),
+                Float(0.5),
+                Float(3.),
+                Float(1e3),
+                Float(1e-2),
+                Float(25.),
+                Float(1000.0001),
+                Int(1000),
+                Int(i32::MAX),
+            ],
+        )
+    }
+
+    #[test]
+    fn float_then_operator() {
+        assert_tokens_match("1-2.", [Int(1), Minus, Float(2.)])
+    }
+
+    fn first_error(s: &str) -> ErrorKind {
+        let mut lexer = Lexer::new(s);
+        loop {
+            match lexer.token() {
+                Ok(Some(_)) => {}
+                Ok(None) => panic!("Expected an error lexing {s:?}"),
+                Err(e) => return e.kind,
+            }
+        }
+    }
+
+    #[test]
+    fn spans() {
+        let mut lexer = Lexer::new("ab +\n 1.5");
+        let mut spans = vec![];
+        while let Ok(Some(token)) = lexer.token() {
+            spans.push((token.start, token.end));
+        }
+        assert_eq!(spans, [(0, 2), (3, 4), (6, 9)]);
+
+        // Spans of tokens too long for their length byte
+        let long = "a".repeat(300);
+        let (tokens, _) = Lexer::new(&format!("{long} + {long}")).tokens();
+        let spans: Vec<_> = (0..tokens.len() as u32).map(|i| tokens.span(i)).collect();
+        assert_eq!(
+            spans,
+            [
+                Span::new(0, 300),
+                Span::new(301, 302),
+                Span::new(303, 603),
+                Span::new(603, 603)
+            ]
+        );
+        assert_eq!(tokens.first_ending_at(301), 1);
+        assert_eq!(tokens.first_ending_at(400), 2);
+    }
+
+    #[test]
+    fn error_spans() {
+        let span = |s| Lexer::new(s).token().unwrap_err().span;
+        assert_eq!(span("1e+ "), Span::new(0, 3));
+        assert_eq!(span("1__0"), Span::new(1, 2));
+        assert_eq!(span("€"), Span::new(0, 3));
+    }
+
+    #[test]
+    fn resumes_after_errors() {
+        let mut lexer = Lexer::new("1__0x $ 2.");
+        assert_eq!(lexer.token().unwrap_err().kind, ErrorKind::Underscore);
+        assert_eq!(lexer.token().unwrap_err().kind, ErrorKind::TokenStart('$'));
+        assert_eq!(lexer.token().unwrap().unwrap().kind, Float(2.));
+        assert_eq!(lexer.token(), Ok(None));
+    }
+
+    #[test]
+    fn float_errors() {
+        assert_eq!(first_error("1e"), ErrorKind::Exponent);
+        assert_eq!(first_error("1e+ "), ErrorKind::Exponent);
+        assert_eq!(first_error("1__0"), ErrorKind::Underscore);
+        assert_eq!(first_error("1_"), ErrorKind::Underscore);
+        assert_eq!(first_error("1._5"), ErrorKind::Underscore);
+        assert_eq!(first_error("2147483648"), ErrorKind::IntegerRange);
+    }
+
+    #[test]
+    fn comments() {
+        let s = "a // line\n/* block /* nested */ */ b /**/";
+        assert_tokens_match(s, [Ident(Id(0)), Ident(Id(1))]);
+        let mut lexer = Lexer::new(s);
+        while let Ok(Some(_)) = lexer.token() {}
+        assert_eq!(
+            lexer.trivia(),
+            [
+                Trivia {
+                    kind: TriviaKind::LineComment,
+                    span: Span::new(2, 9),
+                },
+                Trivia {
+                    kind: TriviaKind::BlockComment,
+                    span: Span::new(10, 34),
+                },
+                Trivia {
+                    kind: TriviaKind::BlockComment,
+                    span: Span::new(37, 41),
+                },
+            ]
+        );
+        assert_tokens_match("1 / 2 //", [Int(1), Slash, Int(2)]);
+    }
+
+    #[test]
+    fn strings() {
+        let mut lexer = Lexer::new(r#""a/b.exr" "\"\\\n\t\u{e9}" """#);
+        let (tokens, errors) = lexer.tokens();
+        assert!(errors.is_empty());
+        assert_eq!(tokens.kinds(), [Str(0), Str(1), Str(2), Eof]);
+        assert_eq!(lexer.strings(), ["a/b.exr", "\"\\\n\té", ""]);
+    }
+
+    #[test]
+    fn unicode_identifiers() {
+        assert_tokens_match(
+            "café _x 数 x̄",
+            [Ident(Id(0)), Ident(Id(1)), Ident(Id(2)), Ident(Id(3))],
+        );
+        assert_eq!(first_error("€"), ErrorKind::TokenStart('€'));
+        assert_eq!(first_error("#"), ErrorKind::TokenStart('#'));
+    }
+
+    #[test]
+    fn trivia_and_string_errors() {
+        assert_eq!(first_error("/* /* */"), ErrorKind::UnterminatedComment);
+        assert_eq!(first_error("\"abc"), ErrorKind::UnterminatedString);
+        assert_eq!(first_error("\"abc\n\""), ErrorKind::UnterminatedString);
+        assert_eq!(first_error(r#""\q""#), ErrorKind::Escape);
+        assert_eq!(first_error(r#""\u{110000}""#), ErrorKind::Escape);
+        assert_eq!(first_error(r#""\u{}""#), ErrorKind::Escape);
+
+        // Lexing resumes after the string, and the error points at the escape
+        let (tokens, errors) = Lexer::new(r#""a\qb" x"#).tokens();
+        assert_eq!(errors[0].span, Span::new(2, 4));
+        assert_eq!(tokens.kinds(), [Invalid, Ident(Id(0)), Eof]);
+
+        // Unterminated comments are dropped rather than becoming tokens
+        let (tokens, errors) = Lexer::new("x /* y").tokens();
+        assert_eq!(errors[0].span, Span::new(2, 6));
+        assert_eq!(tokens.kinds(), [Ident(Id(0)), Eof]);
+    }
+
+    const PUNCTUATION: [&str; 26] = [
+        "(", ")", "{", "}", ",", ":", ".", ";", "=", "+", "-", "*", "/", "!", "<", "<=", ">", ">=",
+        "==", "!=", "&&", "||", "fn", "let", "if", "else",
+    ];
+
+    /// Source text that lexes to a single token on its own
+    fn token_text() -> impl Strategy<Value = String> {
+        prop_oneof![
+            select(&PUNCTUATION[..]).prop_map(str::to_string),
+            "[a-zA-Z_é][a-zA-Z0-9_é]{0,6}",
+            "[0-9](_?[0-9]){0,4}",
+            "[0-9]{1,3}\\.[0-9]{0,3}([eE][+-]?[0-9]{1,2})?",
+            "\"([a-z €]|\\\\[nrt0\\\\\"'])*\"",
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn round_trip(texts in vec(token_text(), 0..16)) {
+            let s = texts.join(" ");
+            let (tokens, errors) = Lexer::new(&s).tokens();
+            prop_assert_eq!(errors, []);
+            let lexed: Vec<_> = tokens
+                .iter()
+                .take(tokens.len() - 1)
+                .map(|token| &s[token.span().range()])
+                .collect();
+            prop_assert_eq!(lexed, texts);
+        }
+
+        #[test]
+        fn spans_in_order(s in any::<String>()) {
+            let (tokens, errors) = Lexer::new(&s).tokens();
+            let spans = tokens.iter().map(|token| token.span());
+            for span in spans.chain(errors.iter().map(|error| error.span)) {
+                prop_assert!(span.start <= span.end && span.end as usize <= s.len());
+                prop_assert!(s.is_char_boundary(span.start as usize));
+                prop_assert!(s.is_char_boundary(span.end as usize));
+            }
+            prop_assert!(tokens.starts().windows(2).all(|pair| pair[0] <= pair[1]));
+        }
     }
 }
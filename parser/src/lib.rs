@@ -1,26 +1,133 @@
-use lexer::{Lexer, Token};
+use lexer::{Lexer, Span};
+use parser::Parser;
 
-mod lexer;
-mod parser;
+pub mod ast;
+pub mod cst;
+pub mod diagnostic;
+pub mod format;
+pub mod incremental;
+pub mod interner;
+pub mod lexer;
+pub mod line_index;
+pub mod parser;
+pub mod resolve;
+pub mod types;
 
-pub fn parse(s: &str) -> Result<(), Error> {
-    let tokens = {
-        let mut tokens = vec![];
-        let mut lexer = Lexer::new(s);
-        while let Some(token) = lexer.token()? {
-            tokens.push(token);
+pub use ast::{Ast, Node, NodeKind};
+pub use diagnostic::Diagnostic;
+pub use format::{format, FormatOptions};
+pub use interner::Interner;
+pub use line_index::LineIndex;
+
+/// The result of parsing a program. An AST is produced even when there are
+/// errors, with whatever could not be parsed replaced by [`NodeKind::Error`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Parse {
+    pub ast: Ast,
+    /// Every error in the source, ordered by the stage that found them
+    pub errors: Vec<Error>,
+    /// Problems that do not prevent the program from being used
+    pub warnings: Vec<Error>,
+    /// Line positions of the source for reporting errors
+    pub lines: LineIndex,
+}
+
+impl Parse {
+    pub fn into_result(self) -> Result<Ast, Vec<Error>> {
+        if self.errors.is_empty() {
+            Ok(self.ast)
+        } else {
+            Err(self.errors)
         }
-        tokens.push(Token {
-            start: s.len().try_into().unwrap(),
-            kind: lexer::TokenKind::Eof,
-        });
-        tokens
-    };
-    Ok(())
+    }
+}
+
+pub fn parse(s: &str) -> Parse {
+    parse_with(s, Interner::new())
+}
+
+/// Parses a program, adding its identifiers to an existing interner. The
+/// interner of the resulting AST can be passed on to parse further sources
+/// whose identifiers should have the same ids.
+pub fn parse_with(s: &str, interner: Interner) -> Parse {
+    let mut lexer = Lexer::with_interner(s, interner);
+    let (tokens, lexer_errors) = lexer.tokens();
+    let strings = lexer.strings().to_vec();
+    let trivia = lexer.trivia().to_vec();
+    let mut errors: Vec<Error> = lexer_errors.into_iter().map(Error::from).collect();
+    let (mut ast, parser_errors) = Parser::new(tokens).parse(lexer.into_interner());
+    ast.strings = strings;
+    ast.trivia = trivia;
+    errors.extend(parser_errors.into_iter().map(Error::from));
+    errors.extend(resolve::resolve(&mut ast).into_iter().map(Error::from));
+    errors.extend(types::check(&mut ast).into_iter().map(Error::from));
+    let (warnings, errors) = errors.into_iter().partition(Error::is_warning);
+    Parse {
+        ast,
+        errors,
+        warnings,
+        lines: LineIndex::new(s),
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
 pub enum Error {
     #[error("{0}")]
     Lexer(#[from] lexer::Error),
+    #[error("{0}")]
+    Parser(#[from] parser::Error),
+    #[error("{0}")]
+    Resolve(#[from] resolve::Error),
+    #[error("{0}")]
+    Type(#[from] types::Error),
+}
+
+impl Error {
+    pub fn span(&self) -> Span {
+        match self {
+            Error::Lexer(e) => e.span,
+            Error::Parser(e) => e.span,
+            Error::Resolve(e) => e.span,
+            Error::Type(e) => e.span,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::Lexer(e) => e.kind.code(),
+            Error::Parser(e) => e.kind.code(),
+            Error::Resolve(e) => e.kind.code(),
+            Error::Type(e) => e.kind.code(),
+        }
+    }
+
+    pub fn is_warning(&self) -> bool {
+        match self {
+            Error::Lexer(_) | Error::Parser(_) | Error::Type(_) => false,
+            Error::Resolve(e) => e.kind.is_warning(),
+        }
+    }
+
+    pub fn help(&self) -> Option<&'static str> {
+        match self {
+            Error::Lexer(e) => e.kind.help(),
+            Error::Parser(e) => e.kind.help(),
+            Error::Resolve(e) => e.kind.help(),
+            Error::Type(e) => e.kind.help(),
+        }
+    }
+
+    /// Gets the error message without location information
+    pub fn message(&self) -> String {
+        match self {
+            Error::Lexer(e) => e.kind.to_string(),
+            Error::Parser(e) => e.kind.to_string(),
+            Error::Resolve(e) => e.kind.to_string(),
+            Error::Type(e) => e.kind.to_string(),
+        }
+    }
+
+    pub fn diagnostic(&self) -> Diagnostic {
+        self.into()
+    }
 }
@@ -0,0 +1,197 @@
+use crate::lexer::Span;
+use std::collections::HashMap;
+
+/// A zero-based line and column with the column measured in UTF-8 bytes
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LineCol {
+    pub line: u32,
+    pub column: u32,
+}
+
+/// A zero-based line and column with the column measured in UTF-16 code
+/// units, as used by editor protocols such as LSP
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LineColUtf16 {
+    pub line: u32,
+    pub column: u32,
+}
+
+/// A non-ASCII character, which has a different width in UTF-8 and UTF-16
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct WideChar {
+    /// Byte offset from the start of the line
+    column: u32,
+    len_utf8: u32,
+    len_utf16: u32,
+}
+
+impl WideChar {
+    /// How many more bytes the character takes in UTF-8 than code units in
+    /// UTF-16
+    fn excess(&self) -> u32 {
+        self.len_utf8 - self.len_utf16
+    }
+}
+
+/// Maps between byte offsets and line/column positions in a source. Built once
+/// per source so that lookups are a binary search rather than a rescan.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineIndex {
+    /// The byte offset of the start of each line
+    line_starts: Vec<u32>,
+    /// The non-ASCII characters of each line that has any
+    wide_chars: HashMap<u32, Vec<WideChar>>,
+    len: u32,
+}
+
+impl LineIndex {
+    pub fn new(s: &str) -> Self {
+        let mut line_starts = vec![0];
+        let mut wide_chars: HashMap<u32, Vec<WideChar>> = HashMap::new();
+        for (i, c) in s.char_indices() {
+            let i = i as u32;
+            if c == '\n' {
+                line_starts.push(i + 1);
+            } else if !c.is_ascii() {
+                let line = line_starts.len() as u32 - 1;
+                wide_chars.entry(line).or_default().push(WideChar {
+                    column: i - line_starts[line as usize],
+                    len_utf8: c.len_utf8() as u32,
+                    len_utf16: c.len_utf16() as u32,
+                });
+            }
+        }
+        Self {
+            line_starts,
+            wide_chars,
+            len: s.len() as u32,
+        }
+    }
+
+    pub fn line_count(&self) -> u32 {
+        self.line_starts.len() as u32
+    }
+
+    /// Gets the span of the given line, excluding its line break
+    pub fn line_span(&self, line: u32) -> Option<Span> {
+        let start = *self.line_starts.get(line as usize)?;
+        let end = self
+            .line_starts
+            .get(line as usize + 1)
+            .map_or(self.len, |next| next - 1);
+        Some(Span::new(start, end))
+    }
+
+    /// Gets the line and column of a byte offset. Offsets past the end of the
+    /// source are clamped to the end.
+    pub fn line_col(&self, offset: u32) -> LineCol {
+        let offset = offset.min(self.len);
+        let line = self.line_starts.partition_point(|&start| start <= offset) - 1;
+        LineCol {
+            line: line as u32,
+            column: offset - self.line_starts[line],
+        }
+    }
+
+    /// Gets the byte offset of a line and column, if it is within the source
+    pub fn offset(&self, line_col: LineCol) -> Option<u32> {
+        let span = self.line_span(line_col.line)?;
+        let offset = span.start + line_col.column;
+        (offset <= span.end).then_some(offset)
+    }
+
+    pub fn to_utf16(&self, line_col: LineCol) -> LineColUtf16 {
+        let excess: u32 = self
+            .wide_chars(line_col.line)
+            .iter()
+            .take_while(|c| c.column < line_col.column)
+            .map(WideChar::excess)
+            .sum();
+        LineColUtf16 {
+            line: line_col.line,
+            column: line_col.column - excess,
+        }
+    }
+
+    pub fn to_utf8(&self, line_col: LineColUtf16) -> LineCol {
+        let mut column = line_col.column;
+        for c in self.wide_chars(line_col.line) {
+            if c.column < column {
+                column += c.excess();
+            } else {
+                break;
+            }
+        }
+        LineCol {
+            line: line_col.line,
+            column,
+        }
+    }
+
+    pub fn line_col_utf16(&self, offset: u32) -> LineColUtf16 {
+        self.to_utf16(self.line_col(offset))
+    }
+
+    pub fn offset_utf16(&self, line_col: LineColUtf16) -> Option<u32> {
+        self.offset(self.to_utf8(line_col))
+    }
+
+    fn wide_chars(&self, line: u32) -> &[WideChar] {
+        self.wide_chars.get(&line).map_or(&[], Vec::as_slice)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lc(line: u32, column: u32) -> LineCol {
+        LineCol { line, column }
+    }
+
+    fn lc16(line: u32, column: u32) -> LineColUtf16 {
+        LineColUtf16 { line, column }
+    }
+
+    #[test]
+    fn lines() {
+        let index = LineIndex::new("ab\n\ncd\n");
+        assert_eq!(index.line_count(), 4);
+        assert_eq!(index.line_col(0), lc(0, 0));
+        assert_eq!(index.line_col(2), lc(0, 2));
+        assert_eq!(index.line_col(3), lc(1, 0));
+        assert_eq!(index.line_col(5), lc(2, 1));
+        assert_eq!(index.line_col(7), lc(3, 0));
+        assert_eq!(index.line_col(100), lc(3, 0));
+        assert_eq!(index.line_span(2), Some(Span::new(4, 6)));
+        assert_eq!(index.line_span(3), Some(Span::new(7, 7)));
+        assert_eq!(index.line_span(4), None);
+    }
+
+    #[test]
+    fn round_trip() {
+        let s = "a + b\n  éx * 𝔸\nc";
+        let index = LineIndex::new(s);
+        for (offset, _) in s.char_indices() {
+            let offset = offset as u32;
+            assert_eq!(index.offset(index.line_col(offset)), Some(offset));
+            assert_eq!(
+                index.offset_utf16(index.line_col_utf16(offset)),
+                Some(offset)
+            );
+        }
+        assert_eq!(index.offset(lc(0, 6)), None);
+        assert_eq!(index.offset(lc(5, 0)), None);
+    }
+
+    #[test]
+    fn utf16() {
+        // é is two UTF-8 bytes and one UTF-16 unit, 𝔸 is four and two
+        let index = LineIndex::new("x\n  éx * 𝔸 y");
+        assert_eq!(index.line_col(6), lc(1, 4));
+        assert_eq!(index.line_col_utf16(6), lc16(1, 3));
+        assert_eq!(index.line_col_utf16(14), lc16(1, 9));
+        assert_eq!(index.to_utf8(lc16(1, 9)), lc(1, 12));
+        assert_eq!(index.to_utf8(lc16(1, 2)), lc(1, 2));
+    }
+}
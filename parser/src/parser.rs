@@ -1,125 +1,1132 @@
-use crate::lexer::{Token, TokenKind};
-use std::{
-    iter::{Enumerate, Peekable},
-    slice::Iter,
+use crate::{
+    ast::{Ast, Node, NodeKind, Nodes},
+    interner::Interner,
+    lexer::{Span, TokenKind, TokenList},
+    types::Type,
 };
+use std::collections::HashMap;
 
-pub enum NodeKind {
-    Ident,
-    Float,
-    Sum,
-    Difference,
-    Product,
-    Negation,
-    Not,
-    Quotient,
-}
-
-// TODO: Better to separately store leaves, unaries, and binaries?
-// TODO: Is node kind needed or can we reuse the token?
-// TODO: SOA
-pub struct Node {
-    kind: NodeKind,
-    token_index: u32,
-    children: (u32, u32),
-}
+/// How deeply expressions may nest. Parsing and the passes after it recurse
+/// into nested expressions, so this keeps them from overflowing the stack.
+const MAX_DEPTH: u32 = 128;
 
-pub struct Parser<'a> {
-    tokens: Peekable<Enumerate<Iter<'a, Token>>>,
-    nodes: Vec<Node>,
+pub struct Parser {
+    tokens: TokenList,
+    index: usize,
+    /// The number of expressions being parsed that contain the current one
+    depth: u32,
+    nodes: Nodes,
+    lists: Vec<u32>,
+    functions: Vec<u32>,
+    interner: Interner,
+    errors: Vec<Error>,
 }
 
-impl<'a> Parser<'a> {
-    pub fn new(tokens: &'a [Token]) -> Self {
+impl Parser {
+    /// The tokens must end with an `Eof` token
+    pub fn new(tokens: TokenList) -> Self {
+        debug_assert_eq!(tokens.last().map(|token| token.kind), Some(TokenKind::Eof));
         Self {
-            nodes: vec![],
-            tokens: tokens.into_iter().enumerate().peekable(),
+            tokens,
+            index: 0,
+            depth: 0,
+            nodes: Nodes::default(),
+            lists: vec![],
+            functions: vec![],
+            interner: Interner::new(),
+            errors: vec![],
+        }
+    }
+
+    /// function* expression EOF
+    ///
+    /// The interner is the one the lexer added the identifiers of the tokens
+    /// to. Parsing continues past errors so that as many as possible are
+    /// reported. Whatever could not be parsed is replaced with
+    /// [`NodeKind::Error`].
+    pub fn parse(mut self, interner: Interner) -> (Ast, Vec<Error>) {
+        self.interner = interner;
+        let mut root = None;
+        loop {
+            match self.peek_token() {
+                TokenKind::Eof => break,
+                TokenKind::Fn => {
+                    if root.is_some() {
+                        let error = self.error(ErrorKind::Eof);
+                        self.errors.push(error);
+                    }
+                    match self.function() {
+                        Ok(function) => self.functions.push(function),
+                        Err(error) => {
+                            self.errors.push(error);
+                            self.synchronize();
+                        }
+                    }
+                }
+                _ if root.is_some() => {
+                    let error = self.error(ErrorKind::Eof);
+                    self.errors.push(error);
+                    self.synchronize();
+                }
+                _ => {
+                    root = Some(match self.expression() {
+                        Ok(root) => root,
+                        Err(error) => self.recover(error, &[]),
+                    })
+                }
+            }
         }
+
+        let root = root.unwrap_or_else(|| {
+            let error = self.error(ErrorKind::Primary);
+            if self.errors.is_empty() {
+                self.errors.push(error);
+            }
+            self.push_leaf(NodeKind::Error, self.index as u32)
+        });
+
+        let ast = Ast {
+            tokens: self.tokens,
+            nodes: self.nodes,
+            lists: self.lists,
+            interner: self.interner,
+            strings: vec![],
+            trivia: vec![],
+            functions: self.functions,
+            symbols: HashMap::new(),
+            bindings: HashMap::new(),
+            types: vec![],
+            root,
+        };
+        (ast, self.errors)
     }
 
+    /// "fn" ident "(" (ident ("," ident)*)? ")" expression ";"
+    fn function(&mut self) -> Result<u32, Error> {
+        let start = self.take_token_index();
+        let name = self.expect_ident()?;
+        self.expect(TokenKind::ParenLeft, ErrorKind::ParenLeft)?;
+        let parameters = self.list(|parser| {
+            let token_index = parser.expect_ident()?;
+            parser.annotation()?;
+            Ok(parser.push_leaf(NodeKind::Parameter, token_index))
+        });
+        let body = match self.expression() {
+            Ok(body) => body,
+            Err(error) => self.recover(error, &[]),
+        };
+        if let Err(error) = self.expect(TokenKind::Semicolon, ErrorKind::Semicolon) {
+            self.errors.push(error);
+            self.synchronize();
+        }
+        Ok(self.push(NodeKind::Function, name, (parameters, body), start))
+    }
+
+    fn expression(&mut self) -> Result<u32, Error> {
+        self.binary(0)
+    }
+
+    /// unary (binary_operator unary)*
+    ///
+    /// Precedence climbing: only operators that bind at least as tightly as
+    /// `min_precedence` are consumed at this level.
+    fn binary(&mut self, min_precedence: u8) -> Result<u32, Error> {
+        let mut lhs = self.unary()?;
+        while let Some((kind, precedence)) = binary_operator(self.peek_token()) {
+            if precedence < min_precedence {
+                break;
+            }
+            let token_index = self.take_token_index();
+            // All binary operators are left associative
+            let rhs = self.binary(precedence + 1)?;
+            let start = self.nodes.span(lhs).start;
+            lhs = self.push_spanning(kind, token_index, (lhs, rhs), start);
+        }
+        Ok(lhs)
+    }
+
+    /// ("-" | "!") unary | postfix
+    ///
+    /// Every way of nesting expressions except swizzles passes through here,
+    /// so this is where the depth is limited.
     fn unary(&mut self) -> Result<u32, Error> {
+        self.nested(Self::unary_inner)
+    }
+
+    fn unary_inner(&mut self) -> Result<u32, Error> {
+        let kind = match self.peek_token() {
+            TokenKind::Minus => NodeKind::Negation,
+            TokenKind::Exclamation => NodeKind::Not,
+            _ => return self.postfix(),
+        };
+        let token_index = self.take_token_index();
+        let child = self.unary()?;
+        Ok(self.push(kind, token_index, (child, 0), token_index))
+    }
+
+    /// primary ("." ident)*
+    ///
+    /// Each swizzle nests the expression before it one level deeper.
+    fn postfix(&mut self) -> Result<u32, Error> {
+        let mut node = self.primary()?;
+        let mut depth = self.depth;
+        while self.peek_token() == TokenKind::Dot {
+            if depth == MAX_DEPTH {
+                return Err(self.error(ErrorKind::Depth));
+            }
+            depth += 1;
+            self.take_token_index();
+            let components = self.expect_components()?;
+            let start = self.nodes.span(node).start;
+            node = self.push_spanning(NodeKind::Swizzle, components, (node, 0), start);
+        }
+        Ok(node)
+    }
+
+    /// int | float | string | ident | call | constructor | block | if
+    /// | "(" expression ")"
+    fn primary(&mut self) -> Result<u32, Error> {
+        let kind = match self.peek_token() {
+            TokenKind::Int(_) => NodeKind::Int,
+            TokenKind::Float(_) => NodeKind::Float,
+            TokenKind::Str(_) => NodeKind::String,
+            TokenKind::Ident(_) => {
+                let token_index = self.take_token_index();
+                if self.peek_token() != TokenKind::ParenLeft {
+                    return Ok(self.push_leaf(NodeKind::Ident, token_index));
+                }
+                self.take_token_index();
+                let arguments = self.list(Self::expression);
+                let kind = if self.is_constructor(token_index) {
+                    NodeKind::Construct
+                } else {
+                    NodeKind::Call
+                };
+                return Ok(self.push(kind, token_index, (arguments, 0), token_index));
+            }
+            TokenKind::ParenLeft => {
+                let start = self.take_token_index();
+                let inner = match self.expression() {
+                    Ok(inner) => inner,
+                    Err(error) => self.recover(error, &[TokenKind::ParenRight]),
+                };
+                if let Err(error) = self.expect(TokenKind::ParenRight, ErrorKind::ParenRight) {
+                    self.errors.push(error);
+                    self.skip_until(&[TokenKind::ParenRight]);
+                    if self.peek_token() == TokenKind::ParenRight {
+                        self.take_token_index();
+                    }
+                }
+                self.nodes.set_span(inner, self.span_from(start));
+                return Ok(inner);
+            }
+            TokenKind::BraceLeft => return Ok(self.block()),
+            TokenKind::If => return self.conditional(),
+            TokenKind::Invalid => NodeKind::Error,
+            _ => return Err(self.error(ErrorKind::Primary)),
+        };
+        let token_index = self.take_token_index();
+        Ok(self.push_leaf(kind, token_index))
+    }
+
+    /// "{" let* expression "}"
+    fn block(&mut self) -> u32 {
+        let start = self.take_token_index();
+        let mut lets = vec![];
+        while self.peek_token() == TokenKind::Let {
+            match self.let_statement() {
+                Ok(statement) => lets.push(statement),
+                Err(error) => {
+                    // Unlike at the top level, the closing brace belongs to
+                    // the block, so only a semicolon is taken
+                    self.errors.push(error);
+                    self.skip_until(&[TokenKind::Semicolon, TokenKind::BraceRight]);
+                    if self.peek_token() == TokenKind::Semicolon {
+                        self.take_token_index();
+                    }
+                }
+            }
+        }
+        let value = match self.expression() {
+            Ok(value) => value,
+            Err(error) => self.recover(error, &[TokenKind::BraceRight]),
+        };
+        if let Err(error) = self.expect(TokenKind::BraceRight, ErrorKind::BraceRight) {
+            self.errors.push(error);
+            self.skip_until(&[TokenKind::BraceRight]);
+            if self.peek_token() == TokenKind::BraceRight {
+                self.take_token_index();
+            }
+        }
+        let lets = self.push_list(lets);
+        self.push(NodeKind::Block, start, (lets, value), start)
+    }
+
+    /// "if" expression block "else" (block | if)
+    fn conditional(&mut self) -> Result<u32, Error> {
+        let start = self.take_token_index();
+        let condition = match self.expression() {
+            Ok(condition) => condition,
+            Err(error) => self.recover(error, &[TokenKind::BraceLeft]),
+        };
+        let then = self.branch()?;
+        self.expect(TokenKind::Else, ErrorKind::Else)?;
+        let otherwise = match self.peek_token() {
+            // A chain of `else if` nests without going through `unary`
+            TokenKind::If => self.nested(Self::conditional)?,
+            _ => self.branch()?,
+        };
+        let branches = self.push_list(vec![then, otherwise]);
+        Ok(self.push(NodeKind::If, start, (condition, branches), start))
+    }
+
+    fn branch(&mut self) -> Result<u32, Error> {
         match self.peek_token() {
-            TokenKind::Minus => {
-                let lhs = self.unary()?;
-                Ok(self.accept_unary(NodeKind::Difference, lhs))
+            TokenKind::BraceLeft => Ok(self.block()),
+            _ => Err(self.error(ErrorKind::BraceLeft)),
+        }
+    }
+
+    /// (":" ident)?
+    ///
+    /// The type name is found again from the token after the annotated name,
+    /// so it doesn't need a node.
+    fn annotation(&mut self) -> Result<(), Error> {
+        if self.peek_token() == TokenKind::Colon {
+            self.take_token_index();
+            match self.peek_token() {
+                TokenKind::Ident(_) => {
+                    self.take_token_index();
+                }
+                _ => return Err(self.error(ErrorKind::Type)),
+            }
+        }
+        Ok(())
+    }
+
+    /// "let" ident ("." ident | ":" ident)? "=" expression ";"
+    fn let_statement(&mut self) -> Result<u32, Error> {
+        let start = self.take_token_index();
+        let name = self.expect_ident()?;
+        if self.peek_token() == TokenKind::Dot {
+            // Like annotations, the write mask is found again from the tokens
+            self.take_token_index();
+            self.expect_components()?;
+        } else {
+            self.annotation()?;
+        }
+        self.expect(TokenKind::Equals, ErrorKind::Equals)?;
+        let value = match self.expression() {
+            Ok(value) => value,
+            Err(error) => self.recover(error, &[]),
+        };
+        // A missing semicolon doesn't obscure what follows, so carry on
+        if let Err(error) = self.expect(TokenKind::Semicolon, ErrorKind::Semicolon) {
+            self.errors.push(error);
+        }
+        Ok(self.push(NodeKind::Let, name, (value, 0), start))
+    }
+
+    /// (item ("," item)*)? ")"
+    ///
+    /// The opening parenthesis has already been taken. Returns the index of the
+    /// list in `lists`. Items that fail to parse are recovered from at the next
+    /// comma or closing parenthesis.
+    fn list(&mut self, mut item: impl FnMut(&mut Self) -> Result<u32, Error>) -> u32 {
+        const STOP: &[TokenKind] = &[TokenKind::Comma, TokenKind::ParenRight];
+        let mut items = vec![];
+        if self.peek_token() != TokenKind::ParenRight {
+            loop {
+                let i = match item(self) {
+                    Ok(i) => i,
+                    Err(error) => self.recover(error, STOP),
+                };
+                items.push(i);
+                if !STOP.contains(&self.peek_token()) {
+                    let error = self.error(ErrorKind::ListEnd);
+                    self.errors.push(error);
+                    self.skip_until(STOP);
+                }
+                match self.peek_token() {
+                    TokenKind::Comma => {
+                        self.take_token_index();
+                    }
+                    _ => break,
+                }
             }
-            TokenKind::Exclamation => {
-                let child = self.unary()?;
-                Ok(self.accept_unary(NodeKind::Not, child))
+        }
+        if self.peek_token() == TokenKind::ParenRight {
+            self.take_token_index();
+        }
+        self.push_list(items)
+    }
+
+    /// Adds a list to `lists` and returns its index
+    fn push_list(&mut self, items: Vec<u32>) -> u32 {
+        let index = self.lists.len() as u32;
+        self.lists.push(items.len() as u32);
+        self.lists.extend(items);
+        index
+    }
+
+    /// Records the error and skips ahead to one of the given tokens or the next
+    /// statement boundary, returning an error node in place of what was
+    /// skipped.
+    fn recover(&mut self, error: Error, stop: &[TokenKind]) -> u32 {
+        let token_index = self.index as u32;
+        let start = self.tokens.span(self.index as u32).start;
+        self.errors.push(error);
+        self.skip_until(stop);
+        self.push_spanning(NodeKind::Error, token_index, (0, 0), start)
+    }
+
+    /// Parses something one level deeper, failing if that is too deep
+    fn nested(&mut self, f: impl FnOnce(&mut Self) -> Result<u32, Error>) -> Result<u32, Error> {
+        if self.depth == MAX_DEPTH {
+            return Err(self.error(ErrorKind::Depth));
+        }
+        self.depth += 1;
+        let result = f(self);
+        self.depth -= 1;
+        result
+    }
+
+    /// Skips tokens until reaching one of the given tokens, a semicolon, or an
+    /// unmatched closing bracket outside of any brackets. Also stops at `fn` or
+    /// the end of input.
+    fn skip_until(&mut self, stop: &[TokenKind]) {
+        let mut depth = 0u32;
+        loop {
+            let token = self.peek_token();
+            match token {
+                TokenKind::Eof | TokenKind::Fn => break,
+                _ if depth > 0 => {}
+                TokenKind::Semicolon | TokenKind::ParenRight | TokenKind::BraceRight => break,
+                _ if stop.contains(&token) => break,
+                _ => {}
             }
-            // TODO: Add context to errors
-            _ => self.primary(),
+            match token {
+                TokenKind::ParenLeft | TokenKind::BraceLeft => depth += 1,
+                TokenKind::ParenRight | TokenKind::BraceRight => depth -= 1,
+                _ => {}
+            }
+            self.take_token_index();
         }
     }
 
-    /// float | ident | "(" expression ")"
-    fn primary(&mut self) -> Result<u32, Error> {
-        match self.take_token() {
-            TokenKind::Float => Ok(self.accept_leaf(NodeKind::Float)),
-            TokenKind::Ident => Ok(self.accept_leaf(NodeKind::Ident)),
-            _ => Err(self.error(ErrorKind::Primary)),
+    /// Skips past the end of the current statement, or past a stray closing
+    /// bracket
+    fn synchronize(&mut self) {
+        self.skip_until(&[]);
+        if let TokenKind::Semicolon | TokenKind::ParenRight | TokenKind::BraceRight =
+            self.peek_token()
+        {
+            self.take_token_index();
         }
     }
 
-    fn take_token(&mut self) -> TokenKind {
-        self.tokens
-            .next()
-            .map(|(_, token)| token.kind)
-            .unwrap_or(TokenKind::Eof)
+    fn expect(&mut self, token: TokenKind, error: ErrorKind) -> Result<u32, Error> {
+        if self.peek_token() == token {
+            Ok(self.take_token_index())
+        } else {
+            Err(self.error(error))
+        }
     }
 
-    fn peek_token(&mut self) -> TokenKind {
-        self.tokens
-            .peek()
-            .map(|(_, token)| token.kind)
-            .unwrap_or(TokenKind::Eof)
+    fn expect_components(&mut self) -> Result<u32, Error> {
+        match self.peek_token() {
+            TokenKind::Ident(_) => Ok(self.take_token_index()),
+            _ => Err(self.error(ErrorKind::Components)),
+        }
+    }
+
+    /// Whether the identifier token names a vector type
+    fn is_constructor(&self, token_index: u32) -> bool {
+        match self.tokens.kind(token_index) {
+            TokenKind::Ident(id) => {
+                Type::from_name(self.interner.resolve(id)).is_some_and(Type::is_vector)
+            }
+            _ => false,
+        }
+    }
+
+    fn expect_ident(&mut self) -> Result<u32, Error> {
+        match self.peek_token() {
+            TokenKind::Ident(_) => Ok(self.take_token_index()),
+            _ => Err(self.error(ErrorKind::Ident)),
+        }
+    }
+
+    fn peek_token(&self) -> TokenKind {
+        self.tokens.kind(self.index as u32)
     }
 
-    fn token_index(&mut self) -> u32 {
-        let &(i, _) = self.tokens.peek().unwrap();
-        i.try_into().unwrap()
+    /// Advances past the current token and returns its index. Never advances
+    /// past the final `Eof`.
+    fn take_token_index(&mut self) -> u32 {
+        let i = self.index;
+        if i + 1 < self.tokens.len() {
+            self.index += 1;
+        }
+        i as u32
+    }
+
+    /// Gets the span from the start of the given token to the end of the last
+    /// token taken
+    fn span_from(&self, start_token: u32) -> Span {
+        let start = self.tokens.span(start_token).start;
+        self.span_from_offset(start)
     }
 
-    fn push(&mut self, node: Node) -> u32 {
-        let i = self.nodes.len();
-        self.nodes.push(node);
-        i.try_into().unwrap()
+    fn span_from_offset(&self, start: u32) -> Span {
+        let end = self.tokens.span(self.index.saturating_sub(1) as u32).end;
+        Span::new(start, end.max(start))
     }
 
-    fn accept_binary(&mut self, kind: NodeKind, lhs: u32, rhs: u32) -> u32 {
-        let token_index = self.token_index();
-        self.take_token();
-        self.push(Node {
+    /// Adds a node spanning from the start of the given token to the end of
+    /// the last token taken
+    fn push(
+        &mut self,
+        kind: NodeKind,
+        token_index: u32,
+        children: (u32, u32),
+        start_token: u32,
+    ) -> u32 {
+        let start = self.tokens.span(start_token).start;
+        self.push_spanning(kind, token_index, children, start)
+    }
+
+    fn push_spanning(
+        &mut self,
+        kind: NodeKind,
+        token_index: u32,
+        children: (u32, u32),
+        start: u32,
+    ) -> u32 {
+        self.nodes.push(Node {
             kind,
             token_index,
-            children: (lhs, rhs),
+            children,
+            span: self.span_from_offset(start),
         })
     }
 
-    fn accept_unary(&mut self, kind: NodeKind, child: u32) -> u32 {
-        self.accept_binary(kind, child, 0)
+    fn push_leaf(&mut self, kind: NodeKind, token_index: u32) -> u32 {
+        self.push(kind, token_index, (0, 0), token_index)
     }
 
-    fn accept_leaf(&mut self, kind: NodeKind) -> u32 {
-        self.accept_unary(kind, 0)
-    }
-
-    fn error(&mut self, kind: ErrorKind) -> Error {
+    fn error(&self, kind: ErrorKind) -> Error {
         Error {
-            token_index: self.token_index(),
+            span: self.tokens.span(self.index as u32),
             kind,
         }
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+/// Gets the node kind and precedence of a binary operator. Higher precedences
+/// bind more tightly.
+fn binary_operator(token: TokenKind) -> Option<(NodeKind, u8)> {
+    let kind = match token {
+        TokenKind::PipePipe => NodeKind::Or,
+        TokenKind::AmpersandAmpersand => NodeKind::And,
+        TokenKind::Less => NodeKind::Less,
+        TokenKind::LessEquals => NodeKind::LessEqual,
+        TokenKind::Greater => NodeKind::Greater,
+        TokenKind::GreaterEquals => NodeKind::GreaterEqual,
+        TokenKind::EqualsEquals => NodeKind::Equal,
+        TokenKind::ExclamationEquals => NodeKind::NotEqual,
+        TokenKind::Plus => NodeKind::Sum,
+        TokenKind::Minus => NodeKind::Difference,
+        TokenKind::Asterisk => NodeKind::Product,
+        TokenKind::Slash => NodeKind::Quotient,
+        _ => return None,
+    };
+    Some((kind, kind.precedence()))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
 pub enum ErrorKind {
+    #[error("Expected a number, identifier, or parenthesized expression")]
     Primary,
-    Unary,
+    #[error("Expected a closing parenthesis")]
+    ParenRight,
+    #[error("Expected the end of the expression")]
+    Eof,
+    #[error("Expected an identifier")]
+    Ident,
+    #[error("Expected an opening parenthesis")]
+    ParenLeft,
+    #[error("Expected a comma or a closing parenthesis")]
+    ListEnd,
+    #[error("Expected a semicolon")]
+    Semicolon,
+    #[error("Expected `=` after the variable name")]
+    Equals,
+    #[error("Expected a closing brace")]
+    BraceRight,
+    #[error("Expected an opening brace")]
+    BraceLeft,
+    #[error("Expected `else` after the body of `if`")]
+    Else,
+    #[error("Expected a type name")]
+    Type,
+    #[error("Expected component names after `.`")]
+    Components,
+    #[error("Expressions are nested too deeply")]
+    Depth,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+impl ErrorKind {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ErrorKind::Primary => "E0101",
+            ErrorKind::ParenRight => "E0102",
+            ErrorKind::Eof => "E0103",
+            ErrorKind::Ident => "E0104",
+            ErrorKind::ParenLeft => "E0105",
+            ErrorKind::ListEnd => "E0106",
+            ErrorKind::Semicolon => "E0107",
+            ErrorKind::Equals => "E0108",
+            ErrorKind::BraceRight => "E0109",
+            ErrorKind::BraceLeft => "E0110",
+            ErrorKind::Else => "E0111",
+            ErrorKind::Type => "E0112",
+            ErrorKind::Components => "E0113",
+            ErrorKind::Depth => "E0114",
+        }
+    }
+
+    pub fn help(&self) -> Option<&'static str> {
+        match self {
+            ErrorKind::Primary | ErrorKind::Ident => None,
+            ErrorKind::ParenRight => Some("Add a `)` to close the parenthesis"),
+            ErrorKind::Eof => Some("Join the expressions with an operator"),
+            ErrorKind::ParenLeft => Some("Declare parameters in parentheses, as in `fn f(a, b)`"),
+            ErrorKind::ListEnd => Some("Separate items with commas"),
+            ErrorKind::Semicolon => Some("End function declarations and let statements with `;`"),
+            ErrorKind::Equals => Some("Assign a value to the variable, as in `let x = 1;`"),
+            ErrorKind::BraceRight => Some("Add a `}` to close the block"),
+            ErrorKind::BraceLeft => {
+                Some("Wrap the branch in braces, as in `if c { a } else { b }`")
+            }
+            ErrorKind::Else => Some("Conditionals need a value for both branches"),
+            ErrorKind::Type => Some("Name a type after `:`, as in `x: vec3`"),
+            ErrorKind::Components => Some("Name the components to use, as in `c.rgb`"),
+            ErrorKind::Depth => Some("Split the expression up with `let` statements"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("Parser error at {span}:\n{kind}")]
 pub struct Error {
-    pub token_index: u32,
+    pub span: Span,
     pub kind: ErrorKind,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{format, parse, FormatOptions};
+    use proptest::{collection::vec, prelude::*, sample::select};
+
+    /// Prints the AST as an S-expression for easy comparison
+    fn sexp(ast: &Ast, node: Node) -> String {
+        let child = |i: u32| sexp(ast, ast.node(i));
+        let list = |i: u32| {
+            ast.list(i)
+                .iter()
+                .map(|&i| format!(" {}", child(i)))
+                .collect::<String>()
+        };
+        let name = || ast.name(ast.id(node).unwrap()).to_string();
+        let (a, b) = node.children;
+        match node.kind {
+            NodeKind::Ident | NodeKind::Parameter => name(),
+            NodeKind::Int => ast.int(node).unwrap().to_string(),
+            NodeKind::Float => ast.float(node).unwrap().to_string(),
+            NodeKind::String => format!("{:?}", ast.string(node).unwrap()),
+            NodeKind::Sum => format!("(+ {} {})", child(a), child(b)),
+            NodeKind::Difference => format!("(- {} {})", child(a), child(b)),
+            NodeKind::Product => format!("(* {} {})", child(a), child(b)),
+            NodeKind::Quotient => format!("(/ {} {})", child(a), child(b)),
+            NodeKind::Negation => format!("(- {})", child(a)),
+            NodeKind::Not => format!("(! {})", child(a)),
+            NodeKind::Less => format!("(< {} {})", child(a), child(b)),
+            NodeKind::LessEqual => format!("(<= {} {})", child(a), child(b)),
+            NodeKind::Greater => format!("(> {} {})", child(a), child(b)),
+            NodeKind::GreaterEqual => format!("(>= {} {})", child(a), child(b)),
+            NodeKind::Equal => format!("(== {} {})", child(a), child(b)),
+            NodeKind::NotEqual => format!("(!= {} {})", child(a), child(b)),
+            NodeKind::And => format!("(&& {} {})", child(a), child(b)),
+            NodeKind::Or => format!("(|| {} {})", child(a), child(b)),
+            NodeKind::If => format!("(if {}{})", child(a), list(b)),
+            NodeKind::Call | NodeKind::Construct => format!("({}{})", name(), list(a)),
+            NodeKind::Swizzle => format!("(. {} {})", child(a), name()),
+            NodeKind::Function => {
+                format!("(fn {} ({}) {})", name(), list(a).trim_start(), child(b))
+            }
+            NodeKind::Error => "<error>".to_string(),
+            NodeKind::Block => format!("{{{} {}}}", list(a), child(b)),
+            NodeKind::Let => match ast.mask(node) {
+                Some(mask) => {
+                    let TokenKind::Ident(mask) = mask.kind else {
+                        unreachable!()
+                    };
+                    format!("(let {}.{} {})", name(), ast.name(mask), child(a))
+                }
+                None => format!("(let {} {})", name(), child(a)),
+            },
+        }
+    }
+
+    fn program_sexp(ast: &Ast) -> String {
+        let mut out: String = ast
+            .functions
+            .iter()
+            .map(|&i| format!("{} ", sexp(ast, ast.node(i))))
+            .collect();
+        out.push_str(&sexp(ast, ast.root()));
+        out
+    }
+
+    fn assert_parses_to(s: &str, expected: &str) {
+        let ast = parse(s).into_result().unwrap();
+        assert_eq!(program_sexp(&ast), expected);
+    }
+
+    /// Parses the source, expecting no syntax errors but ignoring the errors
+    /// of later stages
+    fn assert_syntax(s: &str, expected: &str) {
+        let parse = parse(s);
+        assert_eq!(parser_errors(s), []);
+        assert_eq!(program_sexp(&parse.ast), expected);
+    }
+
+    fn parser_errors(s: &str) -> Vec<Error> {
+        parse(s)
+            .errors
+            .into_iter()
+            .filter_map(|e| match e {
+                crate::Error::Parser(e) => Some(e),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn parse_error(s: &str) -> ErrorKind {
+        parser_errors(s)
+            .into_iter()
+            .next()
+            .expect("Expected a parser error")
+            .kind
+    }
+
+    /// Parses the source, expecting the given errors and partial AST
+    fn assert_recovers(s: &str, expected: &str, errors: &[ErrorKind]) {
+        let parse = parse(s);
+        let actual: Vec<_> = parser_errors(s).into_iter().map(|e| e.kind).collect();
+        assert_eq!(actual, errors);
+        assert_eq!(program_sexp(&parse.ast), expected);
+    }
+
+    #[test]
+    fn leaves() {
+        assert_parses_to("x", "x");
+        assert_parses_to("2.5", "2.5");
+    }
+
+    #[test]
+    fn precedence() {
+        assert_parses_to("1 + 2 * 3", "(+ 1 (* 2 3))");
+        assert_parses_to("1 * 2 + 3", "(+ (* 1 2) 3)");
+        assert_parses_to("1 - 2 / 3 * x", "(- 1 (* (/ 2 3) x))");
+    }
+
+    #[test]
+    fn left_associative() {
+        assert_parses_to("1 - 2 - 3", "(- (- 1 2) 3)");
+        assert_parses_to("1 / 2 / 3", "(/ (/ 1 2) 3)");
+    }
+
+    #[test]
+    fn unary() {
+        assert_parses_to("-x", "(- x)");
+        assert_parses_to("!!(x < 0)", "(! (! (< x 0)))");
+        assert_parses_to("-x * 2", "(* (- x) 2)");
+        assert_parses_to("1 - -2", "(- 1 (- 2))");
+    }
+
+    #[test]
+    fn grouping() {
+        assert_parses_to("(1 + 2) * 3", "(* (+ 1 2) 3)");
+        assert_parses_to("1 - (2 - 3)", "(- 1 (- 2 3))");
+        assert_parses_to("((x))", "x");
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(parse_error(""), ErrorKind::Primary);
+        assert_eq!(parse_error("1 +"), ErrorKind::Primary);
+        assert_eq!(parse_error("(1 + 2"), ErrorKind::ParenRight);
+        assert_eq!(parse_error("1 2"), ErrorKind::Eof);
+        assert_eq!(parse_error(")"), ErrorKind::Primary);
+        assert_eq!(parse_error("f(1 2)"), ErrorKind::ListEnd);
+        assert_eq!(parse_error("fn (a) a; 1"), ErrorKind::Ident);
+        assert_eq!(parse_error("fn f a; 1"), ErrorKind::ParenLeft);
+        assert_eq!(parse_error("fn f(1) 1; 1"), ErrorKind::Ident);
+        assert_eq!(parse_error("fn f(a) a 1"), ErrorKind::Semicolon);
+    }
+
+    #[test]
+    fn spans() {
+        let ast = parse("fn f(a) -a; 2 * (x + 1) - f(3)")
+            .into_result()
+            .unwrap();
+        let span = |i: u32| ast.node(i).span.range();
+        assert_eq!(span(ast.functions[0]), 0..11);
+        assert_eq!(span(ast.root), 12..30);
+        let (product, call) = ast.root().children;
+        assert_eq!(span(product), 12..23);
+        assert_eq!(span(ast.node(product).children.1), 16..23);
+        assert_eq!(span(call), 26..30);
+    }
+
+    #[test]
+    fn error_spans() {
+        let span = |s| parser_errors(s)[0].span.range();
+        assert_eq!(span("1 +"), 3..3);
+        assert_eq!(span("(1 + 2 3"), 7..8);
+    }
+
+    #[test]
+    fn calls() {
+        assert_parses_to("fn f() 1; f()", "(fn f () 1) (f)");
+        assert_parses_to("fn f(a) a; f(x) * 2", "(fn f (a) a) (* (f x) 2)");
+        assert_parses_to(
+            "fn f(a, b) a; -f(1 + 2, f(x, y))",
+            "(fn f (a b) a) (- (f (+ 1 2) (f x y)))",
+        );
+    }
+
+    #[test]
+    fn functions() {
+        assert_parses_to(
+            "fn lerp(a, b, t) a + (b - a) * t; fn half(x) x / 2; lerp(0, 1, half(x))",
+            "(fn lerp (a b t) (+ a (* (- b a) t))) (fn half (x) (/ x 2)) (lerp 0 1 (half x))",
+        );
+    }
+
+    #[test]
+    fn recovers_in_groups() {
+        assert_recovers(
+            "(1 +) * (2 3) - (x",
+            "(- (* <error> 2) x)",
+            &[
+                ErrorKind::Primary,
+                ErrorKind::ParenRight,
+                ErrorKind::ParenRight,
+            ],
+        );
+    }
+
+    #[test]
+    fn recovers_in_lists() {
+        assert_recovers(
+            "fn f(a, 1, c) a; f(*, 2 3, x)",
+            "(fn f (a <error> c) a) (f <error> 2 x)",
+            &[ErrorKind::Ident, ErrorKind::Primary, ErrorKind::ListEnd],
+        );
+    }
+
+    #[test]
+    fn recovers_at_statements() {
+        assert_recovers(
+            "fn f(a) a +; fn (b) b; fn g(c) c 1; fn h() 2; h() 3",
+            "(fn f (a) <error>) (fn g (c) c) (fn h () 2) (h)",
+            &[
+                ErrorKind::Primary,
+                ErrorKind::Ident,
+                ErrorKind::Semicolon,
+                ErrorKind::Eof,
+            ],
+        );
+    }
+
+    #[test]
+    fn deep_nesting() {
+        let nested = |depth| format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+        assert_parses_to(&nested(100), "1");
+        assert_recovers(&nested(10_000), "<error>", &[ErrorKind::Depth]);
+        assert_recovers(
+            &format!("{}1", "-".repeat(1_000_000)),
+            "<error>",
+            &[ErrorKind::Depth],
+        );
+        let conditionals = "if x < 0 { 1 } else ".repeat(10_000) + "{ 0 }";
+        assert_eq!(parse_error(&conditionals), ErrorKind::Depth);
+        let swizzles = format!("x{}", ".x".repeat(5_000));
+        assert_eq!(parse_error(&swizzles), ErrorKind::Depth);
+    }
+
+    #[test]
+    fn missing_expression() {
+        assert_recovers("fn f() 1;", "(fn f () 1) <error>", &[ErrorKind::Primary]);
+        assert_recovers("1 + * 2", "<error>", &[ErrorKind::Primary]);
+    }
+
+    #[test]
+    fn blocks() {
+        assert_parses_to("{ x }", "{ x}");
+        assert_parses_to(
+            "{ let a = 1; let b = a * 2; b - { let a = 3; a } }",
+            "{ (let a 1) (let b (* a 2)) (- b { (let a 3) a})}",
+        );
+        assert_parses_to(
+            "fn f(x) { let y = x; y }; f(2)",
+            "(fn f (x) { (let y x) y}) (f 2)",
+        );
+    }
+
+    #[test]
+    fn recovers_in_blocks() {
+        assert_recovers(
+            "{ let = 1; let a 2; let b = ; let c = 3 c + (a }",
+            "{ (let b <error>) (let c 3) (+ c a)}",
+            &[
+                ErrorKind::Ident,
+                ErrorKind::Equals,
+                ErrorKind::Primary,
+                ErrorKind::Semicolon,
+                ErrorKind::ParenRight,
+            ],
+        );
+        assert_recovers("{ 1 + } * 2", "(* { <error>} 2)", &[ErrorKind::Primary]);
+        assert_recovers("{ 1", "{ 1}", &[ErrorKind::BraceRight]);
+        assert_recovers(
+            "{ let a = 1 }",
+            "{ (let a 1) <error>}",
+            &[ErrorKind::Semicolon, ErrorKind::Primary],
+        );
+        assert_recovers(
+            "{ let = 1 } + 2",
+            "(+ { <error>} 2)",
+            &[ErrorKind::Ident, ErrorKind::Primary],
+        );
+    }
+
+    #[test]
+    fn comparisons() {
+        assert_parses_to("a + 1 < b * 2", "(< (+ a 1) (* b 2))");
+        assert_parses_to(
+            "a < b || c >= d && e != f",
+            "(|| (< a b) (&& (>= c d) (!= e f)))",
+        );
+        assert_parses_to(
+            "a < 1 || b < 2 || c < 3",
+            "(|| (|| (< a 1) (< b 2)) (< c 3))",
+        );
+        assert_parses_to("!(a == b) && c <= d", "(&& (! (== a b)) (<= c d))");
+    }
+
+    #[test]
+    fn conditionals() {
+        assert_parses_to("if a < b { a } else { b }", "(if (< a b) { a} { b})");
+        assert_parses_to(
+            "if a < 0 { 1 } else if b < 0 { 2 } else { 3 }",
+            "(if (< a 0) { 1} (if (< b 0) { 2} { 3}))",
+        );
+        assert_parses_to(
+            "2 * if a > 0 { let b = a; b } else { 0 } + 1",
+            "(+ (* 2 (if (> a 0) { (let b a) b} { 0})) 1)",
+        );
+        assert_eq!(parse_error("if a { 1 }"), ErrorKind::Else);
+        assert_eq!(parse_error("if a 1 else { 2 }"), ErrorKind::BraceLeft);
+        assert_eq!(parse_error("if a { 1 } else 2"), ErrorKind::BraceLeft);
+    }
+
+    #[test]
+    fn swizzles() {
+        assert_parses_to(
+            "fn f(c: color, p: vec2) c.rgb * -p.yx.xxy; f(1, 2)",
+            "(fn f (c p) (* (. c rgb) (- (. (. p yx) xxy)))) (f 1 2)",
+        );
+        assert_parses_to(
+            "fn f(v: vec2) vec3(1, v.xy).zyx; f(1)",
+            "(fn f (v) (. (vec3 1 (. v xy)) zyx)) (f 1)",
+        );
+        assert_parses_to(
+            "fn f(a: vec2, b: vec2) (a + b).x; f(1, 2)",
+            "(fn f (a b) (. (+ a b) x)) (f 1 2)",
+        );
+        assert_parses_to(
+            "fn f(c: color) { let c.rg = c.gr; let d = c; d }; f(1)",
+            "(fn f (c) { (let c.rg (. c gr)) (let d c) d}) (f 1)",
+        );
+        assert_eq!(parse_error("c.+1"), ErrorKind::Components);
+        assert_eq!(parse_error("{ let c. = 1; c }"), ErrorKind::Components);
+
+        // Constructor names only apply to calls
+        let ast = parse("color(vec2) + vec3(1)").ast;
+        let kinds: Vec<_> = ast.nodes.kinds().to_vec();
+        assert_eq!(
+            kinds,
+            [
+                NodeKind::Ident,
+                NodeKind::Construct,
+                NodeKind::Int,
+                NodeKind::Construct,
+                NodeKind::Sum
+            ]
+        );
+    }
+
+    #[test]
+    fn comments_and_strings() {
+        assert_parses_to(
+            "// Reads a layer\nfn f(a: string) a; /* the /* beauty */ pass */ f(\"rgb\\n\")",
+            "(fn f (a) a) (f \"rgb\\n\")",
+        );
+        let parse = parse("1 + /* 2 */ 3 // done");
+        assert_eq!(parse.ast.trivia.len(), 2);
+    }
+
+    #[test]
+    fn annotations() {
+        assert_parses_to(
+            "fn f(a: vec3, b) { let c: float = b; a * c }; f(1, 2)",
+            "(fn f (a b) { (let c b) (* a c)}) (f 1 2)",
+        );
+        assert_eq!(parse_error("fn f(a:) a; 1"), ErrorKind::Type);
+        assert_eq!(parse_error("{ let a: 1 = 2; a }"), ErrorKind::Type);
+    }
+
+    /// An expression to generate source from, along with the S-expression
+    /// that parsing the source should produce
+    #[derive(Debug, Clone)]
+    enum Expr {
+        Ident(&'static str),
+        Int(i32),
+        Float(f32),
+        String(String),
+        Unary(&'static str, Box<Expr>),
+        Binary(&'static str, Box<Expr>, Box<Expr>),
+        Call(&'static str, Vec<Expr>),
+        Swizzle(Box<Expr>, &'static str),
+        If(Box<Expr>, Box<Expr>, Box<Expr>),
+        Block(Vec<(&'static str, Expr)>, Box<Expr>),
+    }
+
+    const NAMES: [&str; 5] = ["a", "b", "x", "_1", "é"];
+    const OPERATORS: [&str; 12] = [
+        "+", "-", "*", "/", "<", "<=", ">", ">=", "==", "!=", "&&", "||",
+    ];
+
+    impl Expr {
+        /// Prints the expression with every operand in parentheses
+        fn source(&self) -> String {
+            match self {
+                Expr::Ident(name) => name.to_string(),
+                Expr::Int(value) => value.to_string(),
+                Expr::Float(value) => format!("{value:?}"),
+                Expr::String(value) => format!("{value:?}"),
+                Expr::Unary(operator, a) => format!("{operator}({})", a.source()),
+                Expr::Binary(operator, a, b) => {
+                    format!("({}) {operator} ({})", a.source(), b.source())
+                }
+                Expr::Call(name, arguments) => {
+                    let arguments: Vec<_> = arguments.iter().map(Expr::source).collect();
+                    format!("{name}({})", arguments.join(", "))
+                }
+                Expr::Swizzle(a, components) => format!("({}).{components}", a.source()),
+                Expr::If(condition, then, otherwise) => format!(
+                    "if ({}) {{ {} }} else {{ {} }}",
+                    condition.source(),
+                    then.source(),
+                    otherwise.source()
+                ),
+                Expr::Block(statements, value) => {
+                    let mut s = String::from("{ ");
+                    for (name, value) in statements {
+                        s.push_str(&format!("let {name} = {}; ", value.source()));
+                    }
+                    s.push_str(&value.source());
+                    s.push_str(" }");
+                    s
+                }
+            }
+        }
+
+        fn sexp(&self) -> String {
+            match self {
+                Expr::Ident(name) => name.to_string(),
+                Expr::Int(value) => value.to_string(),
+                Expr::Float(value) => value.to_string(),
+                Expr::String(value) => format!("{value:?}"),
+                Expr::Unary(operator, a) => format!("({operator} {})", a.sexp()),
+                Expr::Binary(operator, a, b) => {
+                    format!("({operator} {} {})", a.sexp(), b.sexp())
+                }
+                Expr::Call(name, arguments) => {
+                    let arguments: String =
+                        arguments.iter().map(|a| format!(" {}", a.sexp())).collect();
+                    format!("({name}{arguments})")
+                }
+                Expr::Swizzle(a, components) => format!("(. {} {components})", a.sexp()),
+                Expr::If(condition, then, otherwise) => format!(
+                    "(if {} {{ {}}} {{ {}}})",
+                    condition.sexp(),
+                    then.sexp(),
+                    otherwise.sexp()
+                ),
+                Expr::Block(statements, value) => {
+                    let statements: String = statements
+                        .iter()
+                        .map(|(name, value)| format!(" (let {name} {})", value.sexp()))
+                        .collect();
+                    format!("{{{statements} {}}}", value.sexp())
+                }
+            }
+        }
+    }
+
+    fn expr() -> impl Strategy<Value = Expr> {
+        let leaf = prop_oneof![
+            select(&NAMES[..]).prop_map(Expr::Ident),
+            (0..1000).prop_map(Expr::Int),
+            (0..1000u16).prop_map(|n| Expr::Float(n as f32 / 8.)),
+            "[a-z \"\\\\\n\té]{0,4}".prop_map(Expr::String),
+        ];
+        leaf.prop_recursive(5, 48, 3, move |inner| {
+            prop_oneof![
+                (select(&["-", "!"][..]), inner.clone())
+                    .prop_map(|(operator, a)| Expr::Unary(operator, Box::new(a))),
+                (select(&OPERATORS[..]), inner.clone(), inner.clone())
+                    .prop_map(|(operator, a, b)| Expr::Binary(operator, Box::new(a), Box::new(b))),
+                (select(&["f", "g", "vec3"][..]), vec(inner.clone(), 0..4))
+                    .prop_map(|(name, arguments)| Expr::Call(name, arguments)),
+                (inner.clone(), select(&["x", "xy", "rgb"][..]))
+                    .prop_map(|(a, components)| Expr::Swizzle(Box::new(a), components)),
+                (inner.clone(), inner.clone(), inner.clone())
+                    .prop_map(|(a, b, c)| { Expr::If(Box::new(a), Box::new(b), Box::new(c)) }),
+                (vec((select(&NAMES[..]), inner.clone()), 0..3), inner)
+                    .prop_map(|(statements, value)| Expr::Block(statements, Box::new(value))),
+            ]
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn round_trip(expr in expr()) {
+            assert_syntax(&expr.source(), &expr.sexp());
+        }
+
+        #[test]
+        fn formatting_keeps_structure(expr in expr(), width in 10..100usize) {
+            let options = FormatOptions { width, ..Default::default() };
+            let formatted = format(&expr.source(), &options).unwrap();
+            assert_syntax(&formatted, &expr.sexp());
+            prop_assert_eq!(format(&formatted, &options).unwrap(), formatted);
+        }
+    }
+}
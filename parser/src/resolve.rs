@@ -0,0 +1,467 @@
+use crate::{
+    ast::{Ast, NodeKind},
+    lexer::{Id, Span},
+};
+use std::collections::{hash_map::Entry, HashMap, HashSet};
+
+/// Fills in the symbol table of the AST and checks that every call refers to
+/// a declared function with a matching number of arguments. Since programs are
+/// lowered to a DAG, functions may not call themselves, directly or otherwise.
+///
+/// Also binds each identifier to the let statement or parameter it names,
+/// following lexical scope. Function bodies may only use their parameters and
+/// local variables, while free identifiers at the top level are inputs. The
+/// returned errors include warnings for unused variables.
+pub fn resolve(ast: &mut Ast) -> Vec<Error> {
+    let mut errors = vec![];
+    let mut symbols = HashMap::new();
+    for &function in ast.functions.iter() {
+        let node = ast.node(function);
+        let Some(id) = ast.id(node) else {
+            continue;
+        };
+        match symbols.entry(id) {
+            Entry::Occupied(_) => errors.push(Error::new(
+                ast.token(node).span(),
+                ErrorKind::DuplicateFunction,
+            )),
+            Entry::Vacant(entry) => {
+                entry.insert(function);
+            }
+        }
+
+        let mut parameters = HashSet::new();
+        for &parameter in ast.list(node.children.0) {
+            let parameter = ast.node(parameter);
+            if parameter.kind != NodeKind::Parameter {
+                continue;
+            }
+            if !parameters.insert(ast.id(parameter).unwrap()) {
+                errors.push(Error::new(parameter.span, ErrorKind::DuplicateParameter));
+            }
+        }
+    }
+
+    let mut resolver = Resolver {
+        ast,
+        symbols: &symbols,
+        scope: vec![],
+        bindings: HashMap::new(),
+        errors,
+    };
+    let mut callees = HashMap::new();
+    for &function in ast.functions.iter() {
+        let node = ast.node(function);
+        let parameters = ast.list(node.children.0);
+        resolver.scope = parameters
+            .iter()
+            .filter_map(|&parameter| {
+                let id = ast.id(ast.node(parameter))?;
+                Some(Binding {
+                    id,
+                    node: parameter,
+                    used: true,
+                })
+            })
+            .collect();
+        let calls = resolver.body(node.children.1, true);
+        callees.insert(function, calls);
+    }
+    resolver.scope.clear();
+    resolver.body(ast.root, false);
+    let Resolver {
+        bindings,
+        mut errors,
+        ..
+    } = resolver;
+
+    let mut finished = HashSet::new();
+    for &function in ast.functions.iter() {
+        check_recursion(function, &callees, &mut vec![], &mut finished, &mut errors);
+    }
+
+    ast.symbols = symbols;
+    ast.bindings = bindings;
+    errors
+}
+
+/// A variable in scope
+struct Binding {
+    id: Id,
+    /// The let statement or parameter node
+    node: u32,
+    used: bool,
+}
+
+enum Task {
+    Visit(u32),
+    /// Brings a let statement into scope after its value is resolved
+    Declare(u32),
+    /// Ends a block, leaving the given number of bindings in scope
+    EndScope(usize),
+}
+
+struct Resolver<'a> {
+    ast: &'a Ast,
+    symbols: &'a HashMap<Id, u32>,
+    scope: Vec<Binding>,
+    bindings: HashMap<u32, u32>,
+    errors: Vec<Error>,
+}
+
+impl<'a> Resolver<'a> {
+    /// Resolves the identifiers and calls beneath the given node, returning
+    /// the functions called along with the span of each call's name. In a
+    /// closed body, identifiers must name a variable in scope.
+    fn body(&mut self, node: u32, closed: bool) -> Vec<(u32, Span)> {
+        let ast = self.ast;
+        let mut calls = vec![];
+        let mut tasks = vec![Task::Visit(node)];
+        while let Some(task) = tasks.pop() {
+            let index = match task {
+                Task::Visit(index) => index,
+                Task::Declare(statement) => {
+                    let node = ast.node(statement);
+                    let id = ast.id(node).unwrap();
+                    if ast.mask(node).is_some() {
+                        // A write mask updates the variable it shadows
+                        self.bind(statement, id, closed);
+                    }
+                    self.scope.push(Binding {
+                        id,
+                        node: statement,
+                        used: false,
+                    });
+                    continue;
+                }
+                Task::EndScope(len) => {
+                    self.end_scope(len);
+                    continue;
+                }
+            };
+
+            let node = ast.node(index);
+            match node.kind {
+                NodeKind::Ident => self.bind(index, ast.id(node).unwrap(), closed),
+
+                NodeKind::Block => {
+                    // Pushed in reverse so that each let's value is resolved
+                    // before the variable comes into scope
+                    tasks.push(Task::EndScope(self.scope.len()));
+                    tasks.push(Task::Visit(node.children.1));
+                    for &statement in ast.list(node.children.0).iter().rev() {
+                        tasks.push(Task::Declare(statement));
+                        tasks.push(Task::Visit(ast.node(statement).children.0));
+                    }
+                }
+
+                NodeKind::Call => {
+                    let span = ast.token(node).span();
+                    match self.symbols.get(&ast.id(node).unwrap()) {
+                        Some(&function) => {
+                            let expected = ast.list(ast.node(function).children.0).len() as u32;
+                            let actual = ast.list(node.children.0).len() as u32;
+                            if expected != actual {
+                                self.errors.push(Error::new(
+                                    node.span,
+                                    ErrorKind::Arity { expected, actual },
+                                ));
+                            }
+                            calls.push((function, span));
+                        }
+                        None => self
+                            .errors
+                            .push(Error::new(span, ErrorKind::UndefinedFunction)),
+                    }
+                    // Reversed to visit children in source order
+                    tasks.extend(ast.children(node).into_iter().rev().map(Task::Visit));
+                }
+
+                _ => tasks.extend(ast.children(node).into_iter().rev().map(Task::Visit)),
+            }
+        }
+        calls
+    }
+
+    /// Binds the node to the innermost variable with the given name. In a
+    /// closed body, the variable must exist.
+    fn bind(&mut self, index: u32, id: Id, closed: bool) {
+        match self.scope.iter_mut().rev().find(|binding| binding.id == id) {
+            Some(binding) => {
+                binding.used = true;
+                self.bindings.insert(index, binding.node);
+            }
+            None if closed => {
+                let span = self.ast.token(self.ast.node(index)).span();
+                self.errors
+                    .push(Error::new(span, ErrorKind::UndefinedVariable));
+            }
+            None => {}
+        }
+    }
+
+    /// Removes the bindings of a block from scope, warning about any that were
+    /// never used. Names starting with an underscore are exempt.
+    fn end_scope(&mut self, len: usize) {
+        let ast = self.ast;
+        for binding in self.scope.drain(len..) {
+            let token = ast.token(ast.node(binding.node));
+            if !binding.used && !ast.name(binding.id).starts_with('_') {
+                self.errors
+                    .push(Error::new(token.span(), ErrorKind::UnusedVariable));
+            }
+        }
+    }
+}
+
+fn check_recursion(
+    function: u32,
+    callees: &HashMap<u32, Vec<(u32, Span)>>,
+    stack: &mut Vec<u32>,
+    finished: &mut HashSet<u32>,
+    errors: &mut Vec<Error>,
+) {
+    if finished.contains(&function) {
+        return;
+    }
+    stack.push(function);
+    for &(callee, span) in callees[&function].iter() {
+        if stack.contains(&callee) {
+            errors.push(Error::new(span, ErrorKind::Recursion));
+        } else {
+            check_recursion(callee, callees, stack, finished, errors);
+        }
+    }
+    stack.pop();
+    finished.insert(function);
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ErrorKind {
+    #[error("No function with this name has been declared")]
+    UndefinedFunction,
+    #[error("A function with this name has already been declared")]
+    DuplicateFunction,
+    #[error("A parameter with this name has already been declared")]
+    DuplicateParameter,
+    #[error("Expected {expected} arguments but found {actual}")]
+    Arity { expected: u32, actual: u32 },
+    #[error("Functions cannot call themselves")]
+    Recursion,
+    #[error("No variable with this name is in scope")]
+    UndefinedVariable,
+    #[error("This variable is never used")]
+    UnusedVariable,
+}
+
+impl ErrorKind {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ErrorKind::UndefinedFunction => "E0201",
+            ErrorKind::DuplicateFunction => "E0202",
+            ErrorKind::DuplicateParameter => "E0203",
+            ErrorKind::Arity { .. } => "E0204",
+            ErrorKind::Recursion => "E0205",
+            ErrorKind::UndefinedVariable => "E0206",
+            ErrorKind::UnusedVariable => "W0201",
+        }
+    }
+
+    /// Whether the error is only a warning, which does not prevent the
+    /// program from being used
+    pub fn is_warning(&self) -> bool {
+        matches!(self, ErrorKind::UnusedVariable)
+    }
+
+    pub fn help(&self) -> Option<&'static str> {
+        match self {
+            ErrorKind::UndefinedFunction => Some("Declare the function with `fn`"),
+            ErrorKind::DuplicateFunction => Some("Rename one of the functions"),
+            ErrorKind::DuplicateParameter => Some("Rename one of the parameters"),
+            ErrorKind::Arity { .. } => None,
+            ErrorKind::Recursion => Some("Expressions are evaluated as a graph without loops"),
+            ErrorKind::UndefinedVariable => {
+                Some("Functions can only use their parameters and variables declared with `let`")
+            }
+            ErrorKind::UnusedVariable => {
+                Some("Remove the variable or start its name with an underscore")
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("Resolution error at {span}:\n{kind}")]
+pub struct Error {
+    pub span: Span,
+    pub kind: ErrorKind,
+}
+
+impl Error {
+    fn new(span: Span, kind: ErrorKind) -> Self {
+        Self { span, kind }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+
+    fn resolve_errors(s: &str) -> Vec<ErrorKind> {
+        let parse = parse(s);
+        parse
+            .errors
+            .into_iter()
+            .chain(parse.warnings)
+            .map(|e| match e {
+                crate::Error::Resolve(e) => e.kind,
+                other => panic!("Expected a resolution error, got {other:?}"),
+            })
+            .collect()
+    }
+
+    fn resolve_error(s: &str) -> ErrorKind {
+        resolve_errors(s)[0].clone()
+    }
+
+    #[test]
+    fn symbols() {
+        let ast = parse("fn f(a) a; fn g(a, b) f(a) + b; g(1, 2)")
+            .into_result()
+            .unwrap();
+        assert_eq!(ast.symbols.len(), 2);
+        for &function in ast.functions.iter() {
+            let id = ast.id(ast.node(function)).unwrap();
+            assert_eq!(ast.symbols[&id], function);
+        }
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(resolve_error("f(1)"), ErrorKind::UndefinedFunction);
+        assert_eq!(
+            resolve_error("fn f(a) a; fn f(b) b; 1"),
+            ErrorKind::DuplicateFunction
+        );
+        assert_eq!(
+            resolve_error("fn f(a, a) a; 1"),
+            ErrorKind::DuplicateParameter
+        );
+        assert_eq!(
+            resolve_error("fn f(a, b) a; f(1)"),
+            ErrorKind::Arity {
+                expected: 2,
+                actual: 1
+            }
+        );
+        assert_eq!(
+            resolve_error("fn f() 1; fn g(a) f(a); 1"),
+            ErrorKind::Arity {
+                expected: 0,
+                actual: 1
+            }
+        );
+    }
+
+    #[test]
+    fn recursion() {
+        assert_eq!(resolve_error("fn f(a) f(a); 1"), ErrorKind::Recursion);
+        assert_eq!(
+            resolve_error("fn f(a) g(a); fn g(a) h(a); fn h(a) f(a); 1"),
+            ErrorKind::Recursion
+        );
+        assert!(
+            parse("fn f(a) a; fn g(a) f(a) + f(a); fn h(a) g(f(a)); h(1)")
+                .errors
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn reports_all_errors() {
+        assert_eq!(
+            resolve_errors("fn f(a, a) g(a); fn f() 1; f(1, 2) + h()"),
+            [
+                ErrorKind::DuplicateParameter,
+                ErrorKind::DuplicateFunction,
+                ErrorKind::UndefinedFunction,
+                ErrorKind::UndefinedFunction,
+            ]
+        );
+    }
+
+    #[test]
+    fn scopes() {
+        let ast = parse("{ let a = 1; let b = { let a = a + 1; a }; a + b }")
+            .into_result()
+            .unwrap();
+        let lets: Vec<_> = (0..ast.nodes.len() as u32)
+            .filter(|&i| ast.node(i).kind == NodeKind::Let)
+            .collect();
+        let idents: Vec<_> = (0..ast.nodes.len() as u32)
+            .filter(|&i| ast.node(i).kind == NodeKind::Ident)
+            .map(|i| ast.bindings[&i])
+            .collect();
+        let [outer, inner, b] = lets[..] else {
+            panic!("Expected three lets");
+        };
+        assert_eq!(idents, [outer, inner, outer, b]);
+
+        let ast = parse("fn f(x) { let y = x; y }; f(1) + x")
+            .into_result()
+            .unwrap();
+        let parameter = ast.list(ast.node(ast.functions[0]).children.0)[0];
+        let bound: Vec<_> = ast.bindings.values().copied().collect();
+        assert!(bound.contains(&parameter));
+        assert_eq!(ast.bindings.len(), 2);
+    }
+
+    #[test]
+    fn variables() {
+        assert_eq!(resolve_error("fn f(a) b; 1"), ErrorKind::UndefinedVariable);
+        assert_eq!(
+            resolve_error("fn f(a) { let b = b; b }; 1"),
+            ErrorKind::UndefinedVariable
+        );
+        assert_eq!(
+            resolve_error("fn f(a) { let b = 1; a } + b; 1"),
+            ErrorKind::UndefinedVariable
+        );
+        assert_eq!(resolve_error("{ let a = 1; 2 }"), ErrorKind::UnusedVariable);
+        assert_eq!(
+            resolve_error("{ let a = 1; let a = 2; a }"),
+            ErrorKind::UnusedVariable
+        );
+        assert!(resolve_errors("{ let _a = 1; 2 }").is_empty());
+        assert!(resolve_errors("{ let a = 1; { let a = a; a } }").is_empty());
+        assert_eq!(
+            resolve_error("fn f(a) { let b.x = a; b }; 1"),
+            ErrorKind::UndefinedVariable
+        );
+        assert_eq!(
+            resolve_error("{ let a = vec2(1); let a.x = 2; 3 }"),
+            ErrorKind::UnusedVariable
+        );
+    }
+
+    #[test]
+    fn write_masks() {
+        let ast = parse("fn f(v: vec2) { let v.x = 1; v }; f(0)")
+            .into_result()
+            .unwrap();
+        let parameter = ast.list(ast.node(ast.functions[0]).children.0)[0];
+        let statement = (0..ast.nodes.len() as u32)
+            .find(|&i| ast.node(i).kind == NodeKind::Let)
+            .unwrap();
+        assert_eq!(ast.bindings[&statement], parameter);
+    }
+
+    #[test]
+    fn warnings() {
+        let parse = parse("{ let a = 1; b }");
+        assert!(parse.errors.is_empty());
+        assert_eq!(parse.warnings.len(), 1);
+        assert!(parse.into_result().is_ok());
+    }
+}
@@ -0,0 +1,732 @@
+use crate::{
+    ast::{Ast, NodeKind},
+    lexer::{Span, TokenKind},
+};
+use std::{
+    collections::HashMap,
+    fmt::{self, Display, Formatter},
+};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Type {
+    Float,
+    Int,
+    Bool,
+    String,
+    Vec2,
+    Vec3,
+    Vec4,
+    /// Four components like [`Type::Vec4`], but not interchangeable with it
+    Color,
+    /// The type of an expression that could not be checked. It is compatible
+    /// with every other type so that each mistake is only reported once.
+    #[default]
+    Error,
+}
+
+impl Type {
+    /// Gets the type with the given name as written in annotations
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "float" => Some(Type::Float),
+            "int" => Some(Type::Int),
+            "bool" => Some(Type::Bool),
+            "string" => Some(Type::String),
+            "vec2" => Some(Type::Vec2),
+            "vec3" => Some(Type::Vec3),
+            "vec4" => Some(Type::Vec4),
+            "color" => Some(Type::Color),
+            _ => None,
+        }
+    }
+
+    pub fn is_scalar(self) -> bool {
+        matches!(self, Type::Float | Type::Int)
+    }
+
+    pub fn is_vector(self) -> bool {
+        matches!(self, Type::Vec2 | Type::Vec3 | Type::Vec4 | Type::Color)
+    }
+
+    /// Gets the number of components of a scalar or vector type
+    pub fn components(self) -> Option<u32> {
+        match self {
+            Type::Float | Type::Int => Some(1),
+            Type::Vec2 => Some(2),
+            Type::Vec3 => Some(3),
+            Type::Vec4 | Type::Color => Some(4),
+            Type::Bool | Type::String | Type::Error => None,
+        }
+    }
+
+    /// Gets the float or vector type with the given number of components
+    pub fn with_components(components: usize) -> Option<Self> {
+        match components {
+            1 => Some(Type::Float),
+            2 => Some(Type::Vec2),
+            3 => Some(Type::Vec3),
+            4 => Some(Type::Vec4),
+            _ => None,
+        }
+    }
+
+    /// Gets the type of an arithmetic expression on the two types. Integers
+    /// mixed with floats become floats and scalars broadcast to vectors, but
+    /// vectors must match exactly.
+    pub fn arithmetic(self, other: Self) -> Option<Self> {
+        match (self, other) {
+            (Type::Error, _) | (_, Type::Error) => Some(Type::Error),
+            (Type::Int, Type::Int) => Some(Type::Int),
+            (a, b) if a.is_scalar() && b.is_scalar() => Some(Type::Float),
+            (a, b) if a.is_scalar() && b.is_vector() => Some(b),
+            (a, b) if a.is_vector() && b.is_scalar() => Some(a),
+            (a, b) if a.is_vector() && a == b => Some(a),
+            _ => None,
+        }
+    }
+
+    /// Whether a value of this type can be passed or assigned where the other
+    /// type is expected
+    pub fn coerces_to(self, target: Self) -> bool {
+        self == target
+            || self == Type::Error
+            || target == Type::Error
+            || (self == Type::Int && target == Type::Float)
+            || (self.is_scalar() && target.is_vector())
+    }
+}
+
+impl Display for Type {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Type::Float => "float",
+            Type::Int => "int",
+            Type::Bool => "bool",
+            Type::String => "string",
+            Type::Vec2 => "vec2",
+            Type::Vec3 => "vec3",
+            Type::Vec4 => "vec4",
+            Type::Color => "color",
+            Type::Error => "{unknown}",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// Fills in the type of every node in the AST. Parameters and let statements
+/// take the type of their annotation if they have one. Otherwise, parameters
+/// and free identifiers are floats and let statements take the type of their
+/// value. Expects the AST to have been resolved.
+pub fn check(ast: &mut Ast) -> Vec<Error> {
+    let mut checker = Checker {
+        ast,
+        types: vec![Type::Error; ast.nodes.len()],
+        functions: HashMap::new(),
+        errors: vec![],
+    };
+    for &function in ast.functions.iter() {
+        for &parameter in ast.list(ast.node(function).children.0) {
+            if ast.node(parameter).kind == NodeKind::Parameter {
+                checker.types[parameter as usize] = checker.annotation(parameter, Type::Float);
+            }
+        }
+    }
+    for &function in ast.functions.iter() {
+        if !checker.functions.contains_key(&function) {
+            checker.run(vec![
+                Task::Finish(function),
+                Task::Enter(ast.node(function).children.1),
+            ]);
+        }
+    }
+    checker.run(vec![Task::Enter(ast.root)]);
+
+    let Checker { types, errors, .. } = checker;
+    ast.types = types;
+    errors
+}
+
+enum Task {
+    /// Checks the children of a node
+    Enter(u32),
+    /// Checks a node whose children have been checked
+    Exit(u32),
+    /// Records the type of a function once its body has been checked
+    Finish(u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Function {
+    InProgress,
+    Done(Type),
+}
+
+struct Checker<'a> {
+    ast: &'a Ast,
+    types: Vec<Type>,
+    functions: HashMap<u32, Function>,
+    errors: Vec<Error>,
+}
+
+impl<'a> Checker<'a> {
+    fn run(&mut self, mut tasks: Vec<Task>) {
+        let ast = self.ast;
+        while let Some(task) = tasks.pop() {
+            let index = match task {
+                Task::Enter(index) => {
+                    tasks.push(Task::Exit(index));
+                    let children = ast.children(ast.node(index));
+                    tasks.extend(children.into_iter().rev().map(Task::Enter));
+                    continue;
+                }
+                Task::Exit(index) => index,
+                Task::Finish(function) => {
+                    let body = ast.node(function).children.1;
+                    let t = self.types[body as usize];
+                    self.types[function as usize] = t;
+                    self.functions.insert(function, Function::Done(t));
+                    continue;
+                }
+            };
+
+            let node = ast.node(index);
+            let (a, b) = node.children;
+            let t = match node.kind {
+                NodeKind::Int => Type::Int,
+                NodeKind::Float => Type::Float,
+                NodeKind::String => Type::String,
+                NodeKind::Ident => match ast.bindings.get(&index) {
+                    Some(&binding) => self.types[binding as usize],
+                    None => Type::Float,
+                },
+                NodeKind::Let if ast.mask(node).is_some() => {
+                    let t = match ast.bindings.get(&index) {
+                        Some(&binding) => self.types[binding as usize],
+                        // Unlike reading an input, writing to one is an
+                        // error that resolving has already reported
+                        None => Type::Error,
+                    };
+                    if let Some(masked) = self.write_mask(index, t) {
+                        self.expect(a, masked);
+                    }
+                    t
+                }
+                NodeKind::Let => {
+                    let value = self.types[a as usize];
+                    let t = self.annotation(index, value);
+                    self.expect(a, t);
+                    t
+                }
+                NodeKind::Block => self.types[b as usize],
+
+                NodeKind::Sum | NodeKind::Difference | NodeKind::Product | NodeKind::Quotient => {
+                    let (left, right) = (self.types[a as usize], self.types[b as usize]);
+                    left.arithmetic(right).unwrap_or_else(|| {
+                        self.error(node.span, ErrorKind::Operands { left, right })
+                    })
+                }
+                NodeKind::Negation => {
+                    let t = self.types[a as usize];
+                    if t.is_scalar() || t.is_vector() || t == Type::Error {
+                        t
+                    } else {
+                        let span = ast.node(a).span;
+                        self.error(span, ErrorKind::Numeric(t))
+                    }
+                }
+
+                NodeKind::Not => {
+                    self.expect(a, Type::Bool);
+                    Type::Bool
+                }
+                NodeKind::And | NodeKind::Or => {
+                    self.expect(a, Type::Bool);
+                    self.expect(b, Type::Bool);
+                    Type::Bool
+                }
+                NodeKind::Less
+                | NodeKind::LessEqual
+                | NodeKind::Greater
+                | NodeKind::GreaterEqual => {
+                    let (left, right) = (self.types[a as usize], self.types[b as usize]);
+                    let ordered = |t: Type| t.is_scalar() || t == Type::Error;
+                    if !ordered(left) || !ordered(right) {
+                        self.error(node.span, ErrorKind::Comparison { left, right });
+                    }
+                    Type::Bool
+                }
+                NodeKind::Equal | NodeKind::NotEqual => {
+                    let (left, right) = (self.types[a as usize], self.types[b as usize]);
+                    if left != right && left.arithmetic(right).is_none() {
+                        self.error(node.span, ErrorKind::Comparison { left, right });
+                    }
+                    Type::Bool
+                }
+                NodeKind::If => {
+                    let &[then, otherwise] = ast.list(b) else {
+                        unreachable!("Conditionals have two branches");
+                    };
+                    self.expect(a, Type::Bool);
+                    let (then, otherwise) =
+                        (self.types[then as usize], self.types[otherwise as usize]);
+                    if then == otherwise {
+                        then
+                    } else {
+                        then.arithmetic(otherwise).unwrap_or_else(|| {
+                            self.error(node.span, ErrorKind::Branches { then, otherwise })
+                        })
+                    }
+                }
+
+                NodeKind::Construct => {
+                    let name = ast.name(ast.id(node).unwrap());
+                    let t = Type::from_name(name).unwrap();
+                    let arguments = ast.list(a);
+                    let mut actual = 0;
+                    let mut valid = true;
+                    for &argument in arguments {
+                        match self.types[argument as usize].components() {
+                            Some(components) => actual += components,
+                            None => {
+                                self.expect(argument, Type::Float);
+                                valid = false;
+                            }
+                        }
+                    }
+                    let expected = t.components().unwrap();
+                    // A single scalar fills every component
+                    let broadcast = arguments.len() == 1 && actual == 1;
+                    if valid && !broadcast && actual != expected {
+                        let kind = ErrorKind::Components {
+                            t,
+                            expected,
+                            actual,
+                        };
+                        self.error(node.span, kind);
+                    }
+                    t
+                }
+                NodeKind::Swizzle => {
+                    let base = self.types[a as usize];
+                    match self.swizzle(index, base) {
+                        Some(indices) if base == Type::Color && indices.len() == 4 => Type::Color,
+                        Some(indices) => Type::with_components(indices.len()).unwrap(),
+                        None => Type::Error,
+                    }
+                }
+
+                NodeKind::Call => {
+                    let Some(&function) = ast.symbols.get(&ast.id(node).unwrap()) else {
+                        continue;
+                    };
+                    match self.functions.get(&function) {
+                        Some(&Function::Done(t)) => {
+                            let parameters = ast.list(ast.node(function).children.0);
+                            for (&argument, &parameter) in ast.list(a).iter().zip(parameters.iter())
+                            {
+                                self.expect(argument, self.types[parameter as usize]);
+                            }
+                            t
+                        }
+                        // Recursion has already been reported
+                        Some(Function::InProgress) => Type::Error,
+                        None => {
+                            // Check the function first and come back
+                            self.functions.insert(function, Function::InProgress);
+                            tasks.push(Task::Exit(index));
+                            tasks.push(Task::Finish(function));
+                            tasks.push(Task::Enter(ast.node(function).children.1));
+                            continue;
+                        }
+                    }
+                }
+
+                NodeKind::Function | NodeKind::Parameter | NodeKind::Error => Type::Error,
+            };
+            self.types[index as usize] = t;
+        }
+    }
+
+    /// Gets the type named by the annotation of a parameter or let node, or
+    /// the default if there is none
+    fn annotation(&mut self, node: u32, default: Type) -> Type {
+        let Some(token) = self.ast.annotation(self.ast.node(node)) else {
+            return default;
+        };
+        let name = match token.kind {
+            TokenKind::Ident(id) => self.ast.name(id),
+            _ => "",
+        };
+        Type::from_name(name).unwrap_or_else(|| self.error(token.span(), ErrorKind::UnknownType))
+    }
+
+    /// Gets the component indices named by the token of a swizzle node or the
+    /// write mask of a let node, reporting an error if they don't exist on
+    /// the type
+    fn swizzle(&mut self, node: u32, t: Type) -> Option<Vec<u32>> {
+        let ast = self.ast;
+        let node = ast.node(node);
+        let token = match node.kind {
+            NodeKind::Let => ast.mask(node)?,
+            _ => ast.token(node),
+        };
+        let TokenKind::Ident(id) = token.kind else {
+            return None;
+        };
+        let name = ast.name(id);
+        let Some(indices) = component_indices(name) else {
+            self.error(token.span(), ErrorKind::Swizzle);
+            return None;
+        };
+        let components = match t {
+            Type::Error => return None,
+            // Scalars have no components to access
+            t if t.is_scalar() => 0,
+            t => t.components().unwrap_or(0),
+        };
+        let missing = name
+            .chars()
+            .zip(indices.iter())
+            .find(|&(_, &i)| i >= components);
+        if let Some((component, _)) = missing {
+            self.error(token.span(), ErrorKind::Component { t, component });
+            return None;
+        }
+        Some(indices)
+    }
+
+    /// Checks the write mask of a let node against the type of the variable
+    /// it updates, returning the type of value the masked components take
+    fn write_mask(&mut self, node: u32, t: Type) -> Option<Type> {
+        let indices = self.swizzle(node, t)?;
+        let mut seen = [false; 4];
+        for &i in indices.iter() {
+            if std::mem::replace(&mut seen[i as usize], true) {
+                let span = self.ast.mask(self.ast.node(node)).unwrap().span();
+                self.error(span, ErrorKind::Mask);
+                return None;
+            }
+        }
+        Type::with_components(indices.len())
+    }
+
+    /// Reports an error if the node's type cannot be used as the expected type
+    fn expect(&mut self, node: u32, expected: Type) {
+        let actual = self.types[node as usize];
+        if !actual.coerces_to(expected) {
+            let span = self.ast.node(node).span;
+            self.error(span, ErrorKind::Expected { expected, actual });
+        }
+    }
+
+    /// Records an error, returning the type to use for the offending node
+    fn error(&mut self, span: Span, kind: ErrorKind) -> Type {
+        self.errors.push(Error { span, kind });
+        Type::Error
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ErrorKind {
+    #[error("Expected `{expected}` but found `{actual}`")]
+    Expected { expected: Type, actual: Type },
+    #[error("Cannot combine `{left}` and `{right}`")]
+    Operands { left: Type, right: Type },
+    #[error("Cannot compare `{left}` and `{right}`")]
+    Comparison { left: Type, right: Type },
+    #[error("Expected a number or vector but found `{0}`")]
+    Numeric(Type),
+    #[error("The branches have different types `{then}` and `{otherwise}`")]
+    Branches { then: Type, otherwise: Type },
+    #[error("No type with this name exists")]
+    UnknownType,
+    #[error("`{t}` needs {expected} components but found {actual}")]
+    Components { t: Type, expected: u32, actual: u32 },
+    #[error("`{t}` has no component `{component}`")]
+    Component { t: Type, component: char },
+    #[error("Invalid swizzle")]
+    Swizzle,
+    #[error("Components in a write mask must be distinct")]
+    Mask,
+}
+
+impl ErrorKind {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ErrorKind::Expected { .. } => "E0301",
+            ErrorKind::Operands { .. } => "E0302",
+            ErrorKind::Comparison { .. } => "E0303",
+            ErrorKind::Numeric(_) => "E0304",
+            ErrorKind::Branches { .. } => "E0305",
+            ErrorKind::UnknownType => "E0306",
+            ErrorKind::Components { .. } => "E0307",
+            ErrorKind::Component { .. } => "E0308",
+            ErrorKind::Swizzle => "E0309",
+            ErrorKind::Mask => "E0310",
+        }
+    }
+
+    pub fn help(&self) -> Option<&'static str> {
+        match self {
+            ErrorKind::Expected { .. } => None,
+            ErrorKind::Operands { .. } | ErrorKind::Branches { .. } => {
+                Some("Scalars broadcast to vectors, but vectors must have the same type")
+            }
+            ErrorKind::Comparison { .. } => {
+                Some("Only numbers can be ordered, and only matching types compared")
+            }
+            ErrorKind::Numeric(_) => None,
+            ErrorKind::UnknownType => {
+                Some("The types are `float`, `int`, `bool`, `string`, `vec2`, `vec3`, `vec4`, and `color`")
+            }
+            ErrorKind::Components { .. } => {
+                Some("Pass a single scalar or arguments whose components add up to the size")
+            }
+            ErrorKind::Component { .. } => None,
+            ErrorKind::Swizzle => Some("Use up to four of `xyzw` or `rgba` without mixing the two"),
+            ErrorKind::Mask => None,
+        }
+    }
+}
+
+/// Gets the indices of swizzle components such as `xy` or `bgra`
+fn component_indices(name: &str) -> Option<Vec<u32>> {
+    if name.is_empty() || name.len() > 4 {
+        return None;
+    }
+    ["xyzw", "rgba"].into_iter().find_map(|set| {
+        name.chars()
+            .map(|c| set.find(c).map(|i| i as u32))
+            .collect()
+    })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("Type error at {span}:\n{kind}")]
+pub struct Error {
+    pub span: Span,
+    pub kind: ErrorKind,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+
+    fn root_type(s: &str) -> Type {
+        let ast = parse(s).into_result().unwrap();
+        ast.types[ast.root as usize]
+    }
+
+    fn type_errors(s: &str) -> Vec<ErrorKind> {
+        parse(s)
+            .errors
+            .into_iter()
+            .map(|e| match e {
+                crate::Error::Type(e) => e.kind,
+                other => panic!("Expected a type error, got {other:?}"),
+            })
+            .collect()
+    }
+
+    fn type_error(s: &str) -> ErrorKind {
+        type_errors(s)[0].clone()
+    }
+
+    #[test]
+    fn literals() {
+        assert_eq!(root_type("1"), Type::Int);
+        assert_eq!(root_type("1.5"), Type::Float);
+        assert_eq!(root_type("x"), Type::Float);
+        assert_eq!(root_type("1 < 2 && !(x == 1)"), Type::Bool);
+        assert_eq!(root_type("\"a\" != \"b\""), Type::Bool);
+        assert_eq!(
+            type_error("fn f(s: string) s + 1; 1"),
+            ErrorKind::Operands {
+                left: Type::String,
+                right: Type::Int
+            }
+        );
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(root_type("1 + 2 * 3"), Type::Int);
+        assert_eq!(root_type("1 + 2.0"), Type::Float);
+        assert_eq!(root_type("fn f(v: vec3) v * 2; f(1)"), Type::Vec3);
+        assert_eq!(
+            root_type("fn f(c: color, t) -(c * t) + 1; f(0, 1)"),
+            Type::Color
+        );
+        assert_eq!(
+            type_error("fn f(a: vec3, b: vec2) a + b; 1"),
+            ErrorKind::Operands {
+                left: Type::Vec3,
+                right: Type::Vec2
+            }
+        );
+        assert_eq!(
+            type_error("fn f(a: color, b: vec4) a * b; 1"),
+            ErrorKind::Operands {
+                left: Type::Color,
+                right: Type::Vec4
+            }
+        );
+        assert_eq!(type_error("-(1 < 2)"), ErrorKind::Numeric(Type::Bool));
+    }
+
+    #[test]
+    fn bindings() {
+        assert_eq!(root_type("{ let a: float = 1; a }"), Type::Float);
+        assert_eq!(root_type("{ let a = 1; a }"), Type::Int);
+        assert_eq!(root_type("fn f(b: bool) b; f(1 < 2)"), Type::Bool);
+        assert_eq!(
+            type_error("{ let a: int = 1.5; a }"),
+            ErrorKind::Expected {
+                expected: Type::Int,
+                actual: Type::Float
+            }
+        );
+        assert_eq!(
+            type_error("fn f(v: vec2) v; fn g(v: vec3) f(v); 1"),
+            ErrorKind::Expected {
+                expected: Type::Vec2,
+                actual: Type::Vec3
+            }
+        );
+        assert_eq!(type_error("fn f(a: number) a; 1"), ErrorKind::UnknownType);
+    }
+
+    #[test]
+    fn conditions() {
+        assert_eq!(root_type("if x < 0 { 0 } else { x }"), Type::Float);
+        assert_eq!(root_type("if x < 0 { x < 1 } else { x > 2 }"), Type::Bool);
+        assert_eq!(
+            root_type("if x < 0 { \"rgb\" } else { \"a\" }"),
+            Type::String
+        );
+        assert_eq!(
+            type_error("if x { 1 } else { 2 }"),
+            ErrorKind::Expected {
+                expected: Type::Bool,
+                actual: Type::Float
+            }
+        );
+        assert_eq!(
+            type_error("if x > 1 { 1 } else { 1 < 2 }"),
+            ErrorKind::Branches {
+                then: Type::Int,
+                otherwise: Type::Bool
+            }
+        );
+        assert_eq!(
+            type_error("if x > 1 { \"rgb\" } else { 1 < 2 }"),
+            ErrorKind::Branches {
+                then: Type::String,
+                otherwise: Type::Bool
+            }
+        );
+        assert_eq!(
+            type_error("(1 < 2) < 3"),
+            ErrorKind::Comparison {
+                left: Type::Bool,
+                right: Type::Int
+            }
+        );
+        assert_eq!(
+            type_error("1 || x"),
+            ErrorKind::Expected {
+                expected: Type::Bool,
+                actual: Type::Int
+            }
+        );
+    }
+
+    #[test]
+    fn functions() {
+        // Functions declared later are checked before the call
+        let ast = parse("fn f(a: vec4) g(a) + 1; fn g(a: vec4) a; f(x)")
+            .into_result()
+            .unwrap();
+        assert_eq!(ast.types[ast.functions[0] as usize], Type::Vec4);
+        assert_eq!(ast.types[ast.functions[1] as usize], Type::Vec4);
+        assert_eq!(ast.types[ast.root as usize], Type::Vec4);
+
+        // Errors are reported once, no matter how many times the function is
+        // called
+        assert_eq!(type_errors("fn f(a) a || a; f(1) && f(2)").len(), 2);
+    }
+
+    #[test]
+    fn vectors() {
+        assert_eq!(root_type("vec3(1, 2, 3)"), Type::Vec3);
+        assert_eq!(root_type("vec4(vec2(x), 1, 2.5)"), Type::Vec4);
+        assert_eq!(root_type("color(0.5)"), Type::Color);
+        assert_eq!(root_type("vec2(x).y"), Type::Float);
+        assert_eq!(root_type("vec4(x).xz"), Type::Vec2);
+        assert_eq!(root_type("color(x).rgb"), Type::Vec3);
+        assert_eq!(root_type("color(x).bgra"), Type::Color);
+        assert_eq!(root_type("vec4(x).abgr"), Type::Vec4);
+        assert_eq!(
+            type_error("vec3(1, 2)"),
+            ErrorKind::Components {
+                t: Type::Vec3,
+                expected: 3,
+                actual: 2
+            }
+        );
+        assert_eq!(
+            type_error("vec2(1 < 2, 1)"),
+            ErrorKind::Expected {
+                expected: Type::Float,
+                actual: Type::Bool
+            }
+        );
+        assert_eq!(
+            type_error("vec2(x).z"),
+            ErrorKind::Component {
+                t: Type::Vec2,
+                component: 'z'
+            }
+        );
+        assert_eq!(
+            type_error("x.x"),
+            ErrorKind::Component {
+                t: Type::Float,
+                component: 'x'
+            }
+        );
+        assert_eq!(type_error("vec4(x).xg"), ErrorKind::Swizzle);
+        assert_eq!(type_error("vec4(x).xyzwx"), ErrorKind::Swizzle);
+    }
+
+    #[test]
+    fn write_masks() {
+        assert_eq!(
+            root_type("{ let c = vec3(x); let c.xy = 1; c }"),
+            Type::Vec3
+        );
+        assert_eq!(
+            root_type("{ let c = color(x); let c.rb = vec2(1, 0); let c.a = 1; c }"),
+            Type::Color
+        );
+        assert_eq!(
+            type_error("{ let c = vec3(x); let c.xy = c; c }"),
+            ErrorKind::Expected {
+                expected: Type::Vec2,
+                actual: Type::Vec3
+            }
+        );
+        assert_eq!(
+            type_error("{ let c = vec3(x); let c.xx = vec2(1, 2); c }"),
+            ErrorKind::Mask
+        );
+        assert_eq!(
+            type_error("{ let c = vec2(x); let c.z = 1; c }"),
+            ErrorKind::Component {
+                t: Type::Vec2,
+                component: 'z'
+            }
+        );
+    }
+}